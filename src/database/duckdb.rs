@@ -0,0 +1,938 @@
+use crate::models::{
+    IngestedFile, MessageTrackingLog, RejectedLine, SmtpDirection, SmtpEvent, SmtpReceiveLog,
+    SmtpRecipient, SmtpSendLog, SmtpTransaction,
+};
+use async_trait::async_trait;
+use color_eyre::eyre::{Result, eyre};
+use duckdb::{Connection, OptionalExt, ToSql, params};
+use log::{debug, info};
+use std::sync::{Arc, Mutex};
+
+use super::Database;
+use super::migrations::Migration;
+
+/// Embedded DuckDB database stored in a single file
+///
+/// This struct is used to run heavy aggregations over months of logs without a
+/// database server: DuckDB stores the tables by column. Rows are bulk loaded
+/// with the appender API, all queries go through one connection and run on the
+/// blocking thread pool.
+pub struct DuckDbDatabase {
+    connection: Arc<Mutex<Connection>>,
+    table_prefix: String,
+}
+
+impl DuckDbDatabase {
+    pub async fn new(path: &str, table_prefix: Option<&str>) -> Result<Self> {
+        let path = path.to_string();
+        let connection = tokio::task::spawn_blocking(move || -> Result<Connection> {
+            let connection = Connection::open(&path)
+                .map_err(|e| eyre!("Не удалось открыть базу DuckDB {}: {}", path, e))?;
+            Ok(connection)
+        })
+        .await??;
+
+        let db = DuckDbDatabase {
+            connection: Arc::new(Mutex::new(connection)),
+            table_prefix: table_prefix.unwrap_or("").to_string(),
+        };
+
+        Ok(db)
+    }
+
+    /// Runs a closure with the connection on the blocking thread pool
+    async fn run<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Connection) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| eyre!("DuckDB connection is poisoned"))?;
+            f(&mut connection)
+        })
+        .await?
+    }
+
+    /// Schema of the first version, equivalent to the PostgreSQL schema with
+    /// 64-bit identifiers taken from one sequence per table
+    ///
+    /// Only the unique keys used to skip duplicates are indexed: DuckDB answers
+    /// the analytical queries by scanning columns, secondary indexes would only
+    /// slow down the bulk loads.
+    fn schema(&self) -> String {
+        let mut schema = String::new();
+
+        // Create SMTP Receive logs table
+        schema.push_str(&format!(
+            r#"
+            CREATE SEQUENCE IF NOT EXISTS {prefix}smtp_receive_logs_id_seq;
+            CREATE TABLE IF NOT EXISTS {prefix}smtp_receive_logs (
+                id BIGINT PRIMARY KEY DEFAULT nextval('{prefix}smtp_receive_logs_id_seq'),
+                date_time TIMESTAMPTZ NOT NULL,
+                connector_id VARCHAR NOT NULL,
+                session_id VARCHAR NOT NULL,
+                sequence_number INTEGER NOT NULL,
+                local_endpoint VARCHAR NOT NULL,
+                remote_endpoint VARCHAR NOT NULL,
+                event VARCHAR NOT NULL,
+                data VARCHAR,
+                context VARCHAR,
+                sender VARCHAR,
+                recipient VARCHAR,
+                message_id VARCHAR,
+                subject VARCHAR,
+                size BIGINT,
+                UNIQUE (date_time, session_id, sequence_number)
+            );
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        // Create SMTP Send logs table
+        schema.push_str(&format!(
+            r#"
+            CREATE SEQUENCE IF NOT EXISTS {prefix}smtp_send_logs_id_seq;
+            CREATE TABLE IF NOT EXISTS {prefix}smtp_send_logs (
+                id BIGINT PRIMARY KEY DEFAULT nextval('{prefix}smtp_send_logs_id_seq'),
+                date_time TIMESTAMPTZ NOT NULL,
+                connector_id VARCHAR NOT NULL,
+                session_id VARCHAR NOT NULL,
+                sequence_number INTEGER NOT NULL,
+                local_endpoint VARCHAR NOT NULL,
+                remote_endpoint VARCHAR NOT NULL,
+                event VARCHAR NOT NULL,
+                data VARCHAR,
+                context VARCHAR,
+                proxy_session_id VARCHAR,
+                sender VARCHAR,
+                recipient VARCHAR,
+                message_id VARCHAR,
+                record_id VARCHAR,
+                UNIQUE (date_time, session_id, sequence_number)
+            );
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        // Create SMTP protocol events tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            CREATE SEQUENCE IF NOT EXISTS {prefix}{table}_id_seq;
+            CREATE TABLE IF NOT EXISTS {prefix}{table} (
+                id BIGINT PRIMARY KEY DEFAULT nextval('{prefix}{table}_id_seq'),
+                date_time TIMESTAMPTZ NOT NULL,
+                connector_id VARCHAR NOT NULL,
+                session_id VARCHAR NOT NULL,
+                sequence_number INTEGER NOT NULL,
+                local_endpoint VARCHAR NOT NULL,
+                remote_endpoint VARCHAR NOT NULL,
+                event VARCHAR NOT NULL,
+                data VARCHAR,
+                context VARCHAR,
+                UNIQUE (date_time, session_id, sequence_number)
+            );
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("events")
+            ));
+        }
+
+        // Create SMTP transactions tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            CREATE SEQUENCE IF NOT EXISTS {prefix}{table}_id_seq;
+            CREATE TABLE IF NOT EXISTS {prefix}{table} (
+                id BIGINT PRIMARY KEY DEFAULT nextval('{prefix}{table}_id_seq'),
+                date_time TIMESTAMPTZ NOT NULL,
+                connector_id VARCHAR NOT NULL,
+                session_id VARCHAR NOT NULL,
+                transaction_index INTEGER NOT NULL,
+                local_endpoint VARCHAR NOT NULL,
+                remote_endpoint VARCHAR NOT NULL,
+                sender VARCHAR,
+                recipient_count INTEGER NOT NULL,
+                size BIGINT,
+                message_id VARCHAR,
+                record_id VARCHAR,
+                response_code INTEGER,
+                response VARCHAR,
+                UNIQUE (date_time, session_id, transaction_index)
+            );
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("transactions")
+            ));
+        }
+
+        // Create SMTP recipients tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            CREATE SEQUENCE IF NOT EXISTS {prefix}{table}_id_seq;
+            CREATE TABLE IF NOT EXISTS {prefix}{table} (
+                id BIGINT PRIMARY KEY DEFAULT nextval('{prefix}{table}_id_seq'),
+                date_time TIMESTAMPTZ NOT NULL,
+                session_id VARCHAR NOT NULL,
+                sequence_number INTEGER NOT NULL,
+                transaction_index INTEGER NOT NULL,
+                recipient VARCHAR NOT NULL,
+                response_code INTEGER,
+                UNIQUE (date_time, session_id, sequence_number)
+            );
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("recipients")
+            ));
+        }
+
+        // Create Message Tracking logs table
+        schema.push_str(&format!(
+            r#"
+            CREATE SEQUENCE IF NOT EXISTS {prefix}message_tracking_logs_id_seq;
+            CREATE TABLE IF NOT EXISTS {prefix}message_tracking_logs (
+                id BIGINT PRIMARY KEY DEFAULT nextval('{prefix}message_tracking_logs_id_seq'),
+                date_time TIMESTAMPTZ NOT NULL,
+                client_ip VARCHAR,
+                client_hostname VARCHAR,
+                server_ip VARCHAR,
+                server_hostname VARCHAR NOT NULL,
+                source_context VARCHAR,
+                connector_id VARCHAR,
+                source VARCHAR,
+                event_id VARCHAR NOT NULL,
+                internal_message_id VARCHAR NOT NULL,
+                message_id VARCHAR NOT NULL,
+                network_message_id VARCHAR NOT NULL,
+                recipient_address VARCHAR NOT NULL,
+                recipient_status VARCHAR,
+                total_bytes BIGINT,
+                recipient_count INTEGER NOT NULL,
+                related_recipient_address VARCHAR,
+                reference VARCHAR,
+                message_subject VARCHAR,
+                sender_address VARCHAR NOT NULL,
+                return_path VARCHAR,
+                message_info VARCHAR,
+                directionality VARCHAR,
+                tenant_id VARCHAR,
+                original_client_ip VARCHAR,
+                original_server_ip VARCHAR,
+                custom_data VARCHAR,
+                transport_traffic_type VARCHAR,
+                log_id VARCHAR,
+                schema_version VARCHAR,
+                UNIQUE (date_time, internal_message_id, recipient_address, event_id)
+            );
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        // Create rejected lines table
+        schema.push_str(&format!(
+            r#"
+            CREATE SEQUENCE IF NOT EXISTS {prefix}rejected_lines_id_seq;
+            CREATE TABLE IF NOT EXISTS {prefix}rejected_lines (
+                id BIGINT PRIMARY KEY DEFAULT nextval('{prefix}rejected_lines_id_seq'),
+                rejected_at TIMESTAMPTZ NOT NULL,
+                file_path VARCHAR NOT NULL,
+                line_number INTEGER NOT NULL,
+                line VARCHAR NOT NULL,
+                reason VARCHAR NOT NULL,
+                UNIQUE (file_path, line_number)
+            );
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        // Create ingestion journal table
+        schema.push_str(&format!(
+            r#"
+            CREATE SEQUENCE IF NOT EXISTS {prefix}ingested_files_id_seq;
+            CREATE TABLE IF NOT EXISTS {prefix}ingested_files (
+                id BIGINT PRIMARY KEY DEFAULT nextval('{prefix}ingested_files_id_seq'),
+                path VARCHAR NOT NULL UNIQUE,
+                size BIGINT NOT NULL,
+                modified_at TIMESTAMPTZ NOT NULL,
+                content_hash VARCHAR NOT NULL,
+                byte_offset BIGINT NOT NULL,
+                line_number BIGINT NOT NULL,
+                log_type VARCHAR NOT NULL,
+                record_count BIGINT NOT NULL,
+                ingested_at TIMESTAMPTZ NOT NULL
+            );
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        schema
+    }
+
+    /// Bulk loads rows in one transaction, skipping rows conflicting with `unique_columns`
+    ///
+    /// The rows are appended to a temporary staging table with the appender API,
+    /// then copied into the table in a single `INSERT OR IGNORE ... SELECT`.
+    /// Returns the number of rows inserted.
+    async fn insert_rows<T: Send + 'static>(
+        &self,
+        table: &str,
+        columns: &'static [&'static str],
+        unique_columns: &'static str,
+        rows: Vec<T>,
+        values: fn(&T) -> Vec<&dyn ToSql>,
+    ) -> Result<u64> {
+        let table = format!("{}{}", self.table_prefix, table);
+        let staging = format!("{table}_staging");
+        let names = columns.join(", ");
+
+        self.run(move |connection| {
+            let tx = connection.transaction()?;
+            tx.execute_batch(&format!(
+                "CREATE OR REPLACE TEMP TABLE {staging} AS SELECT {names} FROM {table} LIMIT 0"
+            ))?;
+            {
+                let mut appender = tx.appender_to_catalog_and_db(&staging, "temp", "main")?;
+                for row in &rows {
+                    appender.append_row(values(row).as_slice())?;
+                }
+                appender.flush()?;
+            }
+            // Duplicates within the batch are dropped before the insert. The conflict
+            // target is required, the primary key is another unique constraint
+            let inserted_count = tx.execute(
+                &format!(
+                    "INSERT INTO {table} ({names})
+                    SELECT {names} FROM {staging}
+                    QUALIFY row_number() OVER (PARTITION BY {unique_columns}) = 1
+                    ON CONFLICT ({unique_columns}) DO NOTHING"
+                ),
+                [],
+            )?;
+            tx.execute_batch(&format!("DROP TABLE {staging}"))?;
+            tx.commit()?;
+            Ok(inserted_count as u64)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl Database for DuckDbDatabase {
+    async fn schema_version(&self) -> Result<i32> {
+        let table = format!("{}schema_version", self.table_prefix);
+
+        self.run(move |connection| {
+            let exists: bool = connection.query_row(
+                "SELECT EXISTS (SELECT 1 FROM information_schema.tables
+                WHERE table_schema = 'main' AND table_name = ?)",
+                params![table],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                return Ok(0);
+            }
+
+            let version = connection.query_row(
+                &format!("SELECT COALESCE(MAX(version), 0) FROM {table}"),
+                [],
+                |row| row.get(0),
+            )?;
+            Ok(version)
+        })
+        .await
+    }
+
+    fn migrations(&self) -> Vec<Migration> {
+        vec![Migration {
+            version: 1,
+            description: "initial schema",
+            sql: self.schema(),
+        }]
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        let prefix = self.table_prefix.clone();
+        let migration = migration.clone();
+        let version = migration.version;
+
+        self.run(move |connection| {
+            let tx = connection.transaction()?;
+
+            tx.execute_batch(&format!(
+                r#"
+                CREATE TABLE IF NOT EXISTS {prefix}schema_version (
+                    version INTEGER PRIMARY KEY,
+                    description VARCHAR NOT NULL,
+                    applied_at TIMESTAMPTZ NOT NULL DEFAULT current_timestamp
+                );
+                "#
+            ))?;
+
+            let applied: bool = tx.query_row(
+                &format!("SELECT EXISTS (SELECT 1 FROM {prefix}schema_version WHERE version = ?)"),
+                params![migration.version],
+                |row| row.get(0),
+            )?;
+
+            if !applied {
+                tx.execute_batch(&migration.sql)?;
+                tx.execute(
+                    &format!(
+                        "INSERT INTO {prefix}schema_version (version, description) VALUES (?, ?)"
+                    ),
+                    params![migration.version, migration.description],
+                )?;
+            }
+
+            tx.commit()?;
+            Ok(())
+        })
+        .await?;
+
+        info!("Database schema migrated to version {}", version);
+        Ok(())
+    }
+
+    async fn insert_smtp_receive_logs(&self, logs: Vec<SmtpReceiveLog>) -> Result<u64> {
+        if logs.is_empty() {
+            debug!("Нет SMTP Receive логов для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                "smtp_receive_logs",
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "sequence_number",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "event",
+                    "data",
+                    "context",
+                    "sender",
+                    "recipient",
+                    "message_id",
+                    "subject",
+                    "size",
+                ],
+                "date_time, session_id, sequence_number",
+                logs,
+                |log| {
+                    vec![
+                        &log.date_time,
+                        &log.connector_id,
+                        &log.session_id,
+                        &log.sequence_number,
+                        &log.local_endpoint,
+                        &log.remote_endpoint,
+                        &log.event,
+                        &log.data,
+                        &log.context,
+                        &log.sender,
+                        &log.recipient,
+                        &log.message_id,
+                        &log.subject,
+                        &log.size,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} SMTP Receive logs", inserted_count);
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_send_logs(&self, logs: Vec<SmtpSendLog>) -> Result<u64> {
+        if logs.is_empty() {
+            debug!("Нет SMTP Send логов для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                "smtp_send_logs",
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "sequence_number",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "event",
+                    "data",
+                    "context",
+                    "proxy_session_id",
+                    "sender",
+                    "recipient",
+                    "message_id",
+                    "record_id",
+                ],
+                "date_time, session_id, sequence_number",
+                logs,
+                |log| {
+                    vec![
+                        &log.date_time,
+                        &log.connector_id,
+                        &log.session_id,
+                        &log.sequence_number,
+                        &log.local_endpoint,
+                        &log.remote_endpoint,
+                        &log.event,
+                        &log.data,
+                        &log.context,
+                        &log.proxy_session_id,
+                        &log.sender,
+                        &log.recipient,
+                        &log.message_id,
+                        &log.record_id,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} SMTP Send logs", inserted_count);
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_events(
+        &self,
+        direction: SmtpDirection,
+        events: Vec<SmtpEvent>,
+    ) -> Result<u64> {
+        if events.is_empty() {
+            debug!("Нет событий SMTP для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                &direction.table_name("events"),
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "sequence_number",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "event",
+                    "data",
+                    "context",
+                ],
+                "date_time, session_id, sequence_number",
+                events,
+                |event| {
+                    vec![
+                        &event.date_time,
+                        &event.connector_id,
+                        &event.session_id,
+                        &event.sequence_number,
+                        &event.local_endpoint,
+                        &event.remote_endpoint,
+                        &event.event,
+                        &event.data,
+                        &event.context,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} {:?} SMTP events", inserted_count, direction);
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_transactions(
+        &self,
+        direction: SmtpDirection,
+        transactions: Vec<SmtpTransaction>,
+    ) -> Result<u64> {
+        if transactions.is_empty() {
+            debug!("Нет транзакций SMTP для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                &direction.table_name("transactions"),
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "transaction_index",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "sender",
+                    "recipient_count",
+                    "size",
+                    "message_id",
+                    "record_id",
+                    "response_code",
+                    "response",
+                ],
+                "date_time, session_id, transaction_index",
+                transactions,
+                |transaction| {
+                    vec![
+                        &transaction.date_time,
+                        &transaction.connector_id,
+                        &transaction.session_id,
+                        &transaction.transaction_index,
+                        &transaction.local_endpoint,
+                        &transaction.remote_endpoint,
+                        &transaction.sender,
+                        &transaction.recipient_count,
+                        &transaction.size,
+                        &transaction.message_id,
+                        &transaction.record_id,
+                        &transaction.response_code,
+                        &transaction.response,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!(
+            "Inserted {} {:?} SMTP transactions",
+            inserted_count, direction
+        );
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_recipients(
+        &self,
+        direction: SmtpDirection,
+        recipients: Vec<SmtpRecipient>,
+    ) -> Result<u64> {
+        if recipients.is_empty() {
+            debug!("Нет получателей SMTP для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                &direction.table_name("recipients"),
+                &[
+                    "date_time",
+                    "session_id",
+                    "sequence_number",
+                    "transaction_index",
+                    "recipient",
+                    "response_code",
+                ],
+                "date_time, session_id, sequence_number",
+                recipients,
+                |recipient| {
+                    vec![
+                        &recipient.date_time,
+                        &recipient.session_id,
+                        &recipient.sequence_number,
+                        &recipient.transaction_index,
+                        &recipient.recipient,
+                        &recipient.response_code,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!(
+            "Inserted {} {:?} SMTP recipients",
+            inserted_count, direction
+        );
+        Ok(inserted_count)
+    }
+
+    async fn insert_message_tracking_logs(&self, logs: Vec<MessageTrackingLog>) -> Result<u64> {
+        if logs.is_empty() {
+            debug!("Нет Message Tracking логов для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                "message_tracking_logs",
+                &[
+                    "date_time",
+                    "client_ip",
+                    "client_hostname",
+                    "server_ip",
+                    "server_hostname",
+                    "source_context",
+                    "connector_id",
+                    "source",
+                    "event_id",
+                    "internal_message_id",
+                    "message_id",
+                    "network_message_id",
+                    "recipient_address",
+                    "recipient_status",
+                    "total_bytes",
+                    "recipient_count",
+                    "related_recipient_address",
+                    "reference",
+                    "message_subject",
+                    "sender_address",
+                    "return_path",
+                    "message_info",
+                    "directionality",
+                    "tenant_id",
+                    "original_client_ip",
+                    "original_server_ip",
+                    "custom_data",
+                    "transport_traffic_type",
+                    "log_id",
+                    "schema_version",
+                ],
+                "date_time, internal_message_id, recipient_address, event_id",
+                logs,
+                |log| {
+                    vec![
+                        &log.date_time,
+                        &log.client_ip,
+                        &log.client_hostname,
+                        &log.server_ip,
+                        &log.server_hostname,
+                        &log.source_context,
+                        &log.connector_id,
+                        &log.source,
+                        &log.event_id,
+                        &log.internal_message_id,
+                        &log.message_id,
+                        &log.network_message_id,
+                        &log.recipient_address,
+                        &log.recipient_status,
+                        &log.total_bytes,
+                        &log.recipient_count,
+                        &log.related_recipient_address,
+                        &log.reference,
+                        &log.message_subject,
+                        &log.sender_address,
+                        &log.return_path,
+                        &log.message_info,
+                        &log.directionality,
+                        &log.tenant_id,
+                        &log.original_client_ip,
+                        &log.original_server_ip,
+                        &log.custom_data,
+                        &log.transport_traffic_type,
+                        &log.log_id,
+                        &log.schema_version,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} Message Tracking logs", inserted_count);
+        Ok(inserted_count)
+    }
+
+    async fn insert_rejected_lines(&self, lines: Vec<RejectedLine>) -> Result<u64> {
+        if lines.is_empty() {
+            debug!("Нет отклоненных строк для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                "rejected_lines",
+                &["rejected_at", "file_path", "line_number", "line", "reason"],
+                "file_path, line_number",
+                lines,
+                |line| {
+                    vec![
+                        &line.rejected_at,
+                        &line.file_path,
+                        &line.line_number,
+                        &line.line,
+                        &line.reason,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} rejected lines", inserted_count);
+        Ok(inserted_count)
+    }
+
+    async fn get_ingested_file(&self, path: &str) -> Result<Option<IngestedFile>> {
+        let sql = format!(
+            "SELECT id, path, size, modified_at, content_hash, byte_offset, line_number,
+            log_type, record_count, ingested_at
+            FROM {prefix}ingested_files WHERE path = ?",
+            prefix = self.table_prefix
+        );
+        let path = path.to_string();
+
+        self.run(move |connection| {
+            let file = connection
+                .query_row(&sql, params![path], |row| {
+                    Ok(IngestedFile {
+                        id: row.get("id")?,
+                        path: row.get("path")?,
+                        size: row.get("size")?,
+                        modified_at: row.get("modified_at")?,
+                        content_hash: row.get("content_hash")?,
+                        byte_offset: row.get("byte_offset")?,
+                        line_number: row.get("line_number")?,
+                        log_type: row.get("log_type")?,
+                        record_count: row.get("record_count")?,
+                        ingested_at: row.get("ingested_at")?,
+                    })
+                })
+                .optional()?;
+            Ok(file)
+        })
+        .await
+    }
+
+    async fn save_ingested_file(&self, file: IngestedFile) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO {prefix}ingested_files
+            (path, size, modified_at, content_hash, byte_offset, line_number, log_type,
+            record_count, ingested_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (path) DO UPDATE SET
+            size = excluded.size, modified_at = excluded.modified_at,
+            content_hash = excluded.content_hash, byte_offset = excluded.byte_offset,
+            line_number = excluded.line_number, log_type = excluded.log_type,
+            record_count = excluded.record_count, ingested_at = excluded.ingested_at",
+            prefix = self.table_prefix
+        );
+
+        let path = self
+            .run(move |connection| {
+                connection.execute(
+                    &sql,
+                    params![
+                        file.path,
+                        file.size,
+                        file.modified_at,
+                        file.content_hash,
+                        file.byte_offset,
+                        file.line_number,
+                        file.log_type,
+                        file.record_count,
+                        file.ingested_at,
+                    ],
+                )?;
+                Ok(file.path)
+            })
+            .await?;
+
+        debug!("Saved ingestion journal entry for {}", path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::InsertStats;
+    use crate::models::RecordBatch;
+    use chrono::{DateTime, Utc};
+
+    async fn database() -> DuckDbDatabase {
+        let db = DuckDbDatabase::new(":memory:", Some("test_"))
+            .await
+            .unwrap();
+        db.migrate().await.unwrap();
+        db
+    }
+
+    fn event(sequence_number: i32) -> SmtpEvent {
+        SmtpEvent {
+            id: None,
+            date_time: "2024-01-15T14:00:00.123456Z".parse().unwrap(),
+            connector_id: "MAIL01\\Default Frontend MAIL01".into(),
+            session_id: "08DC1234ABCD5678".into(),
+            sequence_number,
+            local_endpoint: "10.0.0.1:25".into(),
+            remote_endpoint: "10.0.0.2:50000".into(),
+            event: "<".into(),
+            data: Some("EHLO client.example.com".into()),
+            context: None,
+        }
+    }
+
+    fn batch(events: Vec<SmtpEvent>) -> RecordBatch {
+        let mut batch = RecordBatch::default();
+        batch.smtp_receive.events = events;
+        batch
+    }
+
+    #[tokio::test]
+    async fn skips_duplicates_within_and_across_batches() {
+        let db = database().await;
+        let events = || batch(vec![event(0), event(1), event(1)]);
+
+        let first = db.insert_batch(events()).await.unwrap();
+        assert_eq!(
+            first,
+            InsertStats {
+                inserted: 2,
+                duplicates: 1
+            }
+        );
+        let second = db.insert_batch(events()).await.unwrap();
+        assert_eq!(
+            second,
+            InsertStats {
+                inserted: 0,
+                duplicates: 3
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_back_appended_timestamps() {
+        let db = database().await;
+        db.insert_batch(batch(vec![event(0)])).await.unwrap();
+
+        let date_time: DateTime<Utc> = db
+            .run(|connection| {
+                Ok(connection.query_row(
+                    "SELECT date_time FROM test_smtp_receive_events",
+                    [],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .unwrap();
+        assert_eq!(date_time, event(0).date_time);
+    }
+
+    #[tokio::test]
+    async fn updates_ingestion_journal_entry() {
+        let db = database().await;
+        let mut file = IngestedFile {
+            id: None,
+            path: "/logs/RECV2024011514-1.LOG".into(),
+            size: 1024,
+            modified_at: "2024-01-15T14:59:59.999999Z".parse().unwrap(),
+            content_hash: "abc".into(),
+            byte_offset: 512,
+            line_number: 10,
+            log_type: "SmtpReceive".into(),
+            record_count: 8,
+            ingested_at: Utc::now(),
+        };
+        db.save_ingested_file(file.clone()).await.unwrap();
+        file.byte_offset = 1024;
+        db.save_ingested_file(file.clone()).await.unwrap();
+
+        let saved = db.get_ingested_file(&file.path).await.unwrap().unwrap();
+        assert_eq!(saved.byte_offset, 1024);
+        assert_eq!(saved.modified_at, file.modified_at);
+        assert!(
+            db.get_ingested_file("/logs/other.log")
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
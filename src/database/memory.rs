@@ -0,0 +1,112 @@
+use super::Database;
+use super::migrations::Migration;
+use crate::models::{
+    IngestedFile, MessageTrackingLog, RejectedLine, SmtpDirection, SmtpEvent, SmtpReceiveLog,
+    SmtpRecipient, SmtpSendLog, SmtpTransaction,
+};
+use async_trait::async_trait;
+use color_eyre::eyre::{Result, eyre};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Database kept in memory, for tests
+///
+/// This struct is used to test the code built on top of `Database` without a
+/// database server. Clones share their state, so a test can keep one to look at
+/// what was written through another.
+///
+/// ### Examples
+///
+/// ```
+/// let db = MemoryDatabase::default();
+/// let fanout = FanOut::new(vec![("db".into(), Box::new(db.clone()))], SinkErrorPolicy::Fail);
+/// ```
+#[derive(Clone, Default)]
+pub struct MemoryDatabase {
+    pub migrations: Vec<Migration>,
+    pub version: Arc<Mutex<i32>>,
+    /// Versions of the migrations applied, in order
+    pub applied: Arc<Mutex<Vec<i32>>>,
+    /// Number of rows inserted into all tables
+    pub rows: Arc<Mutex<u64>>,
+    pub journal: Arc<Mutex<HashMap<String, IngestedFile>>>,
+    /// Makes the inserts fail, as if the database was down
+    pub failing: Arc<AtomicBool>,
+}
+
+impl MemoryDatabase {
+    fn insert(&self, count: usize) -> Result<u64> {
+        if self.failing.load(Ordering::SeqCst) {
+            return Err(eyre!("Database is down"));
+        }
+        *self.rows.lock().unwrap() += count as u64;
+        Ok(count as u64)
+    }
+}
+
+#[async_trait]
+impl Database for MemoryDatabase {
+    async fn schema_version(&self) -> Result<i32> {
+        Ok(*self.version.lock().unwrap())
+    }
+
+    fn migrations(&self) -> Vec<Migration> {
+        self.migrations.clone()
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        self.applied.lock().unwrap().push(migration.version);
+        *self.version.lock().unwrap() = migration.version;
+        Ok(())
+    }
+
+    async fn insert_smtp_receive_logs(&self, logs: Vec<SmtpReceiveLog>) -> Result<u64> {
+        self.insert(logs.len())
+    }
+
+    async fn insert_smtp_send_logs(&self, logs: Vec<SmtpSendLog>) -> Result<u64> {
+        self.insert(logs.len())
+    }
+
+    async fn insert_smtp_events(
+        &self,
+        _direction: SmtpDirection,
+        events: Vec<SmtpEvent>,
+    ) -> Result<u64> {
+        self.insert(events.len())
+    }
+
+    async fn insert_smtp_transactions(
+        &self,
+        _direction: SmtpDirection,
+        transactions: Vec<SmtpTransaction>,
+    ) -> Result<u64> {
+        self.insert(transactions.len())
+    }
+
+    async fn insert_smtp_recipients(
+        &self,
+        _direction: SmtpDirection,
+        recipients: Vec<SmtpRecipient>,
+    ) -> Result<u64> {
+        self.insert(recipients.len())
+    }
+
+    async fn insert_message_tracking_logs(&self, logs: Vec<MessageTrackingLog>) -> Result<u64> {
+        self.insert(logs.len())
+    }
+
+    async fn insert_rejected_lines(&self, lines: Vec<RejectedLine>) -> Result<u64> {
+        self.insert(lines.len())
+    }
+
+    async fn get_ingested_file(&self, path: &str) -> Result<Option<IngestedFile>> {
+        Ok(self.journal.lock().unwrap().get(path).cloned())
+    }
+
+    async fn save_ingested_file(&self, file: IngestedFile) -> Result<()> {
+        self.journal.lock().unwrap().insert(file.path.clone(), file);
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+/// Schema migration
+///
+/// This struct is used to represent one step of the database schema upgrade.
+/// Migrations of a backend are applied in order of their versions, each in its
+/// own transaction, and the applied versions are recorded in `schema_version`.
+///
+/// ### Examples
+///
+/// ```
+/// let migration = Migration {
+///     version: 1,
+///     description: "initial schema",
+///     sql: "CREATE TABLE IF NOT EXISTS ...".to_string(),
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub version: i32,
+    pub description: &'static str,
+    pub sql: String,
+}
+
+/// Returns the version of the last migration, i.e. the schema version the program expects
+pub fn latest_version(migrations: &[Migration]) -> i32 {
+    migrations.iter().map(|m| m.version).max().unwrap_or(0)
+}
+
+/// Tables with an `id` identity column, without the table prefix
+pub const TABLES: [&str; 11] = [
+    "smtp_receive_logs",
+    "smtp_send_logs",
+    "smtp_receive_events",
+    "smtp_send_events",
+    "smtp_receive_transactions",
+    "smtp_send_transactions",
+    "smtp_receive_recipients",
+    "smtp_send_recipients",
+    "message_tracking_logs",
+    "rejected_lines",
+    "ingested_files",
+];
+
+/// Columns holding message sizes in bytes, as `(table, column)`
+pub const BYTE_COUNTERS: [(&str, &str); 4] = [
+    ("smtp_receive_logs", "size"),
+    ("smtp_receive_transactions", "size"),
+    ("smtp_send_transactions", "size"),
+    ("message_tracking_logs", "total_bytes"),
+];
@@ -1,14 +1,89 @@
-use crate::models::{MessageTrackingLog, SmtpReceiveLog, SmtpSendLog};
+use crate::models::{
+    IngestedFile, MessageTrackingLog, RecordBatch, RejectedLine, SmtpDirection, SmtpEvent,
+    SmtpReceiveLog, SmtpRecipient, SmtpRecords, SmtpSendLog, SmtpTransaction,
+};
 use async_trait::async_trait;
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{Result, eyre};
+use log::info;
+use migrations::Migration;
 
+pub mod duckdb;
+#[cfg(test)]
+pub mod memory;
+pub mod migrations;
 pub mod mssql;
+pub mod mysql;
 pub mod postgres;
+pub mod sqlite;
+
+/// Result of inserting a batch of rows
+///
+/// This struct is used to report the rows actually inserted separately from the
+/// rows skipped because they were already in the database (or repeated in the batch).
+///
+/// ### Examples
+///
+/// ```
+/// let stats = db.insert_batch(batch).await?;
+/// info!("{} rows inserted, {} duplicates skipped", stats.inserted, stats.duplicates);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InsertStats {
+    pub inserted: u64,
+    pub duplicates: u64,
+}
+
+impl std::ops::AddAssign for InsertStats {
+    fn add_assign(&mut self, other: Self) {
+        self.inserted += other.inserted;
+        self.duplicates += other.duplicates;
+    }
+}
 
 #[async_trait]
 pub trait Database: Send + Sync {
-    /// Инициализирует таблицы в базе данных
-    async fn init_tables(&self) -> Result<()>;
+    /// Возвращает текущую версию схемы БД (0, если миграции еще не выполнялись)
+    async fn schema_version(&self) -> Result<i32>;
+
+    /// Возвращает миграции схемы для этой СУБД в порядке возрастания версии
+    fn migrations(&self) -> Vec<Migration>;
+
+    /// Выполняет миграцию и записывает ее версию в `schema_version` в одной транзакции
+    async fn apply_migration(&self, migration: &Migration) -> Result<()>;
+
+    /// Проверяет, что схема БД не новее, чем известно этой версии программы,
+    /// и возвращает ее версию
+    async fn check_schema_version(&self) -> Result<i32> {
+        let current = self.schema_version().await?;
+        let latest = migrations::latest_version(&self.migrations());
+        if current > latest {
+            return Err(eyre!(
+                "Схема базы данных имеет версию {}, а эта версия программы поддерживает версии до {}. Обновите exchange-log-parser",
+                current,
+                latest
+            ));
+        }
+        Ok(current)
+    }
+
+    /// Применяет недостающие миграции, возвращает число примененных миграций
+    async fn migrate(&self) -> Result<usize> {
+        let current = self.check_schema_version().await?;
+        let pending: Vec<Migration> = self
+            .migrations()
+            .into_iter()
+            .filter(|migration| migration.version > current)
+            .collect();
+
+        for migration in &pending {
+            info!(
+                "Applying migration {}: {}",
+                migration.version, migration.description
+            );
+            self.apply_migration(migration).await?;
+        }
+        Ok(pending.len())
+    }
 
     /// Вставляет логи SMTP Receive
     async fn insert_smtp_receive_logs(&self, logs: Vec<SmtpReceiveLog>) -> Result<u64>;
@@ -16,14 +91,91 @@ pub trait Database: Send + Sync {
     /// Вставляет логи SMTP Send
     async fn insert_smtp_send_logs(&self, logs: Vec<SmtpSendLog>) -> Result<u64>;
 
+    /// Вставляет события SMTP-протокола (по одной записи на строку лога)
+    async fn insert_smtp_events(
+        &self,
+        direction: SmtpDirection,
+        events: Vec<SmtpEvent>,
+    ) -> Result<u64>;
+
+    /// Вставляет транзакции (отдельные письма) SMTP-сессий
+    async fn insert_smtp_transactions(
+        &self,
+        direction: SmtpDirection,
+        transactions: Vec<SmtpTransaction>,
+    ) -> Result<u64>;
+
+    /// Вставляет получателей (RCPT TO) SMTP-сессий
+    async fn insert_smtp_recipients(
+        &self,
+        direction: SmtpDirection,
+        recipients: Vec<SmtpRecipient>,
+    ) -> Result<u64>;
+
     /// Вставляет логи Message Tracking
     async fn insert_message_tracking_logs(&self, logs: Vec<MessageTrackingLog>) -> Result<u64>;
+
+    /// Вставляет строки логов, которые не удалось разобрать (карантин)
+    async fn insert_rejected_lines(&self, lines: Vec<RejectedLine>) -> Result<u64>;
+
+    /// Возвращает запись журнала загрузки для файла, если файл уже обрабатывался
+    async fn get_ingested_file(&self, path: &str) -> Result<Option<IngestedFile>>;
+
+    /// Добавляет или обновляет запись журнала загрузки
+    async fn save_ingested_file(&self, file: IngestedFile) -> Result<()>;
+
+    /// Вставляет пакет записей всех типов
+    ///
+    /// Возвращает число вставленных строк и число строк, пропущенных как дубликаты.
+    /// Каждая таблица вставляется в своей транзакции, при ошибке она откатывается.
+    async fn insert_batch(&self, batch: RecordBatch) -> Result<InsertStats> {
+        let rows = batch.len() as u64;
+        let mut inserted = 0;
+        inserted += self
+            .insert_smtp_receive_logs(batch.smtp_receive_logs)
+            .await?;
+        inserted += self.insert_smtp_send_logs(batch.smtp_send_logs).await?;
+        inserted += self
+            .insert_smtp_records(SmtpDirection::Receive, batch.smtp_receive)
+            .await?;
+        inserted += self
+            .insert_smtp_records(SmtpDirection::Send, batch.smtp_send)
+            .await?;
+        inserted += self
+            .insert_message_tracking_logs(batch.message_tracking_logs)
+            .await?;
+        inserted += self.insert_rejected_lines(batch.rejected_lines).await?;
+        Ok(InsertStats {
+            inserted,
+            duplicates: rows - inserted,
+        })
+    }
+
+    /// Вставляет события, транзакции и получателей SMTP-сессий одного направления
+    async fn insert_smtp_records(
+        &self,
+        direction: SmtpDirection,
+        records: SmtpRecords,
+    ) -> Result<u64> {
+        let mut inserted = 0;
+        inserted += self.insert_smtp_events(direction, records.events).await?;
+        inserted += self
+            .insert_smtp_transactions(direction, records.transactions)
+            .await?;
+        inserted += self
+            .insert_smtp_recipients(direction, records.recipients)
+            .await?;
+        Ok(inserted)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum DatabaseType {
     Postgres,
     MsSql,
+    MySql,
+    Sqlite,
+    DuckDb,
 }
 
 impl std::str::FromStr for DatabaseType {
@@ -33,6 +185,9 @@ impl std::str::FromStr for DatabaseType {
         match s.to_lowercase().as_str() {
             "postgres" => Ok(DatabaseType::Postgres),
             "mssql" => Ok(DatabaseType::MsSql),
+            "mysql" | "mariadb" => Ok(DatabaseType::MySql),
+            "sqlite" => Ok(DatabaseType::Sqlite),
+            "duckdb" => Ok(DatabaseType::DuckDb),
             _ => Err(color_eyre::eyre::eyre!(
                 "Неподдерживаемый тип базы данных: {}",
                 s
@@ -41,26 +196,142 @@ impl std::str::FromStr for DatabaseType {
     }
 }
 
+/// Connection settings of the database
+///
+/// This struct is used to pass the `--db-*` arguments to `create_database`.
+///
+/// ### Examples
+///
+/// ```
+/// let options = ConnectionOptions { host: "localhost".into(), port: 5432, ..Default::default() };
+/// let db = create_database(DatabaseType::Postgres, &options).await?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionOptions {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+    pub dbname: String,
+    pub table_prefix: Option<String>,
+    pub postgres_ssl: postgres::SslOptions,
+    pub mssql: mssql::MsSqlOptions,
+}
+
 pub async fn create_database(
     db_type: DatabaseType,
-    host: &str,
-    port: u16,
-    user: &str,
-    password: &str,
-    dbname: &str,
-    table_prefix: Option<&str>,
+    options: &ConnectionOptions,
 ) -> Result<Box<dyn Database>> {
+    let ConnectionOptions {
+        host,
+        port,
+        user,
+        password,
+        dbname,
+        ..
+    } = options;
+    let port = *port;
+    let table_prefix = options.table_prefix.as_deref();
     match db_type {
         DatabaseType::Postgres => {
-            let db =
-                postgres::PostgresDatabase::new(host, port, user, password, dbname, table_prefix)
-                    .await?;
+            let db = postgres::PostgresDatabase::new(
+                host,
+                port,
+                user,
+                password,
+                dbname,
+                table_prefix,
+                &options.postgres_ssl,
+            )
+            .await?;
             Ok(Box::new(db))
         }
         DatabaseType::MsSql => {
+            let db = mssql::MsSqlDatabase::new(
+                host,
+                port,
+                user,
+                password,
+                dbname,
+                table_prefix,
+                &options.mssql,
+            )
+            .await?;
+            Ok(Box::new(db))
+        }
+        DatabaseType::MySql => {
             let db =
-                mssql::MsSqlDatabase::new(host, port, user, password, dbname, table_prefix).await?;
+                mysql::MySqlDatabase::new(host, port, user, password, dbname, table_prefix).await?;
+            Ok(Box::new(db))
+        }
+        DatabaseType::Sqlite => {
+            // Для SQLite имя базы данных - путь к файлу
+            let db = sqlite::SqliteDatabase::new(dbname, table_prefix).await?;
+            Ok(Box::new(db))
+        }
+        DatabaseType::DuckDb => {
+            // Для DuckDB имя базы данных - тоже путь к файлу
+            let db = duckdb::DuckDbDatabase::new(dbname, table_prefix).await?;
             Ok(Box::new(db))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::memory::MemoryDatabase;
+    use super::*;
+
+    fn database(version: i32, versions: &[i32]) -> MemoryDatabase {
+        let db = MemoryDatabase {
+            migrations: versions
+                .iter()
+                .map(|&version| Migration {
+                    version,
+                    description: "test",
+                    sql: String::new(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+        *db.version.lock().unwrap() = version;
+        db
+    }
+
+    #[tokio::test]
+    async fn applies_only_pending_migrations_in_order() {
+        let db = database(1, &[1, 2, 3]);
+
+        assert_eq!(db.migrate().await.unwrap(), 2);
+        assert_eq!(*db.applied.lock().unwrap(), vec![2, 3]);
+        assert_eq!(db.schema_version().await.unwrap(), 3);
+
+        // Nothing is left to apply on the next start
+        assert_eq!(db.migrate().await.unwrap(), 0);
+        assert_eq!(*db.applied.lock().unwrap(), vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn applies_all_migrations_to_empty_database() {
+        let db = database(0, &[1, 2]);
+
+        assert_eq!(db.migrate().await.unwrap(), 2);
+        assert_eq!(*db.applied.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn refuses_schema_newer_than_the_program() {
+        let db = database(3, &[1, 2]);
+
+        let error = db.migrate().await.unwrap_err();
+        assert!(
+            error.to_string().starts_with(
+                "Схема базы данных имеет версию 3, а эта версия программы поддерживает версии до 2"
+            ),
+            "{}",
+            error
+        );
+        assert!(db.applied.lock().unwrap().is_empty());
+        assert!(db.check_schema_version().await.is_err());
+    }
+}
@@ -1,12 +1,113 @@
-use crate::models::{MessageTrackingLog, SmtpReceiveLog, SmtpSendLog};
+use crate::models::{
+    IngestedFile, MessageTrackingLog, RejectedLine, SmtpDirection, SmtpEvent, SmtpReceiveLog,
+    SmtpRecipient, SmtpSendLog, SmtpTransaction,
+};
 use async_trait::async_trait;
-use bb8::Pool;
+use bb8::{ManageConnection, Pool};
 use bb8_tiberius::ConnectionManager;
-use color_eyre::eyre::Result;
-use log::{debug, info};
-use tiberius::{AuthMethod, Config, Query};
+use color_eyre::eyre::{Report, Result, eyre};
+use futures::io::{AsyncRead, AsyncWrite};
+use log::{debug, error, info, warn};
+use std::path::{Path, PathBuf};
+use tiberius::{AuthMethod, Client, Config, EncryptionLevel, IntoSql, Query, TokenRow};
 
 use super::Database;
+use super::migrations::{BYTE_COUNTERS, Migration, TABLES};
+
+/// Builds a bulk load row from values convertible with `IntoSql`
+macro_rules! token_row {
+    ($($value:expr),* $(,)?) => {{
+        let mut row = TokenRow::new();
+        $(row.push(IntoSql::into_sql($value));)*
+        row
+    }};
+}
+
+/// Encryption of the connection to MS SQL Server
+///
+/// This enum is used to choose with `--mssql-encryption` what is sent over TLS.
+///
+/// ### Examples
+///
+/// ```
+/// let encryption: MsSqlEncryption = "required".parse()?;
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsSqlEncryption {
+    /// Only the login is encrypted
+    Off,
+    /// Everything is encrypted if the server supports it
+    On,
+    /// Everything is encrypted, the connection fails if the server does not support it
+    Required,
+}
+
+impl std::str::FromStr for MsSqlEncryption {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(MsSqlEncryption::Off),
+            "on" => Ok(MsSqlEncryption::On),
+            "required" => Ok(MsSqlEncryption::Required),
+            _ => Err(eyre!(
+                "Неподдерживаемый уровень шифрования MS SQL Server: {}",
+                s
+            )),
+        }
+    }
+}
+
+impl From<MsSqlEncryption> for EncryptionLevel {
+    fn from(encryption: MsSqlEncryption) -> Self {
+        match encryption {
+            MsSqlEncryption::Off => EncryptionLevel::Off,
+            MsSqlEncryption::On => EncryptionLevel::On,
+            MsSqlEncryption::Required => EncryptionLevel::Required,
+        }
+    }
+}
+
+/// Connection settings of MS SQL Server besides the `--db-*` arguments
+///
+/// This struct is used to pass the `--mssql-*` arguments to `MsSqlDatabase::new`.
+/// The certificate of the server is validated against the system trust store
+/// unless a CA certificate is given or validation is turned off explicitly.
+#[derive(Debug, Clone, Default)]
+pub struct MsSqlOptions {
+    /// ADO.NET connection string used instead of the `--db-*` arguments
+    pub connection_string: Option<String>,
+    /// Named instance, its port is resolved through the SQL Server Browser
+    pub instance: Option<String>,
+    /// Encryption level (the one of the connection string or `required` when not set)
+    pub encryption: Option<MsSqlEncryption>,
+    /// Certificate of the CA that issued the certificate of the server (PEM, CRT or DER)
+    pub ca_cert: Option<PathBuf>,
+    /// Accept any certificate of the server
+    pub trust_server_certificate: bool,
+}
+
+impl MsSqlOptions {
+    /// Rejects the combinations of options that tiberius cannot honor
+    ///
+    /// The connection string has its own settings of the instance and the
+    /// certificate, and tiberius panics when both ways of validation are set.
+    pub fn validate(&self) -> Result<()> {
+        if self.connection_string.is_some()
+            && (self.instance.is_some() || self.ca_cert.is_some() || self.trust_server_certificate)
+        {
+            return Err(eyre!(
+                "--mssql-connection-string нельзя совмещать с --mssql-instance, --mssql-ca-cert и --mssql-trust-server-certificate"
+            ));
+        }
+        if self.ca_cert.is_some() && self.trust_server_certificate {
+            return Err(eyre!(
+                "--mssql-ca-cert и --mssql-trust-server-certificate нельзя указывать вместе"
+            ));
+        }
+        Ok(())
+    }
+}
 
 pub struct MsSqlDatabase {
     pool: Pool<ConnectionManager>,
@@ -21,34 +122,69 @@ impl MsSqlDatabase {
         password: &str,
         dbname: &str,
         table_prefix: Option<&str>,
+        options: &MsSqlOptions,
     ) -> Result<Self> {
-        let mut config = Config::new();
-        config.host(host);
-        config.port(port);
-        config.database(dbname);
-        config.authentication(AuthMethod::sql_server(user, password));
-        config.trust_cert(); // В продакшене нужно настроить правильную проверку сертификата
-
-        let manager = ConnectionManager::build(config)?;
+        options.validate()?;
+
+        let mut config = match &options.connection_string {
+            Some(connection_string) => Config::from_ado_string(connection_string)
+                .map_err(|e| eyre!("Некорректная строка подключения MS SQL Server: {}", e))?,
+            None => {
+                let mut config = Config::new();
+                config.host(host);
+                match &options.instance {
+                    // The port of a named instance is asked from the SQL Server Browser
+                    Some(instance) => config.instance_name(instance),
+                    None => config.port(port),
+                }
+                config.database(dbname);
+                config.authentication(AuthMethod::sql_server(user, password));
+                config
+            }
+        };
+        if let Some(encryption) = options.encryption {
+            config.encryption(encryption.into());
+        }
+        match &options.ca_cert {
+            Some(ca_cert) => {
+                check_ca_certificate(ca_cert)?;
+                config.trust_cert_ca(ca_cert.display());
+            }
+            None if options.trust_server_certificate => {
+                warn!("The certificate of MS SQL Server is accepted without validation");
+                config.trust_cert();
+            }
+            None => {}
+        }
+
+        let address = config.get_addr();
+        let manager = ConnectionManager::new(config).using_named_connection();
+
+        drop(
+            manager
+                .connect()
+                .await
+                .map_err(|e| connection_error(e, &address))?,
+        );
+        debug!("Connected to MS SQL Server {}", address);
+
         let pool = Pool::builder().build(manager).await?;
 
         let db = MsSqlDatabase {
             pool,
             table_prefix: table_prefix.unwrap_or("").to_string(),
         };
-        db.init_tables().await?;
 
         Ok(db)
     }
-}
 
-#[async_trait]
-impl Database for MsSqlDatabase {
-    async fn init_tables(&self) -> Result<()> {
-        let mut client = self.pool.get().await?;
+    /// Schema of the first version: the tables as created by `IF NOT EXISTS ... CREATE TABLE`
+    /// before versioned migrations were introduced
+    fn initial_schema(&self) -> String {
+        let mut schema = String::new();
 
         // Create SMTP Receive logs table
-        let sql_smtp_receive = format!(
+        schema.push_str(&format!(
             r#"
             IF NOT EXISTS (SELECT * FROM sys.objects WHERE object_id = OBJECT_ID(N'[dbo].[{prefix}smtp_receive_logs]') AND type in (N'U'))
             BEGIN
@@ -79,12 +215,10 @@ impl Database for MsSqlDatabase {
             END
             "#,
             prefix = self.table_prefix
-        );
-        let query = Query::new(sql_smtp_receive.as_str());
-        query.execute(&mut client).await?;
+        ));
 
         // Create SMTP Send logs table
-        let sql_smtp_send = format!(
+        schema.push_str(&format!(
             r#"
             IF NOT EXISTS (SELECT * FROM sys.objects WHERE object_id = OBJECT_ID(N'[dbo].[{prefix}smtp_send_logs]') AND type in (N'U'))
             BEGIN
@@ -115,12 +249,117 @@ impl Database for MsSqlDatabase {
             END
             "#,
             prefix = self.table_prefix
-        );
-        let query = Query::new(sql_smtp_send.as_str());
-        query.execute(&mut client).await?;
+        ));
+
+        // Create SMTP protocol events tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            IF NOT EXISTS (SELECT * FROM sys.objects WHERE object_id = OBJECT_ID(N'[dbo].[{prefix}{table}]') AND type in (N'U'))
+            BEGIN
+                CREATE TABLE [dbo].[{prefix}{table}] (
+                    [id] [int] IDENTITY(1,1) PRIMARY KEY,
+                    [date_time] [datetimeoffset](7) NOT NULL,
+                    [connector_id] [nvarchar](max) NOT NULL,
+                    [session_id] [nvarchar](450) NOT NULL,
+                    [sequence_number] [int] NOT NULL,
+                    [local_endpoint] [nvarchar](max) NOT NULL,
+                    [remote_endpoint] [nvarchar](max) NOT NULL,
+                    [event] [nvarchar](max) NOT NULL,
+                    [data] [nvarchar](max) NULL,
+                    [context] [nvarchar](max) NULL
+                )
+
+                CREATE UNIQUE NONCLUSTERED INDEX [IX_{prefix}{table}_unique] ON [dbo].[{prefix}{table}]
+                (
+                    [date_time] ASC,
+                    [session_id] ASC,
+                    [sequence_number] ASC
+                )
+            END
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("events")
+            ));
+        }
+
+        // Create SMTP transactions tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            IF NOT EXISTS (SELECT * FROM sys.objects WHERE object_id = OBJECT_ID(N'[dbo].[{prefix}{table}]') AND type in (N'U'))
+            BEGIN
+                CREATE TABLE [dbo].[{prefix}{table}] (
+                    [id] [int] IDENTITY(1,1) PRIMARY KEY,
+                    [date_time] [datetimeoffset](7) NOT NULL,
+                    [connector_id] [nvarchar](max) NOT NULL,
+                    [session_id] [nvarchar](450) NOT NULL,
+                    [transaction_index] [int] NOT NULL,
+                    [local_endpoint] [nvarchar](max) NOT NULL,
+                    [remote_endpoint] [nvarchar](max) NOT NULL,
+                    [sender] [nvarchar](max) NULL,
+                    [recipient_count] [int] NOT NULL,
+                    [size] [int] NULL,
+                    [message_id] [nvarchar](450) NULL,
+                    [record_id] [nvarchar](max) NULL,
+                    [response_code] [int] NULL,
+                    [response] [nvarchar](max) NULL
+                )
+
+                CREATE UNIQUE NONCLUSTERED INDEX [IX_{prefix}{table}_unique] ON [dbo].[{prefix}{table}]
+                (
+                    [date_time] ASC,
+                    [session_id] ASC,
+                    [transaction_index] ASC
+                )
+
+                CREATE NONCLUSTERED INDEX [IX_{prefix}{table}_message_id] ON [dbo].[{prefix}{table}]
+                (
+                    [message_id] ASC
+                )
+            END
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("transactions")
+            ));
+        }
+
+        // Create SMTP recipients tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            IF NOT EXISTS (SELECT * FROM sys.objects WHERE object_id = OBJECT_ID(N'[dbo].[{prefix}{table}]') AND type in (N'U'))
+            BEGIN
+                CREATE TABLE [dbo].[{prefix}{table}] (
+                    [id] [int] IDENTITY(1,1) PRIMARY KEY,
+                    [date_time] [datetimeoffset](7) NOT NULL,
+                    [session_id] [nvarchar](450) NOT NULL,
+                    [sequence_number] [int] NOT NULL,
+                    [transaction_index] [int] NOT NULL,
+                    [recipient] [nvarchar](450) NOT NULL,
+                    [response_code] [int] NULL
+                )
+
+                CREATE UNIQUE NONCLUSTERED INDEX [IX_{prefix}{table}_unique] ON [dbo].[{prefix}{table}]
+                (
+                    [date_time] ASC,
+                    [session_id] ASC,
+                    [sequence_number] ASC
+                )
+
+                CREATE NONCLUSTERED INDEX [IX_{prefix}{table}_recipient] ON [dbo].[{prefix}{table}]
+                (
+                    [recipient] ASC
+                )
+            END
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("recipients")
+            ));
+        }
 
         // Create Message Tracking logs table
-        let sql_msg_tracking = format!(
+        schema.push_str(&format!(
             r#"
             IF NOT EXISTS (SELECT * FROM sys.objects WHERE object_id = OBJECT_ID(N'[dbo].[{prefix}message_tracking_logs]') AND type in (N'U'))
             BEGIN
@@ -168,59 +407,408 @@ impl Database for MsSqlDatabase {
             END
             "#,
             prefix = self.table_prefix
-        );
-        let query = Query::new(sql_msg_tracking.as_str());
-        query.execute(&mut client).await?;
+        ));
 
-        info!("Database tables initialized successfully");
-        Ok(())
+        // Create rejected lines table
+        schema.push_str(&format!(
+            r#"
+            IF NOT EXISTS (SELECT * FROM sys.objects WHERE object_id = OBJECT_ID(N'[dbo].[{prefix}rejected_lines]') AND type in (N'U'))
+            BEGIN
+                CREATE TABLE [dbo].[{prefix}rejected_lines] (
+                    [id] [int] IDENTITY(1,1) PRIMARY KEY,
+                    [rejected_at] [datetimeoffset](7) NOT NULL,
+                    [file_path] [nvarchar](450) NOT NULL,
+                    [line_number] [int] NOT NULL,
+                    [line] [nvarchar](max) NOT NULL,
+                    [reason] [nvarchar](max) NOT NULL
+                )
+
+                CREATE UNIQUE NONCLUSTERED INDEX [IX_{prefix}rejected_lines_unique] ON [dbo].[{prefix}rejected_lines]
+                (
+                    [file_path] ASC,
+                    [line_number] ASC
+                )
+            END
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        // Create ingestion journal table
+        schema.push_str(&format!(
+            r#"
+            IF NOT EXISTS (SELECT * FROM sys.objects WHERE object_id = OBJECT_ID(N'[dbo].[{prefix}ingested_files]') AND type in (N'U'))
+            BEGIN
+                CREATE TABLE [dbo].[{prefix}ingested_files] (
+                    [id] [int] IDENTITY(1,1) PRIMARY KEY,
+                    [path] [nvarchar](450) NOT NULL,
+                    [size] [bigint] NOT NULL,
+                    [modified_at] [datetimeoffset](7) NOT NULL,
+                    [content_hash] [nvarchar](64) NOT NULL,
+                    [byte_offset] [bigint] NOT NULL,
+                    [line_number] [bigint] NOT NULL,
+                    [log_type] [nvarchar](max) NOT NULL,
+                    [record_count] [bigint] NOT NULL,
+                    [ingested_at] [datetimeoffset](7) NOT NULL
+                )
+
+                CREATE UNIQUE NONCLUSTERED INDEX [IX_{prefix}ingested_files_unique] ON [dbo].[{prefix}ingested_files]
+                (
+                    [path] ASC
+                )
+            END
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        schema
     }
 
-    async fn insert_smtp_receive_logs(&self, logs: Vec<SmtpReceiveLog>) -> Result<u64> {
-        if logs.is_empty() {
-            debug!("Нет SMTP Receive логов для вставки");
-            return Ok(0);
+    /// Schema of the second version: 64-bit identifiers and message sizes
+    fn bigint_schema(&self) -> String {
+        let mut schema = String::from("DECLARE @pk sysname, @sql nvarchar(max);\n");
+
+        // The type of a primary key column can only be changed with the key dropped,
+        // and the key created with the table has a generated name
+        for table in TABLES {
+            schema.push_str(&format!(
+                r#"
+            SELECT @pk = [name] FROM sys.key_constraints
+            WHERE [parent_object_id] = OBJECT_ID(N'[dbo].[{prefix}{table}]') AND [type] = N'PK';
+            SET @sql = N'ALTER TABLE [dbo].[{prefix}{table}] DROP CONSTRAINT ' + QUOTENAME(@pk);
+            EXEC(@sql);
+            ALTER TABLE [dbo].[{prefix}{table}] ALTER COLUMN [id] [bigint] NOT NULL;
+            ALTER TABLE [dbo].[{prefix}{table}] ADD CONSTRAINT [PK_{prefix}{table}] PRIMARY KEY ([id]);
+            "#,
+                prefix = self.table_prefix
+            ));
+        }
+
+        for (table, column) in BYTE_COUNTERS {
+            schema.push_str(&format!(
+                r#"
+            ALTER TABLE [dbo].[{prefix}{table}] ALTER COLUMN [{column}] [bigint] NULL;
+            "#,
+                prefix = self.table_prefix
+            ));
         }
 
+        schema
+    }
+
+    /// Loads rows with a TDS bulk insert into a `#staging` temp table and moves
+    /// them into the target table with a set-based `INSERT ... WHERE NOT EXISTS`
+    ///
+    /// Rows matching `unique_columns` (already in the table or repeated in the
+    /// batch) are skipped. Returns the number of rows inserted.
+    async fn bulk_rows<T: Sync>(
+        &self,
+        table: &str,
+        columns: &[&str],
+        unique_columns: &[&str],
+        rows: &[T],
+        values: impl for<'r> Fn(&'r T) -> TokenRow<'r> + Send + Sync,
+    ) -> Result<u64> {
         let mut client = self.pool.get().await?;
-        let mut inserted_count = 0;
 
-        client.simple_query("BEGIN TRANSACTION").await?;
+        let statements = BulkStatements::new(&self.table_prefix, table, columns, unique_columns);
 
-        for log in logs {
-            let sql = format!(
-                r#"
-                INSERT INTO [dbo].[{prefix}smtp_receive_logs]
-                (date_time, connector_id, session_id, sequence_number, local_endpoint, remote_endpoint,
-                event, data, context, sender, recipient, message_id, subject, size)
-                VALUES (@P1, @P2, @P3, @P4, @P5, @P6, @P7, @P8, @P9, @P10, @P11, @P12, @P13, @P14)
-                "#,
-                prefix = self.table_prefix
-            );
-            let mut query = Query::new(sql.as_str());
-
-            query.bind(log.date_time);
-            query.bind(&log.connector_id);
-            query.bind(&log.session_id);
-            query.bind(log.sequence_number);
-            query.bind(&log.local_endpoint);
-            query.bind(&log.remote_endpoint);
-            query.bind(&log.event);
-            query.bind(log.data.as_deref());
-            query.bind(log.context.as_deref());
-            query.bind(log.sender.as_deref());
-            query.bind(log.recipient.as_deref());
-            query.bind(log.message_id.as_deref());
-            query.bind(log.subject.as_deref());
-            query.bind(log.size);
-
-            let result = query.execute(&mut client).await?;
-            if let Some(rows) = result.rows_affected().first() {
-                inserted_count += *rows as u64;
+        client
+            .simple_query("SET XACT_ABORT ON; BEGIN TRANSACTION")
+            .await?
+            .into_results()
+            .await?;
+
+        let result: Result<u64> = async {
+            // The temp table has to be created in a batch rather than through sp_executesql,
+            // otherwise it is dropped as soon as the statement ends
+            client
+                .simple_query(&statements.create)
+                .await?
+                .into_results()
+                .await?;
+
+            let mut bulk = client.bulk_insert(&statements.staging).await?;
+            for row in rows {
+                bulk.send(values(row)).await?;
             }
+            bulk.finalize().await?;
+
+            let result = client.execute(&statements.insert, &[]).await?;
+
+            client
+                .simple_query(format!(
+                    "DROP TABLE {}; COMMIT TRANSACTION",
+                    statements.staging
+                ))
+                .await?
+                .into_results()
+                .await?;
+
+            Ok(result.total())
         }
+        .await;
 
-        client.simple_query("COMMIT TRANSACTION").await?;
+        if result.is_err() {
+            rollback(&mut client).await;
+        }
+        result
+    }
+}
+
+/// Statements of a bulk load through a `#staging` temp table
+///
+/// This struct is used to build the SQL of `bulk_rows` apart from the connection.
+#[derive(Debug, PartialEq)]
+struct BulkStatements {
+    staging: String,
+    create: String,
+    insert: String,
+}
+
+impl BulkStatements {
+    fn new(prefix: &str, table: &str, columns: &[&str], unique_columns: &[&str]) -> Self {
+        let target = format!("[dbo].[{}{}]", prefix, table);
+        let staging = format!("#{}_staging", table);
+        let names = columns
+            .iter()
+            .map(|column| format!("[{column}]"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let partition = unique_columns
+            .iter()
+            .map(|column| format!("[{column}]"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let matches = unique_columns
+            .iter()
+            .map(|column| format!("[t].[{column}] = [s].[{column}]"))
+            .collect::<Vec<_>>()
+            .join(" AND ");
+
+        BulkStatements {
+            create: format!(
+                "IF OBJECT_ID(N'tempdb..{staging}') IS NOT NULL DROP TABLE {staging}; \
+                SELECT TOP 0 {names} INTO {staging} FROM {target};"
+            ),
+            insert: format!(
+                "INSERT INTO {target} ({names}) \
+                SELECT {names} FROM ( \
+                SELECT {names}, ROW_NUMBER() OVER (PARTITION BY {partition} ORDER BY (SELECT NULL)) AS [row_number] \
+                FROM {staging} \
+                ) AS [s] \
+                WHERE [s].[row_number] = 1 \
+                AND NOT EXISTS (SELECT 1 FROM {target} AS [t] WHERE {matches})"
+            ),
+            staging,
+        }
+    }
+}
+
+/// Length of the indexed `nvarchar` columns, in UTF-16 code units
+const INDEXED_LENGTH: usize = 450;
+
+/// Cuts a value to the length of an indexed `nvarchar(450)` column
+///
+/// SQL Server rejects the whole bulk load if a single value does not fit its
+/// column, so an overlong value (such as a malformed `Message-ID`) is cut instead.
+fn indexed(value: &str) -> &str {
+    let mut length = 0;
+    for (index, c) in value.char_indices() {
+        length += c.len_utf16();
+        if length > INDEXED_LENGTH {
+            return &value[..index];
+        }
+    }
+    value
+}
+
+/// Rolls back the transaction left open on a connection by a failed statement
+///
+/// `XACT_ABORT` covers runtime errors only: after a compile error or a failed bulk
+/// load the transaction stays open, and the connection would go back to the pool
+/// with it.
+async fn rollback<S>(client: &mut Client<S>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let result = match client
+        .simple_query("IF @@TRANCOUNT > 0 ROLLBACK TRANSACTION")
+        .await
+    {
+        Ok(stream) => stream.into_results().await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        error!("Failed to roll back transaction: {}", e);
+    }
+}
+
+/// Checks that the CA certificate can be used by the TLS stack of tiberius,
+/// which only reads `.pem`, `.crt` and `.der` files
+fn check_ca_certificate(path: &Path) -> Result<()> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase);
+    if !matches!(extension.as_deref(), Some("pem" | "crt" | "der")) {
+        return Err(eyre!(
+            "Сертификат CA MS SQL Server {} должен быть файлом .pem, .crt или .der",
+            path.display()
+        ));
+    }
+    std::fs::metadata(path).map_err(|e| {
+        eyre!(
+            "Не удалось прочитать сертификат CA MS SQL Server {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    Ok(())
+}
+
+/// Describes an error of the first connection, explaining the TLS errors
+fn connection_error(error: bb8_tiberius::Error, address: &str) -> Report {
+    match error {
+        bb8_tiberius::Error::Tiberius(tiberius::error::Error::Tls(message)) => eyre!(
+            "Ошибка TLS при подключении к MS SQL Server {}: {}. Укажите CA сервера в --mssql-ca-cert или отключите проверку сертификата с --mssql-trust-server-certificate",
+            address,
+            message
+        ),
+        e => Report::new(e).wrap_err(format!(
+            "Не удалось подключиться к MS SQL Server {}",
+            address
+        )),
+    }
+}
+
+#[async_trait]
+impl Database for MsSqlDatabase {
+    async fn schema_version(&self) -> Result<i32> {
+        let mut client = self.pool.get().await?;
+
+        let sql = format!(
+            r#"
+            IF OBJECT_ID(N'[dbo].[{prefix}schema_version]', N'U') IS NULL
+                SELECT CAST(0 AS int)
+            ELSE
+                SELECT ISNULL(MAX([version]), 0) FROM [dbo].[{prefix}schema_version]
+            "#,
+            prefix = self.table_prefix
+        );
+
+        let row = client.simple_query(sql).await?.into_row().await?;
+        Ok(row.and_then(|row| row.get::<i32, _>(0)).unwrap_or(0))
+    }
+
+    fn migrations(&self) -> Vec<Migration> {
+        vec![
+            Migration {
+                version: 1,
+                description: "initial schema",
+                sql: self.initial_schema(),
+            },
+            Migration {
+                version: 2,
+                description: "64-bit identifiers and message sizes",
+                sql: self.bigint_schema(),
+            },
+        ]
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        let mut client = self.pool.get().await?;
+
+        // The migration runs through EXEC so that it is compiled only after the
+        // previous statements of the batch have been executed
+        let sql = format!(
+            r#"
+            SET XACT_ABORT ON;
+            BEGIN TRANSACTION;
+
+            IF OBJECT_ID(N'[dbo].[{prefix}schema_version]', N'U') IS NULL
+                CREATE TABLE [dbo].[{prefix}schema_version] (
+                    [version] [int] NOT NULL PRIMARY KEY,
+                    [description] [nvarchar](max) NOT NULL,
+                    [applied_at] [datetimeoffset](7) NOT NULL DEFAULT SYSDATETIMEOFFSET()
+                );
+
+            EXEC sp_getapplock @Resource = N'{prefix}schema_version', @LockMode = N'Exclusive', @LockOwner = N'Transaction';
+
+            IF NOT EXISTS (SELECT 1 FROM [dbo].[{prefix}schema_version] WHERE [version] = {version})
+            BEGIN
+                EXEC(N'{migration}');
+                INSERT INTO [dbo].[{prefix}schema_version] ([version], [description])
+                VALUES ({version}, N'{description}');
+            END
+
+            COMMIT TRANSACTION;
+            "#,
+            prefix = self.table_prefix,
+            version = migration.version,
+            migration = migration.sql.replace('\'', "''"),
+            description = migration.description.replace('\'', "''"),
+        );
+
+        let result = match client.simple_query(sql).await {
+            Ok(stream) => stream.into_results().await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            rollback(&mut client).await;
+            return Err(e.into());
+        }
+
+        info!("Database schema migrated to version {}", migration.version);
+        Ok(())
+    }
+
+    async fn insert_smtp_receive_logs(&self, logs: Vec<SmtpReceiveLog>) -> Result<u64> {
+        if logs.is_empty() {
+            debug!("Нет SMTP Receive логов для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .bulk_rows(
+                "smtp_receive_logs",
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "sequence_number",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "event",
+                    "data",
+                    "context",
+                    "sender",
+                    "recipient",
+                    "message_id",
+                    "subject",
+                    "size",
+                ],
+                &["date_time", "session_id", "sequence_number"],
+                &logs,
+                |log| {
+                    token_row![
+                        log.date_time.fixed_offset(),
+                        &log.connector_id,
+                        indexed(&log.session_id),
+                        log.sequence_number,
+                        &log.local_endpoint,
+                        &log.remote_endpoint,
+                        &log.event,
+                        log.data.as_deref(),
+                        log.context.as_deref(),
+                        log.sender.as_deref(),
+                        log.recipient.as_deref(),
+                        log.message_id.as_deref(),
+                        log.subject.as_deref(),
+                        log.size,
+                    ]
+                },
+            )
+            .await?;
 
         debug!("Inserted {} SMTP Receive logs", inserted_count);
         Ok(inserted_count)
@@ -232,47 +820,195 @@ impl Database for MsSqlDatabase {
             return Ok(0);
         }
 
-        let mut client = self.pool.get().await?;
-        let mut inserted_count = 0;
+        let inserted_count = self
+            .bulk_rows(
+                "smtp_send_logs",
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "sequence_number",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "event",
+                    "data",
+                    "context",
+                    "proxy_session_id",
+                    "sender",
+                    "recipient",
+                    "message_id",
+                    "record_id",
+                ],
+                &["date_time", "session_id", "sequence_number"],
+                &logs,
+                |log| {
+                    token_row![
+                        log.date_time.fixed_offset(),
+                        &log.connector_id,
+                        indexed(&log.session_id),
+                        log.sequence_number,
+                        &log.local_endpoint,
+                        &log.remote_endpoint,
+                        &log.event,
+                        log.data.as_deref(),
+                        log.context.as_deref(),
+                        log.proxy_session_id.as_deref(),
+                        log.sender.as_deref(),
+                        log.recipient.as_deref(),
+                        log.message_id.as_deref(),
+                        log.record_id.as_deref(),
+                    ]
+                },
+            )
+            .await?;
 
-        client.simple_query("BEGIN TRANSACTION").await?;
+        debug!("Inserted {} SMTP Send logs", inserted_count);
+        Ok(inserted_count)
+    }
 
-        for log in logs {
-            let sql = format!(
-                r#"
-                INSERT INTO [dbo].[{prefix}smtp_send_logs]
-                (date_time, connector_id, session_id, sequence_number, local_endpoint, remote_endpoint,
-                event, data, context, proxy_session_id, sender, recipient, message_id, record_id)
-                VALUES (@P1, @P2, @P3, @P4, @P5, @P6, @P7, @P8, @P9, @P10, @P11, @P12, @P13, @P14)
-                "#,
-                prefix = self.table_prefix
-            );
-            let mut query = Query::new(sql.as_str());
-
-            query.bind(log.date_time);
-            query.bind(&log.connector_id);
-            query.bind(&log.session_id);
-            query.bind(log.sequence_number);
-            query.bind(&log.local_endpoint);
-            query.bind(&log.remote_endpoint);
-            query.bind(&log.event);
-            query.bind(log.data.as_deref());
-            query.bind(log.context.as_deref());
-            query.bind(log.proxy_session_id.as_deref());
-            query.bind(log.sender.as_deref());
-            query.bind(log.recipient.as_deref());
-            query.bind(log.message_id.as_deref());
-            query.bind(log.record_id.as_deref());
-
-            let result = query.execute(&mut client).await?;
-            if let Some(rows) = result.rows_affected().first() {
-                inserted_count += *rows as u64;
-            }
+    async fn insert_smtp_events(
+        &self,
+        direction: SmtpDirection,
+        events: Vec<SmtpEvent>,
+    ) -> Result<u64> {
+        if events.is_empty() {
+            debug!("Нет событий SMTP для вставки");
+            return Ok(0);
         }
 
-        client.simple_query("COMMIT TRANSACTION").await?;
+        let inserted_count = self
+            .bulk_rows(
+                &direction.table_name("events"),
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "sequence_number",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "event",
+                    "data",
+                    "context",
+                ],
+                &["date_time", "session_id", "sequence_number"],
+                &events,
+                |event| {
+                    token_row![
+                        event.date_time.fixed_offset(),
+                        &event.connector_id,
+                        indexed(&event.session_id),
+                        event.sequence_number,
+                        &event.local_endpoint,
+                        &event.remote_endpoint,
+                        &event.event,
+                        event.data.as_deref(),
+                        event.context.as_deref(),
+                    ]
+                },
+            )
+            .await?;
 
-        debug!("Inserted {} SMTP Send logs", inserted_count);
+        debug!("Inserted {} {:?} SMTP events", inserted_count, direction);
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_transactions(
+        &self,
+        direction: SmtpDirection,
+        transactions: Vec<SmtpTransaction>,
+    ) -> Result<u64> {
+        if transactions.is_empty() {
+            debug!("Нет транзакций SMTP для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .bulk_rows(
+                &direction.table_name("transactions"),
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "transaction_index",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "sender",
+                    "recipient_count",
+                    "size",
+                    "message_id",
+                    "record_id",
+                    "response_code",
+                    "response",
+                ],
+                &["date_time", "session_id", "transaction_index"],
+                &transactions,
+                |transaction| {
+                    token_row![
+                        transaction.date_time.fixed_offset(),
+                        &transaction.connector_id,
+                        indexed(&transaction.session_id),
+                        transaction.transaction_index,
+                        &transaction.local_endpoint,
+                        &transaction.remote_endpoint,
+                        transaction.sender.as_deref(),
+                        transaction.recipient_count,
+                        transaction.size,
+                        transaction.message_id.as_deref().map(indexed),
+                        transaction.record_id.as_deref(),
+                        transaction.response_code,
+                        transaction.response.as_deref(),
+                    ]
+                },
+            )
+            .await?;
+
+        debug!(
+            "Inserted {} {:?} SMTP transactions",
+            inserted_count, direction
+        );
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_recipients(
+        &self,
+        direction: SmtpDirection,
+        recipients: Vec<SmtpRecipient>,
+    ) -> Result<u64> {
+        if recipients.is_empty() {
+            debug!("Нет получателей SMTP для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .bulk_rows(
+                &direction.table_name("recipients"),
+                &[
+                    "date_time",
+                    "session_id",
+                    "sequence_number",
+                    "transaction_index",
+                    "recipient",
+                    "response_code",
+                ],
+                &["date_time", "session_id", "sequence_number"],
+                &recipients,
+                |recipient| {
+                    token_row![
+                        recipient.date_time.fixed_offset(),
+                        indexed(&recipient.session_id),
+                        recipient.sequence_number,
+                        recipient.transaction_index,
+                        indexed(&recipient.recipient),
+                        recipient.response_code,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!(
+            "Inserted {} {:?} SMTP recipients",
+            inserted_count, direction
+        );
         Ok(inserted_count)
     }
 
@@ -282,69 +1018,312 @@ impl Database for MsSqlDatabase {
             return Ok(0);
         }
 
-        let mut client = self.pool.get().await?;
-        let mut inserted_count = 0;
+        let inserted_count = self
+            .bulk_rows(
+                "message_tracking_logs",
+                &[
+                    "date_time",
+                    "client_ip",
+                    "client_hostname",
+                    "server_ip",
+                    "server_hostname",
+                    "source_context",
+                    "connector_id",
+                    "source",
+                    "event_id",
+                    "internal_message_id",
+                    "message_id",
+                    "network_message_id",
+                    "recipient_address",
+                    "recipient_status",
+                    "total_bytes",
+                    "recipient_count",
+                    "related_recipient_address",
+                    "reference",
+                    "message_subject",
+                    "sender_address",
+                    "return_path",
+                    "message_info",
+                    "directionality",
+                    "tenant_id",
+                    "original_client_ip",
+                    "original_server_ip",
+                    "custom_data",
+                    "transport_traffic_type",
+                    "log_id",
+                    "schema_version",
+                ],
+                &[
+                    "date_time",
+                    "internal_message_id",
+                    "recipient_address",
+                    "event_id",
+                ],
+                &logs,
+                |log| {
+                    token_row![
+                        log.date_time.fixed_offset(),
+                        log.client_ip.as_deref(),
+                        log.client_hostname.as_deref(),
+                        log.server_ip.as_deref(),
+                        &log.server_hostname,
+                        log.source_context.as_deref(),
+                        log.connector_id.as_deref(),
+                        log.source.as_deref(),
+                        indexed(&log.event_id),
+                        indexed(&log.internal_message_id),
+                        &log.message_id,
+                        &log.network_message_id,
+                        indexed(&log.recipient_address),
+                        log.recipient_status.as_deref(),
+                        log.total_bytes,
+                        log.recipient_count,
+                        log.related_recipient_address.as_deref(),
+                        log.reference.as_deref(),
+                        log.message_subject.as_deref(),
+                        &log.sender_address,
+                        log.return_path.as_deref(),
+                        log.message_info.as_deref(),
+                        log.directionality.as_deref(),
+                        log.tenant_id.as_deref(),
+                        log.original_client_ip.as_deref(),
+                        log.original_server_ip.as_deref(),
+                        log.custom_data.as_deref(),
+                        log.transport_traffic_type.as_deref(),
+                        log.log_id.as_deref(),
+                        log.schema_version.as_deref(),
+                    ]
+                },
+            )
+            .await?;
 
-        client.simple_query("BEGIN TRANSACTION").await?;
+        debug!("Inserted {} Message Tracking logs", inserted_count);
+        Ok(inserted_count)
+    }
 
-        for log in logs {
-            let sql = format!(
-                r#"
-                INSERT INTO [dbo].[{prefix}message_tracking_logs]
-                (date_time, client_ip, client_hostname, server_ip, server_hostname, source_context,
-                connector_id, source, event_id, internal_message_id, message_id, network_message_id,
-                recipient_address, recipient_status, total_bytes, recipient_count, related_recipient_address,
-                reference, message_subject, sender_address, return_path, message_info, directionality,
-                tenant_id, original_client_ip, original_server_ip, custom_data, transport_traffic_type,
-                log_id, schema_version)
-                VALUES (@P1, @P2, @P3, @P4, @P5, @P6, @P7, @P8, @P9, @P10, @P11, @P12, @P13, @P14,
-                        @P15, @P16, @P17, @P18, @P19, @P20, @P21, @P22, @P23, @P24, @P25, @P26,
-                        @P27, @P28, @P29, @P30)
-                "#,
-                prefix = self.table_prefix
-            );
-            let mut query = Query::new(sql.as_str());
-
-            query.bind(log.date_time);
-            query.bind(log.client_ip.as_deref());
-            query.bind(log.client_hostname.as_deref());
-            query.bind(log.server_ip.as_deref());
-            query.bind(&log.server_hostname);
-            query.bind(log.source_context.as_deref());
-            query.bind(log.connector_id.as_deref());
-            query.bind(log.source.as_deref());
-            query.bind(&log.event_id);
-            query.bind(&log.internal_message_id);
-            query.bind(&log.message_id);
-            query.bind(&log.network_message_id);
-            query.bind(&log.recipient_address);
-            query.bind(log.recipient_status.as_deref());
-            query.bind(log.total_bytes);
-            query.bind(log.recipient_count);
-            query.bind(log.related_recipient_address.as_deref());
-            query.bind(log.reference.as_deref());
-            query.bind(log.message_subject.as_deref());
-            query.bind(&log.sender_address);
-            query.bind(log.return_path.as_deref());
-            query.bind(log.message_info.as_deref());
-            query.bind(log.directionality.as_deref());
-            query.bind(log.tenant_id.as_deref());
-            query.bind(log.original_client_ip.as_deref());
-            query.bind(log.original_server_ip.as_deref());
-            query.bind(log.custom_data.as_deref());
-            query.bind(log.transport_traffic_type.as_deref());
-            query.bind(log.log_id.as_deref());
-            query.bind(log.schema_version.as_deref());
-
-            let result = query.execute(&mut client).await?;
-            if let Some(rows) = result.rows_affected().first() {
-                inserted_count += *rows as u64;
-            }
+    async fn insert_rejected_lines(&self, lines: Vec<RejectedLine>) -> Result<u64> {
+        if lines.is_empty() {
+            debug!("Нет отклоненных строк для вставки");
+            return Ok(0);
         }
 
-        client.simple_query("COMMIT TRANSACTION").await?;
+        let inserted_count = self
+            .bulk_rows(
+                "rejected_lines",
+                &["rejected_at", "file_path", "line_number", "line", "reason"],
+                &["file_path", "line_number"],
+                &lines,
+                |line| {
+                    token_row![
+                        line.rejected_at.fixed_offset(),
+                        indexed(&line.file_path),
+                        line.line_number,
+                        &line.line,
+                        &line.reason,
+                    ]
+                },
+            )
+            .await?;
 
-        debug!("Inserted {} Message Tracking logs", inserted_count);
+        debug!("Inserted {} rejected lines", inserted_count);
         Ok(inserted_count)
     }
+
+    async fn get_ingested_file(&self, path: &str) -> Result<Option<IngestedFile>> {
+        let mut client = self.pool.get().await?;
+
+        let sql = format!(
+            r#"
+            SELECT id, path, size, modified_at, content_hash, byte_offset, line_number,
+            log_type, record_count, ingested_at
+            FROM [dbo].[{prefix}ingested_files] WHERE path = @P1
+            "#,
+            prefix = self.table_prefix
+        );
+        let mut query = Query::new(sql.as_str());
+        query.bind(path);
+
+        let row = query.query(&mut client).await?.into_row().await?;
+        Ok(row.map(|row| IngestedFile {
+            id: row.get("id"),
+            path: row.get::<&str, _>("path").unwrap_or_default().to_string(),
+            size: row.get("size").unwrap_or_default(),
+            modified_at: row.get("modified_at").unwrap_or_default(),
+            content_hash: row
+                .get::<&str, _>("content_hash")
+                .unwrap_or_default()
+                .to_string(),
+            byte_offset: row.get("byte_offset").unwrap_or_default(),
+            line_number: row.get("line_number").unwrap_or_default(),
+            log_type: row
+                .get::<&str, _>("log_type")
+                .unwrap_or_default()
+                .to_string(),
+            record_count: row.get("record_count").unwrap_or_default(),
+            ingested_at: row.get("ingested_at").unwrap_or_default(),
+        }))
+    }
+
+    async fn save_ingested_file(&self, file: IngestedFile) -> Result<()> {
+        let mut client = self.pool.get().await?;
+
+        let sql = format!(
+            r#"
+            UPDATE [dbo].[{prefix}ingested_files]
+            SET size = @P2, modified_at = @P3, content_hash = @P4, byte_offset = @P5,
+            line_number = @P6, log_type = @P7, record_count = @P8, ingested_at = @P9
+            WHERE path = @P1;
+
+            IF @@ROWCOUNT = 0
+                INSERT INTO [dbo].[{prefix}ingested_files]
+                (path, size, modified_at, content_hash, byte_offset, line_number, log_type,
+                record_count, ingested_at)
+                VALUES (@P1, @P2, @P3, @P4, @P5, @P6, @P7, @P8, @P9);
+            "#,
+            prefix = self.table_prefix
+        );
+        let mut query = Query::new(sql.as_str());
+
+        query.bind(&file.path);
+        query.bind(file.size);
+        query.bind(file.modified_at);
+        query.bind(&file.content_hash);
+        query.bind(file.byte_offset);
+        query.bind(file.line_number);
+        query.bind(&file.log_type);
+        query.bind(file.record_count);
+        query.bind(file.ingested_at);
+
+        query.execute(&mut client).await?;
+
+        debug!("Saved ingestion journal entry for {}", file.path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection_string() -> MsSqlOptions {
+        MsSqlOptions {
+            connection_string: Some("server=tcp:localhost,1433;user=sa;password=x".into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn accepts_compatible_options() {
+        assert!(MsSqlOptions::default().validate().is_ok());
+        assert!(connection_string().validate().is_ok());
+        let options = MsSqlOptions {
+            instance: Some("SQLEXPRESS".into()),
+            ca_cert: Some("ca.pem".into()),
+            encryption: Some(MsSqlEncryption::Required),
+            ..Default::default()
+        };
+        assert!(options.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_connection_string_with_instance() {
+        let options = MsSqlOptions {
+            instance: Some("SQLEXPRESS".into()),
+            ..connection_string()
+        };
+        assert!(options.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_connection_string_with_ca_cert() {
+        let options = MsSqlOptions {
+            ca_cert: Some("ca.pem".into()),
+            ..connection_string()
+        };
+        assert!(options.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_connection_string_with_trust_server_certificate() {
+        let options = MsSqlOptions {
+            trust_server_certificate: true,
+            ..connection_string()
+        };
+        assert!(options.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_ca_cert_with_trust_server_certificate() {
+        let options = MsSqlOptions {
+            ca_cert: Some("ca.pem".into()),
+            trust_server_certificate: true,
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn new_returns_error_instead_of_panicking() {
+        let options = MsSqlOptions {
+            connection_string: Some(
+                "server=tcp:localhost,1;user=sa;password=x;TrustServerCertificate=true".into(),
+            ),
+            ca_cert: Some("ca.pem".into()),
+            ..Default::default()
+        };
+        let result = MsSqlDatabase::new("localhost", 1433, "sa", "x", "db", None, &options).await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn loads_through_staging_table_and_skips_existing_rows() {
+        let statements = BulkStatements::new(
+            "elp_",
+            "smtp_receive_recipients",
+            &["date_time", "session_id", "sequence_number", "recipient"],
+            &["date_time", "session_id", "sequence_number"],
+        );
+
+        assert_eq!(
+            statements,
+            BulkStatements {
+                staging: "#smtp_receive_recipients_staging".to_string(),
+                create: "IF OBJECT_ID(N'tempdb..#smtp_receive_recipients_staging') IS NOT NULL \
+                    DROP TABLE #smtp_receive_recipients_staging; \
+                    SELECT TOP 0 [date_time], [session_id], [sequence_number], [recipient] \
+                    INTO #smtp_receive_recipients_staging \
+                    FROM [dbo].[elp_smtp_receive_recipients];"
+                    .to_string(),
+                insert: "INSERT INTO [dbo].[elp_smtp_receive_recipients] \
+                    ([date_time], [session_id], [sequence_number], [recipient]) \
+                    SELECT [date_time], [session_id], [sequence_number], [recipient] FROM ( \
+                    SELECT [date_time], [session_id], [sequence_number], [recipient], \
+                    ROW_NUMBER() OVER (PARTITION BY [date_time], [session_id], [sequence_number] \
+                    ORDER BY (SELECT NULL)) AS [row_number] \
+                    FROM #smtp_receive_recipients_staging \
+                    ) AS [s] \
+                    WHERE [s].[row_number] = 1 \
+                    AND NOT EXISTS (SELECT 1 FROM [dbo].[elp_smtp_receive_recipients] AS [t] \
+                    WHERE [t].[date_time] = [s].[date_time] \
+                    AND [t].[session_id] = [s].[session_id] \
+                    AND [t].[sequence_number] = [s].[sequence_number])"
+                    .to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn cuts_values_to_indexed_column_length() {
+        let short = "<message@example.com>";
+        assert_eq!(indexed(short), short);
+
+        let long = "a".repeat(INDEXED_LENGTH + 10);
+        assert_eq!(indexed(&long), &long[..INDEXED_LENGTH]);
+
+        // Characters outside the BMP take two UTF-16 code units and are not split
+        let wide = "😀".repeat(INDEXED_LENGTH / 2 + 1);
+        let cut = indexed(&wide);
+        assert_eq!(cut.chars().count(), INDEXED_LENGTH / 2);
+        assert_eq!(cut.encode_utf16().count(), INDEXED_LENGTH);
+    }
 }
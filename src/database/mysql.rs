@@ -0,0 +1,888 @@
+use crate::models::{
+    IngestedFile, MessageTrackingLog, RejectedLine, SmtpDirection, SmtpEvent, SmtpReceiveLog,
+    SmtpRecipient, SmtpSendLog, SmtpTransaction,
+};
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use color_eyre::eyre::{Report, Result, eyre};
+use log::{debug, info};
+use mysql_async::prelude::Queryable;
+use mysql_async::{Conn, OptsBuilder, Pool, Row, TxOpts, Value};
+
+use super::Database;
+use super::migrations::Migration;
+
+/// Maximum number of rows in one multi-row `INSERT`
+const ROWS_PER_INSERT: usize = 1000;
+
+/// Maximum number of placeholders in a prepared statement
+const MAX_PLACEHOLDERS: usize = 65535;
+
+/// Seconds to wait for the migration lock held by another instance
+const MIGRATION_LOCK_TIMEOUT: u32 = 300;
+
+pub struct MySqlDatabase {
+    pool: Pool,
+    table_prefix: String,
+}
+
+impl MySqlDatabase {
+    pub async fn new(
+        host: &str,
+        port: u16,
+        user: &str,
+        password: &str,
+        dbname: &str,
+        table_prefix: Option<&str>,
+    ) -> Result<Self> {
+        let opts = OptsBuilder::default()
+            .ip_or_hostname(host)
+            .tcp_port(port)
+            .user(Some(user))
+            .pass(Some(password))
+            .db_name(Some(dbname));
+
+        let pool = Pool::new(opts);
+
+        drop(pool.get_conn().await.map_err(|e| {
+            Report::new(e).wrap_err(format!("Не удалось подключиться к MySQL {}:{}", host, port))
+        })?);
+        debug!("Connected to MySQL {}:{}", host, port);
+
+        let db = MySqlDatabase {
+            pool,
+            table_prefix: table_prefix.unwrap_or("").to_string(),
+        };
+
+        Ok(db)
+    }
+
+    /// Schema of the first version, equivalent to the PostgreSQL schema with
+    /// 64-bit identifiers
+    ///
+    /// Text columns that are part of an index are `VARCHAR`, since MySQL indexes
+    /// are limited to 3072 bytes. Timestamps are stored in UTC.
+    fn schema(&self) -> String {
+        let mut schema = String::new();
+
+        // Create SMTP Receive logs table
+        schema.push_str(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS `{prefix}smtp_receive_logs` (
+                `id` BIGINT AUTO_INCREMENT PRIMARY KEY,
+                `date_time` DATETIME(6) NOT NULL,
+                `connector_id` TEXT NOT NULL,
+                `session_id` VARCHAR(255) NOT NULL,
+                `sequence_number` INT NOT NULL,
+                `local_endpoint` TEXT NOT NULL,
+                `remote_endpoint` TEXT NOT NULL,
+                `event` TEXT NOT NULL,
+                `data` TEXT,
+                `context` TEXT,
+                `sender` TEXT,
+                `recipient` TEXT,
+                `message_id` TEXT,
+                `subject` TEXT,
+                `size` BIGINT,
+                UNIQUE KEY `{prefix}smtp_receive_logs_unique_idx` (`date_time`, `session_id`, `sequence_number`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        // Create SMTP Send logs table
+        schema.push_str(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS `{prefix}smtp_send_logs` (
+                `id` BIGINT AUTO_INCREMENT PRIMARY KEY,
+                `date_time` DATETIME(6) NOT NULL,
+                `connector_id` TEXT NOT NULL,
+                `session_id` VARCHAR(255) NOT NULL,
+                `sequence_number` INT NOT NULL,
+                `local_endpoint` TEXT NOT NULL,
+                `remote_endpoint` TEXT NOT NULL,
+                `event` TEXT NOT NULL,
+                `data` TEXT,
+                `context` TEXT,
+                `proxy_session_id` TEXT,
+                `sender` TEXT,
+                `recipient` TEXT,
+                `message_id` TEXT,
+                `record_id` TEXT,
+                UNIQUE KEY `{prefix}smtp_send_logs_unique_idx` (`date_time`, `session_id`, `sequence_number`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        // Create SMTP protocol events tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            CREATE TABLE IF NOT EXISTS `{prefix}{table}` (
+                `id` BIGINT AUTO_INCREMENT PRIMARY KEY,
+                `date_time` DATETIME(6) NOT NULL,
+                `connector_id` TEXT NOT NULL,
+                `session_id` VARCHAR(255) NOT NULL,
+                `sequence_number` INT NOT NULL,
+                `local_endpoint` TEXT NOT NULL,
+                `remote_endpoint` TEXT NOT NULL,
+                `event` TEXT NOT NULL,
+                `data` TEXT,
+                `context` TEXT,
+                UNIQUE KEY `{prefix}{table}_unique_idx` (`date_time`, `session_id`, `sequence_number`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("events")
+            ));
+        }
+
+        // Create SMTP transactions tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            CREATE TABLE IF NOT EXISTS `{prefix}{table}` (
+                `id` BIGINT AUTO_INCREMENT PRIMARY KEY,
+                `date_time` DATETIME(6) NOT NULL,
+                `connector_id` TEXT NOT NULL,
+                `session_id` VARCHAR(255) NOT NULL,
+                `transaction_index` INT NOT NULL,
+                `local_endpoint` TEXT NOT NULL,
+                `remote_endpoint` TEXT NOT NULL,
+                `sender` TEXT,
+                `recipient_count` INT NOT NULL,
+                `size` BIGINT,
+                `message_id` VARCHAR(255),
+                `record_id` TEXT,
+                `response_code` INT,
+                `response` TEXT,
+                UNIQUE KEY `{prefix}{table}_unique_idx` (`date_time`, `session_id`, `transaction_index`),
+                KEY `{prefix}{table}_message_id_idx` (`message_id`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("transactions")
+            ));
+        }
+
+        // Create SMTP recipients tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            CREATE TABLE IF NOT EXISTS `{prefix}{table}` (
+                `id` BIGINT AUTO_INCREMENT PRIMARY KEY,
+                `date_time` DATETIME(6) NOT NULL,
+                `session_id` VARCHAR(255) NOT NULL,
+                `sequence_number` INT NOT NULL,
+                `transaction_index` INT NOT NULL,
+                `recipient` VARCHAR(320) NOT NULL,
+                `response_code` INT,
+                UNIQUE KEY `{prefix}{table}_unique_idx` (`date_time`, `session_id`, `sequence_number`),
+                KEY `{prefix}{table}_recipient_idx` (`recipient`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("recipients")
+            ));
+        }
+
+        // Create Message Tracking logs table
+        schema.push_str(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS `{prefix}message_tracking_logs` (
+                `id` BIGINT AUTO_INCREMENT PRIMARY KEY,
+                `date_time` DATETIME(6) NOT NULL,
+                `client_ip` TEXT,
+                `client_hostname` TEXT,
+                `server_ip` TEXT,
+                `server_hostname` TEXT NOT NULL,
+                `source_context` TEXT,
+                `connector_id` TEXT,
+                `source` TEXT,
+                `event_id` VARCHAR(64) NOT NULL,
+                `internal_message_id` VARCHAR(128) NOT NULL,
+                `message_id` TEXT NOT NULL,
+                `network_message_id` TEXT NOT NULL,
+                `recipient_address` VARCHAR(320) NOT NULL,
+                `recipient_status` TEXT,
+                `total_bytes` BIGINT,
+                `recipient_count` INT NOT NULL,
+                `related_recipient_address` TEXT,
+                `reference` TEXT,
+                `message_subject` TEXT,
+                `sender_address` TEXT NOT NULL,
+                `return_path` TEXT,
+                `message_info` TEXT,
+                `directionality` TEXT,
+                `tenant_id` TEXT,
+                `original_client_ip` TEXT,
+                `original_server_ip` TEXT,
+                `custom_data` TEXT,
+                `transport_traffic_type` TEXT,
+                `log_id` TEXT,
+                `schema_version` TEXT,
+                UNIQUE KEY `{prefix}message_tracking_logs_unique_idx` (`date_time`, `internal_message_id`, `recipient_address`, `event_id`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        // Create rejected lines table
+        schema.push_str(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS `{prefix}rejected_lines` (
+                `id` BIGINT AUTO_INCREMENT PRIMARY KEY,
+                `rejected_at` DATETIME(6) NOT NULL,
+                `file_path` VARCHAR(512) NOT NULL,
+                `line_number` INT NOT NULL,
+                `line` MEDIUMTEXT NOT NULL,
+                `reason` TEXT NOT NULL,
+                UNIQUE KEY `{prefix}rejected_lines_unique_idx` (`file_path`, `line_number`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        // Create ingestion journal table
+        schema.push_str(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS `{prefix}ingested_files` (
+                `id` BIGINT AUTO_INCREMENT PRIMARY KEY,
+                `path` VARCHAR(700) NOT NULL,
+                `size` BIGINT NOT NULL,
+                `modified_at` DATETIME(6) NOT NULL,
+                `content_hash` CHAR(64) NOT NULL,
+                `byte_offset` BIGINT NOT NULL,
+                `line_number` BIGINT NOT NULL,
+                `log_type` VARCHAR(32) NOT NULL,
+                `record_count` BIGINT NOT NULL,
+                `ingested_at` DATETIME(6) NOT NULL,
+                UNIQUE KEY `{prefix}ingested_files_unique_idx` (`path`)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        schema
+    }
+
+    /// Inserts rows with multi-row `INSERT` statements in one transaction
+    ///
+    /// Rows conflicting with a unique key are skipped: `ON DUPLICATE KEY UPDATE`
+    /// with an unchanged value does not count them as affected. Returns the number
+    /// of rows inserted.
+    async fn insert_rows<T>(
+        &self,
+        table: &str,
+        columns: &[&str],
+        rows: Vec<T>,
+        values: impl Fn(T) -> Vec<Value> + Send + Sync,
+    ) -> Result<u64>
+    where
+        T: Send,
+    {
+        let rows_per_insert = ROWS_PER_INSERT.min(MAX_PLACEHOLDERS / columns.len());
+
+        let mut tx = self.pool.start_transaction(TxOpts::default()).await?;
+        let mut inserted_count = 0;
+
+        let mut rows = rows.into_iter().peekable();
+        while rows.peek().is_some() {
+            let chunk: Vec<T> = rows.by_ref().take(rows_per_insert).collect();
+            let sql = insert_sql(&self.table_prefix, table, columns, chunk.len());
+            let params: Vec<Value> = chunk.into_iter().flat_map(&values).collect();
+
+            tx.exec_drop(sql, params).await?;
+            inserted_count += tx.affected_rows();
+        }
+
+        // An uncommitted transaction is rolled back when it is dropped
+        tx.commit().await?;
+        Ok(inserted_count)
+    }
+
+    /// Applies a migration while the migration lock is held
+    async fn apply_migration_locked(&self, conn: &mut Conn, migration: &Migration) -> Result<()> {
+        conn.query_drop(format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS `{prefix}schema_version` (
+                `version` INT PRIMARY KEY,
+                `description` TEXT NOT NULL,
+                `applied_at` DATETIME(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6)
+            ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4
+            "#,
+            prefix = self.table_prefix
+        ))
+        .await?;
+
+        // Another instance may have applied the migration while we waited for the lock
+        let applied: Option<bool> = conn
+            .exec_first(
+                format!(
+                    "SELECT COUNT(*) > 0 FROM `{prefix}schema_version` WHERE `version` = ?",
+                    prefix = self.table_prefix
+                ),
+                (migration.version,),
+            )
+            .await?;
+        if applied == Some(true) {
+            return Ok(());
+        }
+
+        conn.query_drop(&migration.sql).await?;
+        conn.exec_drop(
+            format!(
+                "INSERT INTO `{prefix}schema_version` (`version`, `description`) VALUES (?, ?)",
+                prefix = self.table_prefix
+            ),
+            (migration.version, migration.description),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Builds a multi-row `INSERT` that skips the rows conflicting with a unique key
+fn insert_sql(prefix: &str, table: &str, columns: &[&str], row_count: usize) -> String {
+    let names = columns
+        .iter()
+        .map(|column| format!("`{column}`"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let placeholders = format!("({})", vec!["?"; columns.len()].join(", "));
+    format!(
+        "INSERT INTO `{prefix}{table}` ({names}) VALUES {values} \
+        ON DUPLICATE KEY UPDATE `id` = `id`",
+        values = vec![placeholders.as_str(); row_count].join(", ")
+    )
+}
+
+/// Cuts a value to the length of a `VARCHAR` column, in characters
+///
+/// In strict mode MySQL rejects the whole statement if a single value does not
+/// fit its column, so an overlong value (such as a malformed `Message-ID`) is cut
+/// instead.
+fn varchar(mut value: String, length: usize) -> String {
+    if let Some((index, _)) = value.char_indices().nth(length) {
+        value.truncate(index);
+    }
+    value
+}
+
+#[async_trait]
+impl Database for MySqlDatabase {
+    async fn schema_version(&self) -> Result<i32> {
+        let mut conn = self.pool.get_conn().await?;
+        let table = format!("{}schema_version", self.table_prefix);
+
+        let exists: Option<bool> = conn
+            .exec_first(
+                "SELECT COUNT(*) > 0 FROM information_schema.tables
+                WHERE table_schema = DATABASE() AND table_name = ?",
+                (table.as_str(),),
+            )
+            .await?;
+        if !exists.unwrap_or(false) {
+            return Ok(0);
+        }
+
+        let version: Option<i32> = conn
+            .query_first(format!("SELECT COALESCE(MAX(`version`), 0) FROM `{table}`"))
+            .await?;
+        Ok(version.unwrap_or(0))
+    }
+
+    fn migrations(&self) -> Vec<Migration> {
+        vec![Migration {
+            version: 1,
+            description: "initial schema",
+            sql: self.schema(),
+        }]
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        let mut conn = self.pool.get_conn().await?;
+
+        // Lock names are global to the server and limited to 64 characters
+        let lock = format!("{}schema_version", self.table_prefix);
+
+        // DDL is not transactional in MySQL, so concurrent migrations are serialized
+        // with a named lock instead, and each migration has to be safe to run again
+        let locked: Option<Option<i32>> = conn
+            .exec_first(
+                "SELECT GET_LOCK(LEFT(CONCAT(DATABASE(), '.', ?), 64), ?)",
+                (lock.as_str(), MIGRATION_LOCK_TIMEOUT),
+            )
+            .await?;
+        if locked.flatten() != Some(1) {
+            return Err(eyre!("Не удалось получить блокировку миграции схемы БД"));
+        }
+
+        let result = self.apply_migration_locked(&mut conn, migration).await;
+
+        conn.exec_drop(
+            "SELECT RELEASE_LOCK(LEFT(CONCAT(DATABASE(), '.', ?), 64))",
+            (lock.as_str(),),
+        )
+        .await?;
+        result?;
+
+        info!("Database schema migrated to version {}", migration.version);
+        Ok(())
+    }
+
+    async fn insert_smtp_receive_logs(&self, logs: Vec<SmtpReceiveLog>) -> Result<u64> {
+        if logs.is_empty() {
+            debug!("Нет SMTP Receive логов для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                "smtp_receive_logs",
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "sequence_number",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "event",
+                    "data",
+                    "context",
+                    "sender",
+                    "recipient",
+                    "message_id",
+                    "subject",
+                    "size",
+                ],
+                logs,
+                |log| {
+                    vec![
+                        log.date_time.naive_utc().into(),
+                        log.connector_id.into(),
+                        varchar(log.session_id, 255).into(),
+                        log.sequence_number.into(),
+                        log.local_endpoint.into(),
+                        log.remote_endpoint.into(),
+                        log.event.into(),
+                        log.data.into(),
+                        log.context.into(),
+                        log.sender.into(),
+                        log.recipient.into(),
+                        log.message_id.into(),
+                        log.subject.into(),
+                        log.size.into(),
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} SMTP Receive logs", inserted_count);
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_send_logs(&self, logs: Vec<SmtpSendLog>) -> Result<u64> {
+        if logs.is_empty() {
+            debug!("Нет SMTP Send логов для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                "smtp_send_logs",
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "sequence_number",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "event",
+                    "data",
+                    "context",
+                    "proxy_session_id",
+                    "sender",
+                    "recipient",
+                    "message_id",
+                    "record_id",
+                ],
+                logs,
+                |log| {
+                    vec![
+                        log.date_time.naive_utc().into(),
+                        log.connector_id.into(),
+                        varchar(log.session_id, 255).into(),
+                        log.sequence_number.into(),
+                        log.local_endpoint.into(),
+                        log.remote_endpoint.into(),
+                        log.event.into(),
+                        log.data.into(),
+                        log.context.into(),
+                        log.proxy_session_id.into(),
+                        log.sender.into(),
+                        log.recipient.into(),
+                        log.message_id.into(),
+                        log.record_id.into(),
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} SMTP Send logs", inserted_count);
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_events(
+        &self,
+        direction: SmtpDirection,
+        events: Vec<SmtpEvent>,
+    ) -> Result<u64> {
+        if events.is_empty() {
+            debug!("Нет событий SMTP для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                &direction.table_name("events"),
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "sequence_number",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "event",
+                    "data",
+                    "context",
+                ],
+                events,
+                |event| {
+                    vec![
+                        event.date_time.naive_utc().into(),
+                        event.connector_id.into(),
+                        varchar(event.session_id, 255).into(),
+                        event.sequence_number.into(),
+                        event.local_endpoint.into(),
+                        event.remote_endpoint.into(),
+                        event.event.into(),
+                        event.data.into(),
+                        event.context.into(),
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} {:?} SMTP events", inserted_count, direction);
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_transactions(
+        &self,
+        direction: SmtpDirection,
+        transactions: Vec<SmtpTransaction>,
+    ) -> Result<u64> {
+        if transactions.is_empty() {
+            debug!("Нет транзакций SMTP для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                &direction.table_name("transactions"),
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "transaction_index",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "sender",
+                    "recipient_count",
+                    "size",
+                    "message_id",
+                    "record_id",
+                    "response_code",
+                    "response",
+                ],
+                transactions,
+                |transaction| {
+                    vec![
+                        transaction.date_time.naive_utc().into(),
+                        transaction.connector_id.into(),
+                        varchar(transaction.session_id, 255).into(),
+                        transaction.transaction_index.into(),
+                        transaction.local_endpoint.into(),
+                        transaction.remote_endpoint.into(),
+                        transaction.sender.into(),
+                        transaction.recipient_count.into(),
+                        transaction.size.into(),
+                        transaction.message_id.map(|id| varchar(id, 255)).into(),
+                        transaction.record_id.into(),
+                        transaction.response_code.into(),
+                        transaction.response.into(),
+                    ]
+                },
+            )
+            .await?;
+
+        debug!(
+            "Inserted {} {:?} SMTP transactions",
+            inserted_count, direction
+        );
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_recipients(
+        &self,
+        direction: SmtpDirection,
+        recipients: Vec<SmtpRecipient>,
+    ) -> Result<u64> {
+        if recipients.is_empty() {
+            debug!("Нет получателей SMTP для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                &direction.table_name("recipients"),
+                &[
+                    "date_time",
+                    "session_id",
+                    "sequence_number",
+                    "transaction_index",
+                    "recipient",
+                    "response_code",
+                ],
+                recipients,
+                |recipient| {
+                    vec![
+                        recipient.date_time.naive_utc().into(),
+                        varchar(recipient.session_id, 255).into(),
+                        recipient.sequence_number.into(),
+                        recipient.transaction_index.into(),
+                        varchar(recipient.recipient, 320).into(),
+                        recipient.response_code.into(),
+                    ]
+                },
+            )
+            .await?;
+
+        debug!(
+            "Inserted {} {:?} SMTP recipients",
+            inserted_count, direction
+        );
+        Ok(inserted_count)
+    }
+
+    async fn insert_message_tracking_logs(&self, logs: Vec<MessageTrackingLog>) -> Result<u64> {
+        if logs.is_empty() {
+            debug!("Нет Message Tracking логов для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                "message_tracking_logs",
+                &[
+                    "date_time",
+                    "client_ip",
+                    "client_hostname",
+                    "server_ip",
+                    "server_hostname",
+                    "source_context",
+                    "connector_id",
+                    "source",
+                    "event_id",
+                    "internal_message_id",
+                    "message_id",
+                    "network_message_id",
+                    "recipient_address",
+                    "recipient_status",
+                    "total_bytes",
+                    "recipient_count",
+                    "related_recipient_address",
+                    "reference",
+                    "message_subject",
+                    "sender_address",
+                    "return_path",
+                    "message_info",
+                    "directionality",
+                    "tenant_id",
+                    "original_client_ip",
+                    "original_server_ip",
+                    "custom_data",
+                    "transport_traffic_type",
+                    "log_id",
+                    "schema_version",
+                ],
+                logs,
+                |log| {
+                    vec![
+                        log.date_time.naive_utc().into(),
+                        log.client_ip.into(),
+                        log.client_hostname.into(),
+                        log.server_ip.into(),
+                        log.server_hostname.into(),
+                        log.source_context.into(),
+                        log.connector_id.into(),
+                        log.source.into(),
+                        varchar(log.event_id, 64).into(),
+                        varchar(log.internal_message_id, 128).into(),
+                        log.message_id.into(),
+                        log.network_message_id.into(),
+                        varchar(log.recipient_address, 320).into(),
+                        log.recipient_status.into(),
+                        log.total_bytes.into(),
+                        log.recipient_count.into(),
+                        log.related_recipient_address.into(),
+                        log.reference.into(),
+                        log.message_subject.into(),
+                        log.sender_address.into(),
+                        log.return_path.into(),
+                        log.message_info.into(),
+                        log.directionality.into(),
+                        log.tenant_id.into(),
+                        log.original_client_ip.into(),
+                        log.original_server_ip.into(),
+                        log.custom_data.into(),
+                        log.transport_traffic_type.into(),
+                        log.log_id.into(),
+                        log.schema_version.into(),
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} Message Tracking logs", inserted_count);
+        Ok(inserted_count)
+    }
+
+    async fn insert_rejected_lines(&self, lines: Vec<RejectedLine>) -> Result<u64> {
+        if lines.is_empty() {
+            debug!("Нет отклоненных строк для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                "rejected_lines",
+                &["rejected_at", "file_path", "line_number", "line", "reason"],
+                lines,
+                |line| {
+                    vec![
+                        line.rejected_at.naive_utc().into(),
+                        varchar(line.file_path, 512).into(),
+                        line.line_number.into(),
+                        line.line.into(),
+                        line.reason.into(),
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} rejected lines", inserted_count);
+        Ok(inserted_count)
+    }
+
+    async fn get_ingested_file(&self, path: &str) -> Result<Option<IngestedFile>> {
+        let mut conn = self.pool.get_conn().await?;
+
+        let row: Option<Row> = conn
+            .exec_first(
+                format!(
+                    "SELECT `id`, `path`, `size`, `modified_at`, `content_hash`, `byte_offset`,
+                    `line_number`, `log_type`, `record_count`, `ingested_at`
+                    FROM `{prefix}ingested_files` WHERE `path` = ?",
+                    prefix = self.table_prefix
+                ),
+                (path,),
+            )
+            .await?;
+
+        Ok(row.map(|row| IngestedFile {
+            id: row.get("id"),
+            path: row.get("path").unwrap_or_default(),
+            size: row.get("size").unwrap_or_default(),
+            modified_at: row
+                .get::<NaiveDateTime, _>("modified_at")
+                .unwrap_or_default()
+                .and_utc(),
+            content_hash: row.get("content_hash").unwrap_or_default(),
+            byte_offset: row.get("byte_offset").unwrap_or_default(),
+            line_number: row.get("line_number").unwrap_or_default(),
+            log_type: row.get("log_type").unwrap_or_default(),
+            record_count: row.get("record_count").unwrap_or_default(),
+            ingested_at: row
+                .get::<NaiveDateTime, _>("ingested_at")
+                .unwrap_or_default()
+                .and_utc(),
+        }))
+    }
+
+    async fn save_ingested_file(&self, file: IngestedFile) -> Result<()> {
+        let mut conn = self.pool.get_conn().await?;
+
+        // VALUES() is deprecated in MySQL 8.0.20, but its replacement is not supported by MariaDB
+        conn.exec_drop(
+            format!(
+                "INSERT INTO `{prefix}ingested_files`
+                (`path`, `size`, `modified_at`, `content_hash`, `byte_offset`, `line_number`,
+                `log_type`, `record_count`, `ingested_at`)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+                ON DUPLICATE KEY UPDATE
+                `size` = VALUES(`size`), `modified_at` = VALUES(`modified_at`),
+                `content_hash` = VALUES(`content_hash`), `byte_offset` = VALUES(`byte_offset`),
+                `line_number` = VALUES(`line_number`), `log_type` = VALUES(`log_type`),
+                `record_count` = VALUES(`record_count`), `ingested_at` = VALUES(`ingested_at`)",
+                prefix = self.table_prefix
+            ),
+            vec![
+                Value::from(&file.path),
+                file.size.into(),
+                file.modified_at.naive_utc().into(),
+                Value::from(&file.content_hash),
+                file.byte_offset.into(),
+                file.line_number.into(),
+                Value::from(&file.log_type),
+                file.record_count.into(),
+                file.ingested_at.naive_utc().into(),
+            ],
+        )
+        .await?;
+
+        debug!("Saved ingestion journal entry for {}", file.path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserts_several_rows_and_skips_duplicates() {
+        let sql = insert_sql(
+            "elp_",
+            "smtp_receive_recipients",
+            &["date_time", "recipient"],
+            3,
+        );
+
+        assert_eq!(
+            sql,
+            "INSERT INTO `elp_smtp_receive_recipients` (`date_time`, `recipient`) \
+            VALUES (?, ?), (?, ?), (?, ?) \
+            ON DUPLICATE KEY UPDATE `id` = `id`"
+        );
+    }
+
+    #[test]
+    fn cuts_values_to_column_length_in_characters() {
+        assert_eq!(varchar("user@example.com".into(), 320), "user@example.com");
+        assert_eq!(varchar("a".repeat(300), 255), "a".repeat(255));
+        assert_eq!(varchar("Тема письма".into(), 4), "Тема");
+    }
+}
@@ -1,11 +1,90 @@
-use crate::models::{MessageTrackingLog, SmtpReceiveLog, SmtpSendLog};
+use crate::models::{
+    IngestedFile, MessageTrackingLog, RejectedLine, SmtpDirection, SmtpEvent, SmtpReceiveLog,
+    SmtpRecipient, SmtpSendLog, SmtpTransaction,
+};
+use crate::tls;
 use async_trait::async_trait;
-use color_eyre::eyre::Result;
-use deadpool_postgres::{Config, Pool, Runtime};
+use color_eyre::eyre::{Report, Result, eyre};
+use deadpool_postgres::{Config, Pool, PoolError, Runtime};
 use log::{debug, info};
+use std::path::PathBuf;
+use std::pin::pin;
+use std::sync::Arc;
 use tokio_postgres::NoTls;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{
+    CryptoProvider, verify_tls12_signature, verify_tls13_signature,
+};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, SignatureScheme,
+};
 
 use super::Database;
+use super::migrations::{BYTE_COUNTERS, Migration, TABLES};
+
+/// TLS mode of the connection to PostgreSQL
+///
+/// This enum is used to choose with `--db-sslmode` whether the connection is
+/// encrypted and how the certificate of the server is checked. The modes are
+/// those of `sslmode` of libpq (`allow` is not supported).
+///
+/// ### Examples
+///
+/// ```
+/// let mode: SslMode = "verify-full".parse()?;
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SslMode {
+    /// No TLS
+    Disable,
+    /// TLS if the server supports it, without checking the certificate
+    #[default]
+    Prefer,
+    /// TLS without checking the certificate (checking the chain if a CA is given, as libpq)
+    Require,
+    /// TLS, the certificate must be issued by a trusted CA
+    VerifyCa,
+    /// TLS, the certificate must be issued by a trusted CA for the host name
+    VerifyFull,
+}
+
+impl std::str::FromStr for SslMode {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "disable" => Ok(SslMode::Disable),
+            "prefer" => Ok(SslMode::Prefer),
+            "require" => Ok(SslMode::Require),
+            "verify-ca" => Ok(SslMode::VerifyCa),
+            "verify-full" => Ok(SslMode::VerifyFull),
+            _ => Err(eyre!("Неподдерживаемый режим TLS PostgreSQL: {}", s)),
+        }
+    }
+}
+
+/// TLS settings of the connection to PostgreSQL
+///
+/// This struct is used to pass `--db-sslmode`, the CA bundle and the client
+/// certificate to `PostgresDatabase::new`. Without a CA bundle the Mozilla roots
+/// are trusted.
+#[derive(Debug, Clone, Default)]
+pub struct SslOptions {
+    pub mode: SslMode,
+    /// PEM file with the trusted CA certificates
+    pub root_cert: Option<PathBuf>,
+    /// PEM file with the client certificate (and its chain)
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the private key of the client certificate
+    pub client_key: Option<PathBuf>,
+}
 
 pub struct PostgresDatabase {
     pool: Pool,
@@ -20,6 +99,7 @@ impl PostgresDatabase {
         password: &str,
         dbname: &str,
         table_prefix: Option<&str>,
+        ssl: &SslOptions,
     ) -> Result<Self> {
         let mut cfg = Config::new();
         cfg.host = Some(host.to_string());
@@ -28,27 +108,43 @@ impl PostgresDatabase {
         cfg.password = Some(password.to_string());
         cfg.dbname = Some(dbname.to_string());
 
-        let pool = cfg.create_pool(Some(Runtime::Tokio1), NoTls)?;
+        let pool = match ssl.mode {
+            SslMode::Disable => cfg.create_pool(Some(Runtime::Tokio1), NoTls)?,
+            SslMode::Prefer => {
+                cfg.ssl_mode = Some(deadpool_postgres::SslMode::Prefer);
+                let tls = MakeRustlsConnect::new(tls_config(ssl)?);
+                cfg.create_pool(Some(Runtime::Tokio1), tls)?
+            }
+            SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => {
+                cfg.ssl_mode = Some(deadpool_postgres::SslMode::Require);
+                let tls = MakeRustlsConnect::new(tls_config(ssl)?);
+                cfg.create_pool(Some(Runtime::Tokio1), tls)?
+            }
+        };
+
+        drop(
+            pool.get()
+                .await
+                .map_err(|e| connection_error(e, host, port))?,
+        );
+        debug!("Connected to PostgreSQL {}:{} ({:?})", host, port, ssl.mode);
 
         let db = PostgresDatabase {
             pool,
             table_prefix: table_prefix.unwrap_or("").to_string(),
         };
-        db.init_tables().await?;
 
         Ok(db)
     }
-}
 
-#[async_trait]
-impl Database for PostgresDatabase {
-    async fn init_tables(&self) -> Result<()> {
-        let client = self.pool.get().await?;
+    /// Schema of the first version: the tables as created by `CREATE TABLE IF NOT EXISTS`
+    /// before versioned migrations were introduced
+    fn initial_schema(&self) -> String {
+        let mut schema = String::new();
 
         // Create SMTP Receive logs table
-        client
-            .batch_execute(&format!(
-                r#"
+        schema.push_str(&format!(
+            r#"
             CREATE TABLE IF NOT EXISTS {prefix}smtp_receive_logs (
                 id SERIAL PRIMARY KEY,
                 date_time TIMESTAMPTZ NOT NULL,
@@ -69,14 +165,12 @@ impl Database for PostgresDatabase {
             CREATE UNIQUE INDEX IF NOT EXISTS {prefix}smtp_receive_logs_unique_idx 
             ON {prefix}smtp_receive_logs (date_time, session_id, sequence_number);
             "#,
-                prefix = self.table_prefix
-            ))
-            .await?;
+            prefix = self.table_prefix
+        ));
 
         // Create SMTP Send logs table
-        client
-            .batch_execute(&format!(
-                r#"
+        schema.push_str(&format!(
+            r#"
             CREATE TABLE IF NOT EXISTS {prefix}smtp_send_logs (
                 id SERIAL PRIMARY KEY,
                 date_time TIMESTAMPTZ NOT NULL,
@@ -97,12 +191,88 @@ impl Database for PostgresDatabase {
             CREATE UNIQUE INDEX IF NOT EXISTS {prefix}smtp_send_logs_unique_idx 
             ON {prefix}smtp_send_logs (date_time, session_id, sequence_number);
             "#,
-                prefix = self.table_prefix
-            ))
-            .await?;
+            prefix = self.table_prefix
+        ));
+
+        // Create SMTP protocol events tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            CREATE TABLE IF NOT EXISTS {prefix}{table} (
+                id SERIAL PRIMARY KEY,
+                date_time TIMESTAMPTZ NOT NULL,
+                connector_id TEXT NOT NULL,
+                session_id TEXT NOT NULL,
+                sequence_number INTEGER NOT NULL,
+                local_endpoint TEXT NOT NULL,
+                remote_endpoint TEXT NOT NULL,
+                event TEXT NOT NULL,
+                data TEXT,
+                context TEXT
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS {prefix}{table}_unique_idx 
+            ON {prefix}{table} (date_time, session_id, sequence_number);
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("events")
+            ));
+        }
+
+        // Create SMTP transactions tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            CREATE TABLE IF NOT EXISTS {prefix}{table} (
+                id SERIAL PRIMARY KEY,
+                date_time TIMESTAMPTZ NOT NULL,
+                connector_id TEXT NOT NULL,
+                session_id TEXT NOT NULL,
+                transaction_index INTEGER NOT NULL,
+                local_endpoint TEXT NOT NULL,
+                remote_endpoint TEXT NOT NULL,
+                sender TEXT,
+                recipient_count INTEGER NOT NULL,
+                size INTEGER,
+                message_id TEXT,
+                record_id TEXT,
+                response_code INTEGER,
+                response TEXT
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS {prefix}{table}_unique_idx 
+            ON {prefix}{table} (date_time, session_id, transaction_index);
+            CREATE INDEX IF NOT EXISTS {prefix}{table}_message_id_idx 
+            ON {prefix}{table} (message_id);
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("transactions")
+            ));
+        }
+
+        // Create SMTP recipients tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            CREATE TABLE IF NOT EXISTS {prefix}{table} (
+                id SERIAL PRIMARY KEY,
+                date_time TIMESTAMPTZ NOT NULL,
+                session_id TEXT NOT NULL,
+                sequence_number INTEGER NOT NULL,
+                transaction_index INTEGER NOT NULL,
+                recipient TEXT NOT NULL,
+                response_code INTEGER
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS {prefix}{table}_unique_idx 
+            ON {prefix}{table} (date_time, session_id, sequence_number);
+            CREATE INDEX IF NOT EXISTS {prefix}{table}_recipient_idx 
+            ON {prefix}{table} (recipient);
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("recipients")
+            ));
+        }
 
         // Create Message Tracking logs table
-        client.batch_execute(&format!(
+        schema.push_str(&format!(
             r#"
             CREATE TABLE IF NOT EXISTS {prefix}message_tracking_logs (
                 id SERIAL PRIMARY KEY,
@@ -141,39 +311,444 @@ impl Database for PostgresDatabase {
             ON {prefix}message_tracking_logs (date_time, internal_message_id, recipient_address, event_id);
             "#,
             prefix = self.table_prefix
-        )).await?;
+        ));
 
-        info!("Database tables initialized successfully");
-        Ok(())
+        // Create rejected lines table
+        schema.push_str(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {prefix}rejected_lines (
+                id SERIAL PRIMARY KEY,
+                rejected_at TIMESTAMPTZ NOT NULL,
+                file_path TEXT NOT NULL,
+                line_number INTEGER NOT NULL,
+                line TEXT NOT NULL,
+                reason TEXT NOT NULL
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS {prefix}rejected_lines_unique_idx 
+            ON {prefix}rejected_lines (file_path, line_number);
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        // Create ingestion journal table
+        schema.push_str(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {prefix}ingested_files (
+                id SERIAL PRIMARY KEY,
+                path TEXT NOT NULL,
+                size BIGINT NOT NULL,
+                modified_at TIMESTAMPTZ NOT NULL,
+                content_hash TEXT NOT NULL,
+                byte_offset BIGINT NOT NULL,
+                line_number BIGINT NOT NULL,
+                log_type TEXT NOT NULL,
+                record_count BIGINT NOT NULL,
+                ingested_at TIMESTAMPTZ NOT NULL
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS {prefix}ingested_files_unique_idx 
+            ON {prefix}ingested_files (path);
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        schema
     }
 
-    async fn insert_smtp_receive_logs(&self, logs: Vec<SmtpReceiveLog>) -> Result<u64> {
-        if logs.is_empty() {
-            debug!("Нет SMTP Receive логов для вставки");
-            return Ok(0);
+    /// Schema of the second version: 64-bit identifiers and message sizes
+    fn bigint_schema(&self) -> String {
+        let mut schema = String::new();
+
+        for table in TABLES {
+            let columns: Vec<String> = std::iter::once("id")
+                .chain(
+                    BYTE_COUNTERS
+                        .iter()
+                        .filter(|(counter_table, _)| *counter_table == table)
+                        .map(|(_, column)| *column),
+                )
+                .map(|column| format!("ALTER COLUMN {column} TYPE BIGINT"))
+                .collect();
+
+            schema.push_str(&format!(
+                r#"
+            ALTER TABLE {prefix}{table} {columns};
+            ALTER SEQUENCE {prefix}{table}_id_seq AS BIGINT;
+            "#,
+                prefix = self.table_prefix,
+                columns = columns.join(", ")
+            ));
         }
 
+        schema
+    }
+
+    /// Inserts rows with binary `COPY` into a temporary staging table and moves
+    /// them into the target table with `INSERT ... SELECT`
+    ///
+    /// Rows conflicting with `unique_columns` (already in the table or repeated
+    /// in the batch) are skipped. Returns the number of rows inserted.
+    async fn copy_rows<T: Sync>(
+        &self,
+        table: &str,
+        columns: &[(&str, Type)],
+        unique_columns: &str,
+        rows: &[T],
+        values: impl Fn(&T) -> Vec<&(dyn ToSql + Sync)> + Send + Sync,
+    ) -> Result<u64> {
         let mut client = self.pool.get().await?;
-        let mut inserted_count = 0;
+        let tx = client.transaction().await?;
+
+        let names: Vec<&str> = columns.iter().map(|(name, _)| *name).collect();
+        let types: Vec<Type> = columns.iter().map(|(_, ty)| ty.clone()).collect();
+        let statements = CopyStatements::new(&self.table_prefix, table, &names, unique_columns);
+
+        tx.batch_execute(&statements.create).await?;
+
+        let sink = tx.copy_in(&statements.copy).await?;
+        let mut writer = pin!(BinaryCopyInWriter::new(sink, &types));
+        for row in rows {
+            writer.as_mut().write(&values(row)).await?;
+        }
+        writer.finish().await?;
+
+        let inserted_count = tx.execute(&statements.insert, &[]).await?;
+
+        tx.commit().await?;
+        Ok(inserted_count)
+    }
+}
+
+/// Statements of a bulk insert through a staging table
+///
+/// This struct is used to build the SQL of `copy_rows` apart from the connection.
+#[derive(Debug, PartialEq)]
+struct CopyStatements {
+    create: String,
+    copy: String,
+    insert: String,
+}
+
+impl CopyStatements {
+    fn new(prefix: &str, table: &str, names: &[&str], unique_columns: &str) -> Self {
+        let names = names.join(", ");
+        let staging = format!("{}_staging", table);
+        CopyStatements {
+            // The staging table has only the copied columns, so that the identity
+            // sequence of the target table is not touched for duplicates
+            create: format!(
+                "CREATE TEMP TABLE {staging} ON COMMIT DROP AS \
+                SELECT {names} FROM {prefix}{table} WITH NO DATA"
+            ),
+            copy: format!("COPY {staging} ({names}) FROM STDIN BINARY"),
+            insert: format!(
+                "INSERT INTO {prefix}{table} ({names}) \
+                SELECT {names} FROM {staging} \
+                ON CONFLICT ({unique_columns}) DO NOTHING"
+            ),
+        }
+    }
+}
+
+/// Check of the server certificate done in a TLS mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verification {
+    /// The certificate is not checked
+    None,
+    /// The chain is checked, the host name is not
+    IgnoreHostName,
+    /// The chain and the host name are checked
+    Full,
+}
+
+/// Chooses the check of the server certificate for a TLS mode
+fn verification(ssl: &SslOptions) -> Verification {
+    match ssl.mode {
+        SslMode::VerifyFull => Verification::Full,
+        SslMode::VerifyCa => Verification::IgnoreHostName,
+        // As libpq, `require` with a CA bundle checks the chain like `verify-ca`
+        SslMode::Require if ssl.root_cert.is_some() => Verification::IgnoreHostName,
+        SslMode::Disable | SslMode::Prefer | SslMode::Require => Verification::None,
+    }
+}
+
+/// Builds the rustls configuration for a TLS mode
+fn tls_config(ssl: &SslOptions) -> Result<ClientConfig> {
+    let provider = tls::crypto_provider();
+    let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match verification(ssl) {
+        Verification::Full => {
+            builder.with_root_certificates(tls::root_certificates(ssl.root_cert.as_deref())?)
+        }
+        Verification::IgnoreHostName => {
+            let roots = tls::root_certificates(ssl.root_cert.as_deref())?;
+            let verifier =
+                WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(IgnoreHostName(verifier)))
+        }
+        Verification::None => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification(provider))),
+    };
+
+    match (&ssl.client_cert, &ssl.client_key) {
+        (Some(cert), Some(key)) => Ok(builder
+            .with_client_auth_cert(tls::load_certificates(cert)?, tls::load_private_key(key)?)?),
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err(eyre!(
+            "Сертификат и ключ клиента PostgreSQL (--db-ssl-cert и --db-ssl-key) задаются вместе"
+        )),
+    }
+}
+
+/// Describes an error of the first connection, explaining the TLS errors
+fn connection_error(error: PoolError, host: &str, port: u16) -> Report {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&error);
+    while let Some(e) = source {
+        let tls_error = e
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref())
+            .and_then(|e| e.downcast_ref::<tokio_rustls::rustls::Error>());
+        match tls_error {
+            Some(tokio_rustls::rustls::Error::InvalidCertificate(e)) => {
+                return eyre!(
+                    "Сертификат сервера PostgreSQL {}:{} не прошел проверку: {}. Укажите CA сервера в --db-ssl-root-cert или выберите другой режим --db-sslmode",
+                    host,
+                    port,
+                    e
+                );
+            }
+            Some(e) => {
+                return eyre!(
+                    "Ошибка TLS при подключении к PostgreSQL {}:{}: {}",
+                    host,
+                    port,
+                    e
+                );
+            }
+            None => source = e.source(),
+        }
+    }
+    Report::new(error).wrap_err(format!(
+        "Не удалось подключиться к PostgreSQL {}:{}",
+        host, port
+    ))
+}
+
+/// Certificate verifier of `prefer` and `require`: the connection is encrypted,
+/// but the server is not authenticated
+#[derive(Debug)]
+struct NoCertificateVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Certificate verifier of `verify-ca`: the chain is checked, the host name is not
+#[derive(Debug)]
+struct IgnoreHostName(Arc<WebPkiServerVerifier>);
 
+impl ServerCertVerifier for IgnoreHostName {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        match self
+            .0
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+        {
+            Err(tokio_rustls::rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) => Ok(ServerCertVerified::assertion()),
+            result => result,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+#[async_trait]
+impl Database for PostgresDatabase {
+    async fn schema_version(&self) -> Result<i32> {
+        let client = self.pool.get().await?;
+        let table = format!("{}schema_version", self.table_prefix);
+
+        let exists: bool = client
+            .query_one("SELECT to_regclass($1) IS NOT NULL", &[&table])
+            .await?
+            .get(0);
+        if !exists {
+            return Ok(0);
+        }
+
+        let row = client
+            .query_one(
+                &format!("SELECT COALESCE(MAX(version), 0) FROM {table}"),
+                &[],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    fn migrations(&self) -> Vec<Migration> {
+        vec![
+            Migration {
+                version: 1,
+                description: "initial schema",
+                sql: self.initial_schema(),
+            },
+            Migration {
+                version: 2,
+                description: "64-bit identifiers and message sizes",
+                sql: self.bigint_schema(),
+            },
+        ]
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        let mut client = self.pool.get().await?;
         let tx = client.transaction().await?;
 
-        let stmt = tx
-            .prepare(&format!(
-                "INSERT INTO {prefix}smtp_receive_logs 
-            (date_time, connector_id, session_id, sequence_number, local_endpoint, remote_endpoint, 
-            event, data, context, sender, recipient, message_id, subject, size)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
-            ON CONFLICT (date_time, session_id, sequence_number) DO NOTHING",
-                prefix = self.table_prefix
-            ))
+        tx.batch_execute(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {prefix}schema_version (
+                version INTEGER PRIMARY KEY,
+                description TEXT NOT NULL,
+                applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            LOCK TABLE {prefix}schema_version IN EXCLUSIVE MODE;
+            "#,
+            prefix = self.table_prefix
+        ))
+        .await?;
+
+        // Another instance may have applied the migration while we waited for the lock
+        let applied: bool = tx
+            .query_one(
+                &format!(
+                    "SELECT EXISTS (SELECT 1 FROM {prefix}schema_version WHERE version = $1)",
+                    prefix = self.table_prefix
+                ),
+                &[&migration.version],
+            )
+            .await?
+            .get(0);
+
+        if !applied {
+            tx.batch_execute(&migration.sql).await?;
+            tx.execute(
+                &format!(
+                    "INSERT INTO {prefix}schema_version (version, description) VALUES ($1, $2)",
+                    prefix = self.table_prefix
+                ),
+                &[&migration.version, &migration.description],
+            )
             .await?;
+        }
+
+        tx.commit().await?;
 
-        for log in logs {
-            let result = tx
-                .execute(
-                    &stmt,
-                    &[
+        info!("Database schema migrated to version {}", migration.version);
+        Ok(())
+    }
+
+    async fn insert_smtp_receive_logs(&self, logs: Vec<SmtpReceiveLog>) -> Result<u64> {
+        if logs.is_empty() {
+            debug!("Нет SMTP Receive логов для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .copy_rows(
+                "smtp_receive_logs",
+                &[
+                    ("date_time", Type::TIMESTAMPTZ),
+                    ("connector_id", Type::TEXT),
+                    ("session_id", Type::TEXT),
+                    ("sequence_number", Type::INT4),
+                    ("local_endpoint", Type::TEXT),
+                    ("remote_endpoint", Type::TEXT),
+                    ("event", Type::TEXT),
+                    ("data", Type::TEXT),
+                    ("context", Type::TEXT),
+                    ("sender", Type::TEXT),
+                    ("recipient", Type::TEXT),
+                    ("message_id", Type::TEXT),
+                    ("subject", Type::TEXT),
+                    ("size", Type::INT8),
+                ],
+                "date_time, session_id, sequence_number",
+                &logs,
+                |log| {
+                    vec![
                         &log.date_time,
                         &log.connector_id,
                         &log.session_id,
@@ -188,13 +763,10 @@ impl Database for PostgresDatabase {
                         &log.message_id,
                         &log.subject,
                         &log.size,
-                    ],
-                )
-                .await?;
-            inserted_count += result;
-        }
-
-        tx.commit().await?;
+                    ]
+                },
+            )
+            .await?;
 
         debug!("Inserted {} SMTP Receive logs", inserted_count);
         Ok(inserted_count)
@@ -206,27 +778,29 @@ impl Database for PostgresDatabase {
             return Ok(0);
         }
 
-        let mut client = self.pool.get().await?;
-        let mut inserted_count = 0;
-
-        let tx = client.transaction().await?;
-
-        let stmt = tx
-            .prepare(&format!(
-                "INSERT INTO {prefix}smtp_send_logs 
-            (date_time, connector_id, session_id, sequence_number, local_endpoint, remote_endpoint, 
-            event, data, context, proxy_session_id, sender, recipient, message_id, record_id)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
-            ON CONFLICT (date_time, session_id, sequence_number) DO NOTHING",
-                prefix = self.table_prefix
-            ))
-            .await?;
-
-        for log in logs {
-            let result = tx
-                .execute(
-                    &stmt,
-                    &[
+        let inserted_count = self
+            .copy_rows(
+                "smtp_send_logs",
+                &[
+                    ("date_time", Type::TIMESTAMPTZ),
+                    ("connector_id", Type::TEXT),
+                    ("session_id", Type::TEXT),
+                    ("sequence_number", Type::INT4),
+                    ("local_endpoint", Type::TEXT),
+                    ("remote_endpoint", Type::TEXT),
+                    ("event", Type::TEXT),
+                    ("data", Type::TEXT),
+                    ("context", Type::TEXT),
+                    ("proxy_session_id", Type::TEXT),
+                    ("sender", Type::TEXT),
+                    ("recipient", Type::TEXT),
+                    ("message_id", Type::TEXT),
+                    ("record_id", Type::TEXT),
+                ],
+                "date_time, session_id, sequence_number",
+                &logs,
+                |log| {
+                    vec![
                         &log.date_time,
                         &log.connector_id,
                         &log.session_id,
@@ -241,47 +815,206 @@ impl Database for PostgresDatabase {
                         &log.recipient,
                         &log.message_id,
                         &log.record_id,
-                    ],
-                )
-                .await?;
-            inserted_count += result;
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} SMTP Send logs", inserted_count);
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_events(
+        &self,
+        direction: SmtpDirection,
+        events: Vec<SmtpEvent>,
+    ) -> Result<u64> {
+        if events.is_empty() {
+            debug!("Нет событий SMTP для вставки");
+            return Ok(0);
         }
 
-        tx.commit().await?;
+        let inserted_count = self
+            .copy_rows(
+                &direction.table_name("events"),
+                &[
+                    ("date_time", Type::TIMESTAMPTZ),
+                    ("connector_id", Type::TEXT),
+                    ("session_id", Type::TEXT),
+                    ("sequence_number", Type::INT4),
+                    ("local_endpoint", Type::TEXT),
+                    ("remote_endpoint", Type::TEXT),
+                    ("event", Type::TEXT),
+                    ("data", Type::TEXT),
+                    ("context", Type::TEXT),
+                ],
+                "date_time, session_id, sequence_number",
+                &events,
+                |event| {
+                    vec![
+                        &event.date_time,
+                        &event.connector_id,
+                        &event.session_id,
+                        &event.sequence_number,
+                        &event.local_endpoint,
+                        &event.remote_endpoint,
+                        &event.event,
+                        &event.data,
+                        &event.context,
+                    ]
+                },
+            )
+            .await?;
 
-        debug!("Inserted {} SMTP Send logs", inserted_count);
+        debug!("Inserted {} {:?} SMTP events", inserted_count, direction);
         Ok(inserted_count)
     }
 
-    async fn insert_message_tracking_logs(&self, logs: Vec<MessageTrackingLog>) -> Result<u64> {
-        if logs.is_empty() {
-            debug!("Нет Message Tracking логов для вставки");
+    async fn insert_smtp_transactions(
+        &self,
+        direction: SmtpDirection,
+        transactions: Vec<SmtpTransaction>,
+    ) -> Result<u64> {
+        if transactions.is_empty() {
+            debug!("Нет транзакций SMTP для вставки");
             return Ok(0);
         }
 
-        let mut client = self.pool.get().await?;
-        let mut inserted_count = 0;
+        let inserted_count = self
+            .copy_rows(
+                &direction.table_name("transactions"),
+                &[
+                    ("date_time", Type::TIMESTAMPTZ),
+                    ("connector_id", Type::TEXT),
+                    ("session_id", Type::TEXT),
+                    ("transaction_index", Type::INT4),
+                    ("local_endpoint", Type::TEXT),
+                    ("remote_endpoint", Type::TEXT),
+                    ("sender", Type::TEXT),
+                    ("recipient_count", Type::INT4),
+                    ("size", Type::INT8),
+                    ("message_id", Type::TEXT),
+                    ("record_id", Type::TEXT),
+                    ("response_code", Type::INT4),
+                    ("response", Type::TEXT),
+                ],
+                "date_time, session_id, transaction_index",
+                &transactions,
+                |transaction| {
+                    vec![
+                        &transaction.date_time,
+                        &transaction.connector_id,
+                        &transaction.session_id,
+                        &transaction.transaction_index,
+                        &transaction.local_endpoint,
+                        &transaction.remote_endpoint,
+                        &transaction.sender,
+                        &transaction.recipient_count,
+                        &transaction.size,
+                        &transaction.message_id,
+                        &transaction.record_id,
+                        &transaction.response_code,
+                        &transaction.response,
+                    ]
+                },
+            )
+            .await?;
 
-        let tx = client.transaction().await?;
+        debug!(
+            "Inserted {} {:?} SMTP transactions",
+            inserted_count, direction
+        );
+        Ok(inserted_count)
+    }
 
-        let stmt = tx.prepare(&format!(
-            "INSERT INTO {prefix}message_tracking_logs 
-            (date_time, client_ip, client_hostname, server_ip, server_hostname, source_context,
-            connector_id, source, event_id, internal_message_id, message_id, network_message_id,
-            recipient_address, recipient_status, total_bytes, recipient_count, related_recipient_address,
-            reference, message_subject, sender_address, return_path, message_info, directionality,
-            tenant_id, original_client_ip, original_server_ip, custom_data, transport_traffic_type,
-            log_id, schema_version)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30)
-            ON CONFLICT (date_time, internal_message_id, recipient_address, event_id) DO NOTHING",
-            prefix = self.table_prefix
-        )).await?;
+    async fn insert_smtp_recipients(
+        &self,
+        direction: SmtpDirection,
+        recipients: Vec<SmtpRecipient>,
+    ) -> Result<u64> {
+        if recipients.is_empty() {
+            debug!("Нет получателей SMTP для вставки");
+            return Ok(0);
+        }
 
-        for log in logs {
-            let result = tx
-                .execute(
-                    &stmt,
-                    &[
+        let inserted_count = self
+            .copy_rows(
+                &direction.table_name("recipients"),
+                &[
+                    ("date_time", Type::TIMESTAMPTZ),
+                    ("session_id", Type::TEXT),
+                    ("sequence_number", Type::INT4),
+                    ("transaction_index", Type::INT4),
+                    ("recipient", Type::TEXT),
+                    ("response_code", Type::INT4),
+                ],
+                "date_time, session_id, sequence_number",
+                &recipients,
+                |recipient| {
+                    vec![
+                        &recipient.date_time,
+                        &recipient.session_id,
+                        &recipient.sequence_number,
+                        &recipient.transaction_index,
+                        &recipient.recipient,
+                        &recipient.response_code,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!(
+            "Inserted {} {:?} SMTP recipients",
+            inserted_count, direction
+        );
+        Ok(inserted_count)
+    }
+
+    async fn insert_message_tracking_logs(&self, logs: Vec<MessageTrackingLog>) -> Result<u64> {
+        if logs.is_empty() {
+            debug!("Нет Message Tracking логов для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .copy_rows(
+                "message_tracking_logs",
+                &[
+                    ("date_time", Type::TIMESTAMPTZ),
+                    ("client_ip", Type::TEXT),
+                    ("client_hostname", Type::TEXT),
+                    ("server_ip", Type::TEXT),
+                    ("server_hostname", Type::TEXT),
+                    ("source_context", Type::TEXT),
+                    ("connector_id", Type::TEXT),
+                    ("source", Type::TEXT),
+                    ("event_id", Type::TEXT),
+                    ("internal_message_id", Type::TEXT),
+                    ("message_id", Type::TEXT),
+                    ("network_message_id", Type::TEXT),
+                    ("recipient_address", Type::TEXT),
+                    ("recipient_status", Type::TEXT),
+                    ("total_bytes", Type::INT8),
+                    ("recipient_count", Type::INT4),
+                    ("related_recipient_address", Type::TEXT),
+                    ("reference", Type::TEXT),
+                    ("message_subject", Type::TEXT),
+                    ("sender_address", Type::TEXT),
+                    ("return_path", Type::TEXT),
+                    ("message_info", Type::TEXT),
+                    ("directionality", Type::TEXT),
+                    ("tenant_id", Type::TEXT),
+                    ("original_client_ip", Type::TEXT),
+                    ("original_server_ip", Type::TEXT),
+                    ("custom_data", Type::TEXT),
+                    ("transport_traffic_type", Type::TEXT),
+                    ("log_id", Type::TEXT),
+                    ("schema_version", Type::TEXT),
+                ],
+                "date_time, internal_message_id, recipient_address, event_id",
+                &logs,
+                |log| {
+                    vec![
                         &log.date_time,
                         &log.client_ip,
                         &log.client_hostname,
@@ -312,15 +1045,204 @@ impl Database for PostgresDatabase {
                         &log.transport_traffic_type,
                         &log.log_id,
                         &log.schema_version,
-                    ],
-                )
-                .await?;
-            inserted_count += result;
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} Message Tracking logs", inserted_count);
+        Ok(inserted_count)
+    }
+
+    async fn insert_rejected_lines(&self, lines: Vec<RejectedLine>) -> Result<u64> {
+        if lines.is_empty() {
+            debug!("Нет отклоненных строк для вставки");
+            return Ok(0);
         }
 
-        tx.commit().await?;
+        let inserted_count = self
+            .copy_rows(
+                "rejected_lines",
+                &[
+                    ("rejected_at", Type::TIMESTAMPTZ),
+                    ("file_path", Type::TEXT),
+                    ("line_number", Type::INT4),
+                    ("line", Type::TEXT),
+                    ("reason", Type::TEXT),
+                ],
+                "file_path, line_number",
+                &lines,
+                |line| {
+                    vec![
+                        &line.rejected_at,
+                        &line.file_path,
+                        &line.line_number,
+                        &line.line,
+                        &line.reason,
+                    ]
+                },
+            )
+            .await?;
 
-        debug!("Inserted {} Message Tracking logs", inserted_count);
+        debug!("Inserted {} rejected lines", inserted_count);
         Ok(inserted_count)
     }
+
+    async fn get_ingested_file(&self, path: &str) -> Result<Option<IngestedFile>> {
+        let client = self.pool.get().await?;
+
+        let row = client
+            .query_opt(
+                &format!(
+                    "SELECT id, path, size, modified_at, content_hash, byte_offset, line_number,
+                    log_type, record_count, ingested_at
+                    FROM {prefix}ingested_files WHERE path = $1",
+                    prefix = self.table_prefix
+                ),
+                &[&path],
+            )
+            .await?;
+
+        Ok(row.map(|row| IngestedFile {
+            id: row.get("id"),
+            path: row.get("path"),
+            size: row.get("size"),
+            modified_at: row.get("modified_at"),
+            content_hash: row.get("content_hash"),
+            byte_offset: row.get("byte_offset"),
+            line_number: row.get("line_number"),
+            log_type: row.get("log_type"),
+            record_count: row.get("record_count"),
+            ingested_at: row.get("ingested_at"),
+        }))
+    }
+
+    async fn save_ingested_file(&self, file: IngestedFile) -> Result<()> {
+        let client = self.pool.get().await?;
+
+        client
+            .execute(
+                &format!(
+                    "INSERT INTO {prefix}ingested_files 
+                    (path, size, modified_at, content_hash, byte_offset, line_number, log_type,
+                    record_count, ingested_at)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                    ON CONFLICT (path) DO UPDATE SET
+                    size = EXCLUDED.size, modified_at = EXCLUDED.modified_at,
+                    content_hash = EXCLUDED.content_hash, byte_offset = EXCLUDED.byte_offset,
+                    line_number = EXCLUDED.line_number, log_type = EXCLUDED.log_type,
+                    record_count = EXCLUDED.record_count, ingested_at = EXCLUDED.ingested_at",
+                    prefix = self.table_prefix
+                ),
+                &[
+                    &file.path,
+                    &file.size,
+                    &file.modified_at,
+                    &file.content_hash,
+                    &file.byte_offset,
+                    &file.line_number,
+                    &file.log_type,
+                    &file.record_count,
+                    &file.ingested_at,
+                ],
+            )
+            .await?;
+
+        debug!("Saved ingestion journal entry for {}", file.path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copies_through_staging_table_and_skips_conflicts() {
+        let statements = CopyStatements::new(
+            "elp_",
+            "smtp_recipients",
+            &["date_time", "session_id", "sequence_number", "recipient"],
+            "date_time, session_id, sequence_number",
+        );
+
+        assert_eq!(
+            statements,
+            CopyStatements {
+                create: "CREATE TEMP TABLE smtp_recipients_staging ON COMMIT DROP AS \
+                    SELECT date_time, session_id, sequence_number, recipient \
+                    FROM elp_smtp_recipients WITH NO DATA"
+                    .to_string(),
+                copy: "COPY smtp_recipients_staging \
+                    (date_time, session_id, sequence_number, recipient) FROM STDIN BINARY"
+                    .to_string(),
+                insert: "INSERT INTO elp_smtp_recipients \
+                    (date_time, session_id, sequence_number, recipient) \
+                    SELECT date_time, session_id, sequence_number, recipient \
+                    FROM smtp_recipients_staging \
+                    ON CONFLICT (date_time, session_id, sequence_number) DO NOTHING"
+                    .to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn staging_table_is_not_prefixed() {
+        let statements = CopyStatements::new("", "rejected_lines", &["file_path"], "file_path");
+
+        assert!(
+            statements
+                .create
+                .ends_with("FROM rejected_lines WITH NO DATA")
+        );
+        assert!(
+            statements
+                .insert
+                .starts_with("INSERT INTO rejected_lines (file_path) SELECT")
+        );
+    }
+
+    fn ssl(mode: &str, root_cert: Option<&str>) -> SslOptions {
+        SslOptions {
+            mode: mode.parse().unwrap(),
+            root_cert: root_cert.map(PathBuf::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn chooses_certificate_check_for_each_mode() {
+        assert_eq!(verification(&ssl("prefer", None)), Verification::None);
+        assert_eq!(verification(&ssl("require", None)), Verification::None);
+        assert_eq!(
+            verification(&ssl("verify-ca", None)),
+            Verification::IgnoreHostName
+        );
+        assert_eq!(verification(&ssl("verify-full", None)), Verification::Full);
+    }
+
+    #[test]
+    fn require_with_ca_bundle_checks_the_chain() {
+        assert_eq!(
+            verification(&ssl("require", Some("ca.pem"))),
+            Verification::IgnoreHostName
+        );
+        assert_eq!(
+            verification(&ssl("verify-full", Some("ca.pem"))),
+            Verification::Full
+        );
+    }
+
+    #[test]
+    fn builds_tls_config_for_each_mode() {
+        for mode in ["prefer", "require", "verify-ca", "verify-full"] {
+            assert!(tls_config(&ssl(mode, None)).is_ok(), "{}", mode);
+        }
+
+        let options = SslOptions {
+            client_cert: Some("client.pem".into()),
+            ..ssl("verify-full", None)
+        };
+        assert!(tls_config(&options).is_err());
+    }
 }
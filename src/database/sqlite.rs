@@ -0,0 +1,914 @@
+use crate::models::{
+    IngestedFile, MessageTrackingLog, RejectedLine, SmtpDirection, SmtpEvent, SmtpReceiveLog,
+    SmtpRecipient, SmtpSendLog, SmtpTransaction,
+};
+use async_trait::async_trait;
+use color_eyre::eyre::{Result, eyre};
+use log::{debug, info};
+use rusqlite::{Connection, OptionalExtension, ToSql, TransactionBehavior, params};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::Database;
+use super::migrations::Migration;
+
+/// Embedded SQLite database stored in a single file
+///
+/// This struct is used to analyse logs locally, without a database server. All
+/// queries go through one connection and run on the blocking thread pool.
+pub struct SqliteDatabase {
+    connection: Arc<Mutex<Connection>>,
+    table_prefix: String,
+}
+
+impl SqliteDatabase {
+    pub async fn new(path: &str, table_prefix: Option<&str>) -> Result<Self> {
+        let path = path.to_string();
+        let connection = tokio::task::spawn_blocking(move || -> Result<Connection> {
+            let connection = Connection::open(&path)?;
+            connection.busy_timeout(Duration::from_secs(30))?;
+            connection.pragma_update(None, "journal_mode", "WAL")?;
+            connection.pragma_update(None, "synchronous", "NORMAL")?;
+            Ok(connection)
+        })
+        .await??;
+
+        let db = SqliteDatabase {
+            connection: Arc::new(Mutex::new(connection)),
+            table_prefix: table_prefix.unwrap_or("").to_string(),
+        };
+
+        Ok(db)
+    }
+
+    /// Runs a closure with the connection on the blocking thread pool
+    async fn run<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Connection) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| eyre!("SQLite connection is poisoned"))?;
+            f(&mut connection)
+        })
+        .await?
+    }
+
+    /// Schema of the first version, equivalent to the PostgreSQL schema with
+    /// 64-bit identifiers (`INTEGER PRIMARY KEY` is the 64-bit row id)
+    fn schema(&self) -> String {
+        let mut schema = String::new();
+
+        // Create SMTP Receive logs table
+        schema.push_str(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {prefix}smtp_receive_logs (
+                id INTEGER PRIMARY KEY,
+                date_time TEXT NOT NULL,
+                connector_id TEXT NOT NULL,
+                session_id TEXT NOT NULL,
+                sequence_number INTEGER NOT NULL,
+                local_endpoint TEXT NOT NULL,
+                remote_endpoint TEXT NOT NULL,
+                event TEXT NOT NULL,
+                data TEXT,
+                context TEXT,
+                sender TEXT,
+                recipient TEXT,
+                message_id TEXT,
+                subject TEXT,
+                size INTEGER
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS {prefix}smtp_receive_logs_unique_idx 
+            ON {prefix}smtp_receive_logs (date_time, session_id, sequence_number);
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        // Create SMTP Send logs table
+        schema.push_str(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {prefix}smtp_send_logs (
+                id INTEGER PRIMARY KEY,
+                date_time TEXT NOT NULL,
+                connector_id TEXT NOT NULL,
+                session_id TEXT NOT NULL,
+                sequence_number INTEGER NOT NULL,
+                local_endpoint TEXT NOT NULL,
+                remote_endpoint TEXT NOT NULL,
+                event TEXT NOT NULL,
+                data TEXT,
+                context TEXT,
+                proxy_session_id TEXT,
+                sender TEXT,
+                recipient TEXT,
+                message_id TEXT,
+                record_id TEXT
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS {prefix}smtp_send_logs_unique_idx 
+            ON {prefix}smtp_send_logs (date_time, session_id, sequence_number);
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        // Create SMTP protocol events tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            CREATE TABLE IF NOT EXISTS {prefix}{table} (
+                id INTEGER PRIMARY KEY,
+                date_time TEXT NOT NULL,
+                connector_id TEXT NOT NULL,
+                session_id TEXT NOT NULL,
+                sequence_number INTEGER NOT NULL,
+                local_endpoint TEXT NOT NULL,
+                remote_endpoint TEXT NOT NULL,
+                event TEXT NOT NULL,
+                data TEXT,
+                context TEXT
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS {prefix}{table}_unique_idx 
+            ON {prefix}{table} (date_time, session_id, sequence_number);
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("events")
+            ));
+        }
+
+        // Create SMTP transactions tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            CREATE TABLE IF NOT EXISTS {prefix}{table} (
+                id INTEGER PRIMARY KEY,
+                date_time TEXT NOT NULL,
+                connector_id TEXT NOT NULL,
+                session_id TEXT NOT NULL,
+                transaction_index INTEGER NOT NULL,
+                local_endpoint TEXT NOT NULL,
+                remote_endpoint TEXT NOT NULL,
+                sender TEXT,
+                recipient_count INTEGER NOT NULL,
+                size INTEGER,
+                message_id TEXT,
+                record_id TEXT,
+                response_code INTEGER,
+                response TEXT
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS {prefix}{table}_unique_idx 
+            ON {prefix}{table} (date_time, session_id, transaction_index);
+            CREATE INDEX IF NOT EXISTS {prefix}{table}_message_id_idx 
+            ON {prefix}{table} (message_id);
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("transactions")
+            ));
+        }
+
+        // Create SMTP recipients tables
+        for direction in [SmtpDirection::Receive, SmtpDirection::Send] {
+            schema.push_str(&format!(
+                r#"
+            CREATE TABLE IF NOT EXISTS {prefix}{table} (
+                id INTEGER PRIMARY KEY,
+                date_time TEXT NOT NULL,
+                session_id TEXT NOT NULL,
+                sequence_number INTEGER NOT NULL,
+                transaction_index INTEGER NOT NULL,
+                recipient TEXT NOT NULL,
+                response_code INTEGER
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS {prefix}{table}_unique_idx 
+            ON {prefix}{table} (date_time, session_id, sequence_number);
+            CREATE INDEX IF NOT EXISTS {prefix}{table}_recipient_idx 
+            ON {prefix}{table} (recipient);
+            "#,
+                prefix = self.table_prefix,
+                table = direction.table_name("recipients")
+            ));
+        }
+
+        // Create Message Tracking logs table
+        schema.push_str(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {prefix}message_tracking_logs (
+                id INTEGER PRIMARY KEY,
+                date_time TEXT NOT NULL,
+                client_ip TEXT,
+                client_hostname TEXT,
+                server_ip TEXT,
+                server_hostname TEXT NOT NULL,
+                source_context TEXT,
+                connector_id TEXT,
+                source TEXT,
+                event_id TEXT NOT NULL,
+                internal_message_id TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                network_message_id TEXT NOT NULL,
+                recipient_address TEXT NOT NULL,
+                recipient_status TEXT,
+                total_bytes INTEGER,
+                recipient_count INTEGER NOT NULL,
+                related_recipient_address TEXT,
+                reference TEXT,
+                message_subject TEXT,
+                sender_address TEXT NOT NULL,
+                return_path TEXT,
+                message_info TEXT,
+                directionality TEXT,
+                tenant_id TEXT,
+                original_client_ip TEXT,
+                original_server_ip TEXT,
+                custom_data TEXT,
+                transport_traffic_type TEXT,
+                log_id TEXT,
+                schema_version TEXT
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS {prefix}message_tracking_logs_unique_idx 
+            ON {prefix}message_tracking_logs (date_time, internal_message_id, recipient_address, event_id);
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        // Create rejected lines table
+        schema.push_str(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {prefix}rejected_lines (
+                id INTEGER PRIMARY KEY,
+                rejected_at TEXT NOT NULL,
+                file_path TEXT NOT NULL,
+                line_number INTEGER NOT NULL,
+                line TEXT NOT NULL,
+                reason TEXT NOT NULL
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS {prefix}rejected_lines_unique_idx 
+            ON {prefix}rejected_lines (file_path, line_number);
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        // Create ingestion journal table
+        schema.push_str(&format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {prefix}ingested_files (
+                id INTEGER PRIMARY KEY,
+                path TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                modified_at TEXT NOT NULL,
+                content_hash TEXT NOT NULL,
+                byte_offset INTEGER NOT NULL,
+                line_number INTEGER NOT NULL,
+                log_type TEXT NOT NULL,
+                record_count INTEGER NOT NULL,
+                ingested_at TEXT NOT NULL
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS {prefix}ingested_files_unique_idx 
+            ON {prefix}ingested_files (path);
+            "#,
+            prefix = self.table_prefix
+        ));
+
+        schema
+    }
+
+    /// Inserts rows in one transaction, skipping rows conflicting with `unique_columns`
+    ///
+    /// Returns the number of rows inserted.
+    async fn insert_rows<T: Send + 'static>(
+        &self,
+        table: &str,
+        columns: &'static [&'static str],
+        unique_columns: &'static str,
+        rows: Vec<T>,
+        values: fn(&T) -> Vec<&dyn ToSql>,
+    ) -> Result<u64> {
+        let sql = format!(
+            "INSERT INTO {prefix}{table} ({names}) VALUES ({placeholders})
+            ON CONFLICT ({unique_columns}) DO NOTHING",
+            prefix = self.table_prefix,
+            names = columns.join(", "),
+            placeholders = (1..=columns.len())
+                .map(|i| format!("?{i}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        self.run(move |connection| {
+            let tx = connection.transaction()?;
+            let mut inserted_count = 0;
+            {
+                let mut stmt = tx.prepare_cached(&sql)?;
+                for row in &rows {
+                    inserted_count += stmt.execute(values(row).as_slice())? as u64;
+                }
+            }
+            tx.commit()?;
+            Ok(inserted_count)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl Database for SqliteDatabase {
+    async fn schema_version(&self) -> Result<i32> {
+        let table = format!("{}schema_version", self.table_prefix);
+
+        self.run(move |connection| {
+            let exists: bool = connection.query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+                params![table],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                return Ok(0);
+            }
+
+            let version = connection.query_row(
+                &format!("SELECT COALESCE(MAX(version), 0) FROM {table}"),
+                [],
+                |row| row.get(0),
+            )?;
+            Ok(version)
+        })
+        .await
+    }
+
+    fn migrations(&self) -> Vec<Migration> {
+        vec![Migration {
+            version: 1,
+            description: "initial schema",
+            sql: self.schema(),
+        }]
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        let prefix = self.table_prefix.clone();
+        let migration = migration.clone();
+        let version = migration.version;
+
+        self.run(move |connection| {
+            // An immediate transaction takes the write lock of the database file up front
+            let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            tx.execute_batch(&format!(
+                r#"
+                CREATE TABLE IF NOT EXISTS {prefix}schema_version (
+                    version INTEGER PRIMARY KEY,
+                    description TEXT NOT NULL,
+                    applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+                );
+                "#
+            ))?;
+
+            let applied: bool = tx.query_row(
+                &format!("SELECT EXISTS (SELECT 1 FROM {prefix}schema_version WHERE version = ?1)"),
+                params![migration.version],
+                |row| row.get(0),
+            )?;
+
+            if !applied {
+                tx.execute_batch(&migration.sql)?;
+                tx.execute(
+                    &format!(
+                        "INSERT INTO {prefix}schema_version (version, description) VALUES (?1, ?2)"
+                    ),
+                    params![migration.version, migration.description],
+                )?;
+            }
+
+            tx.commit()?;
+            Ok(())
+        })
+        .await?;
+
+        info!("Database schema migrated to version {}", version);
+        Ok(())
+    }
+
+    async fn insert_smtp_receive_logs(&self, logs: Vec<SmtpReceiveLog>) -> Result<u64> {
+        if logs.is_empty() {
+            debug!("Нет SMTP Receive логов для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                "smtp_receive_logs",
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "sequence_number",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "event",
+                    "data",
+                    "context",
+                    "sender",
+                    "recipient",
+                    "message_id",
+                    "subject",
+                    "size",
+                ],
+                "date_time, session_id, sequence_number",
+                logs,
+                |log| {
+                    vec![
+                        &log.date_time,
+                        &log.connector_id,
+                        &log.session_id,
+                        &log.sequence_number,
+                        &log.local_endpoint,
+                        &log.remote_endpoint,
+                        &log.event,
+                        &log.data,
+                        &log.context,
+                        &log.sender,
+                        &log.recipient,
+                        &log.message_id,
+                        &log.subject,
+                        &log.size,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} SMTP Receive logs", inserted_count);
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_send_logs(&self, logs: Vec<SmtpSendLog>) -> Result<u64> {
+        if logs.is_empty() {
+            debug!("Нет SMTP Send логов для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                "smtp_send_logs",
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "sequence_number",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "event",
+                    "data",
+                    "context",
+                    "proxy_session_id",
+                    "sender",
+                    "recipient",
+                    "message_id",
+                    "record_id",
+                ],
+                "date_time, session_id, sequence_number",
+                logs,
+                |log| {
+                    vec![
+                        &log.date_time,
+                        &log.connector_id,
+                        &log.session_id,
+                        &log.sequence_number,
+                        &log.local_endpoint,
+                        &log.remote_endpoint,
+                        &log.event,
+                        &log.data,
+                        &log.context,
+                        &log.proxy_session_id,
+                        &log.sender,
+                        &log.recipient,
+                        &log.message_id,
+                        &log.record_id,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} SMTP Send logs", inserted_count);
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_events(
+        &self,
+        direction: SmtpDirection,
+        events: Vec<SmtpEvent>,
+    ) -> Result<u64> {
+        if events.is_empty() {
+            debug!("Нет событий SMTP для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                &direction.table_name("events"),
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "sequence_number",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "event",
+                    "data",
+                    "context",
+                ],
+                "date_time, session_id, sequence_number",
+                events,
+                |event| {
+                    vec![
+                        &event.date_time,
+                        &event.connector_id,
+                        &event.session_id,
+                        &event.sequence_number,
+                        &event.local_endpoint,
+                        &event.remote_endpoint,
+                        &event.event,
+                        &event.data,
+                        &event.context,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} {:?} SMTP events", inserted_count, direction);
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_transactions(
+        &self,
+        direction: SmtpDirection,
+        transactions: Vec<SmtpTransaction>,
+    ) -> Result<u64> {
+        if transactions.is_empty() {
+            debug!("Нет транзакций SMTP для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                &direction.table_name("transactions"),
+                &[
+                    "date_time",
+                    "connector_id",
+                    "session_id",
+                    "transaction_index",
+                    "local_endpoint",
+                    "remote_endpoint",
+                    "sender",
+                    "recipient_count",
+                    "size",
+                    "message_id",
+                    "record_id",
+                    "response_code",
+                    "response",
+                ],
+                "date_time, session_id, transaction_index",
+                transactions,
+                |transaction| {
+                    vec![
+                        &transaction.date_time,
+                        &transaction.connector_id,
+                        &transaction.session_id,
+                        &transaction.transaction_index,
+                        &transaction.local_endpoint,
+                        &transaction.remote_endpoint,
+                        &transaction.sender,
+                        &transaction.recipient_count,
+                        &transaction.size,
+                        &transaction.message_id,
+                        &transaction.record_id,
+                        &transaction.response_code,
+                        &transaction.response,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!(
+            "Inserted {} {:?} SMTP transactions",
+            inserted_count, direction
+        );
+        Ok(inserted_count)
+    }
+
+    async fn insert_smtp_recipients(
+        &self,
+        direction: SmtpDirection,
+        recipients: Vec<SmtpRecipient>,
+    ) -> Result<u64> {
+        if recipients.is_empty() {
+            debug!("Нет получателей SMTP для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                &direction.table_name("recipients"),
+                &[
+                    "date_time",
+                    "session_id",
+                    "sequence_number",
+                    "transaction_index",
+                    "recipient",
+                    "response_code",
+                ],
+                "date_time, session_id, sequence_number",
+                recipients,
+                |recipient| {
+                    vec![
+                        &recipient.date_time,
+                        &recipient.session_id,
+                        &recipient.sequence_number,
+                        &recipient.transaction_index,
+                        &recipient.recipient,
+                        &recipient.response_code,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!(
+            "Inserted {} {:?} SMTP recipients",
+            inserted_count, direction
+        );
+        Ok(inserted_count)
+    }
+
+    async fn insert_message_tracking_logs(&self, logs: Vec<MessageTrackingLog>) -> Result<u64> {
+        if logs.is_empty() {
+            debug!("Нет Message Tracking логов для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                "message_tracking_logs",
+                &[
+                    "date_time",
+                    "client_ip",
+                    "client_hostname",
+                    "server_ip",
+                    "server_hostname",
+                    "source_context",
+                    "connector_id",
+                    "source",
+                    "event_id",
+                    "internal_message_id",
+                    "message_id",
+                    "network_message_id",
+                    "recipient_address",
+                    "recipient_status",
+                    "total_bytes",
+                    "recipient_count",
+                    "related_recipient_address",
+                    "reference",
+                    "message_subject",
+                    "sender_address",
+                    "return_path",
+                    "message_info",
+                    "directionality",
+                    "tenant_id",
+                    "original_client_ip",
+                    "original_server_ip",
+                    "custom_data",
+                    "transport_traffic_type",
+                    "log_id",
+                    "schema_version",
+                ],
+                "date_time, internal_message_id, recipient_address, event_id",
+                logs,
+                |log| {
+                    vec![
+                        &log.date_time,
+                        &log.client_ip,
+                        &log.client_hostname,
+                        &log.server_ip,
+                        &log.server_hostname,
+                        &log.source_context,
+                        &log.connector_id,
+                        &log.source,
+                        &log.event_id,
+                        &log.internal_message_id,
+                        &log.message_id,
+                        &log.network_message_id,
+                        &log.recipient_address,
+                        &log.recipient_status,
+                        &log.total_bytes,
+                        &log.recipient_count,
+                        &log.related_recipient_address,
+                        &log.reference,
+                        &log.message_subject,
+                        &log.sender_address,
+                        &log.return_path,
+                        &log.message_info,
+                        &log.directionality,
+                        &log.tenant_id,
+                        &log.original_client_ip,
+                        &log.original_server_ip,
+                        &log.custom_data,
+                        &log.transport_traffic_type,
+                        &log.log_id,
+                        &log.schema_version,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} Message Tracking logs", inserted_count);
+        Ok(inserted_count)
+    }
+
+    async fn insert_rejected_lines(&self, lines: Vec<RejectedLine>) -> Result<u64> {
+        if lines.is_empty() {
+            debug!("Нет отклоненных строк для вставки");
+            return Ok(0);
+        }
+
+        let inserted_count = self
+            .insert_rows(
+                "rejected_lines",
+                &["rejected_at", "file_path", "line_number", "line", "reason"],
+                "file_path, line_number",
+                lines,
+                |line| {
+                    vec![
+                        &line.rejected_at,
+                        &line.file_path,
+                        &line.line_number,
+                        &line.line,
+                        &line.reason,
+                    ]
+                },
+            )
+            .await?;
+
+        debug!("Inserted {} rejected lines", inserted_count);
+        Ok(inserted_count)
+    }
+
+    async fn get_ingested_file(&self, path: &str) -> Result<Option<IngestedFile>> {
+        let sql = format!(
+            "SELECT id, path, size, modified_at, content_hash, byte_offset, line_number,
+            log_type, record_count, ingested_at
+            FROM {prefix}ingested_files WHERE path = ?1",
+            prefix = self.table_prefix
+        );
+        let path = path.to_string();
+
+        self.run(move |connection| {
+            let file = connection
+                .query_row(&sql, params![path], |row| {
+                    Ok(IngestedFile {
+                        id: row.get("id")?,
+                        path: row.get("path")?,
+                        size: row.get("size")?,
+                        modified_at: row.get("modified_at")?,
+                        content_hash: row.get("content_hash")?,
+                        byte_offset: row.get("byte_offset")?,
+                        line_number: row.get("line_number")?,
+                        log_type: row.get("log_type")?,
+                        record_count: row.get("record_count")?,
+                        ingested_at: row.get("ingested_at")?,
+                    })
+                })
+                .optional()?;
+            Ok(file)
+        })
+        .await
+    }
+
+    async fn save_ingested_file(&self, file: IngestedFile) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO {prefix}ingested_files
+            (path, size, modified_at, content_hash, byte_offset, line_number, log_type,
+            record_count, ingested_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            ON CONFLICT (path) DO UPDATE SET
+            size = excluded.size, modified_at = excluded.modified_at,
+            content_hash = excluded.content_hash, byte_offset = excluded.byte_offset,
+            line_number = excluded.line_number, log_type = excluded.log_type,
+            record_count = excluded.record_count, ingested_at = excluded.ingested_at",
+            prefix = self.table_prefix
+        );
+
+        let path = self
+            .run(move |connection| {
+                connection.execute(
+                    &sql,
+                    params![
+                        file.path,
+                        file.size,
+                        file.modified_at,
+                        file.content_hash,
+                        file.byte_offset,
+                        file.line_number,
+                        file.log_type,
+                        file.record_count,
+                        file.ingested_at,
+                    ],
+                )?;
+                Ok(file.path)
+            })
+            .await?;
+
+        debug!("Saved ingestion journal entry for {}", path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::InsertStats;
+    use crate::models::RecordBatch;
+
+    async fn database() -> SqliteDatabase {
+        let db = SqliteDatabase::new(":memory:", Some("test_"))
+            .await
+            .unwrap();
+        db.migrate().await.unwrap();
+        db
+    }
+
+    fn event(sequence_number: i32) -> SmtpEvent {
+        SmtpEvent {
+            id: None,
+            date_time: "2024-01-15T14:00:00.123456Z".parse().unwrap(),
+            connector_id: "MAIL01\\Default Frontend MAIL01".into(),
+            session_id: "08DC1234ABCD5678".into(),
+            sequence_number,
+            local_endpoint: "10.0.0.1:25".into(),
+            remote_endpoint: "10.0.0.2:50000".into(),
+            event: "<".into(),
+            data: Some("EHLO client.example.com".into()),
+            context: None,
+        }
+    }
+
+    fn transaction(size: i64) -> SmtpTransaction {
+        SmtpTransaction {
+            id: None,
+            date_time: "2024-01-15T14:00:00.123456Z".parse().unwrap(),
+            connector_id: "MAIL01\\Default Frontend MAIL01".into(),
+            session_id: "08DC1234ABCD5678".into(),
+            transaction_index: 0,
+            local_endpoint: "10.0.0.1:25".into(),
+            remote_endpoint: "10.0.0.2:50000".into(),
+            sender: Some("sender@example.com".into()),
+            recipient_count: 1,
+            size: Some(size),
+            message_id: Some("<message@example.com>".into()),
+            record_id: None,
+            response_code: Some(250),
+            response: None,
+        }
+    }
+
+    fn batch() -> RecordBatch {
+        let mut batch = RecordBatch::default();
+        batch.smtp_receive.events = vec![event(0), event(1), event(1)];
+        batch.smtp_receive.transactions = vec![transaction(5_000_000_000)];
+        batch
+    }
+
+    #[tokio::test]
+    async fn skips_duplicates_within_and_across_batches() {
+        let db = database().await;
+
+        let first = db.insert_batch(batch()).await.unwrap();
+        assert_eq!(
+            first,
+            InsertStats {
+                inserted: 3,
+                duplicates: 1
+            }
+        );
+        let second = db.insert_batch(batch()).await.unwrap();
+        assert_eq!(
+            second,
+            InsertStats {
+                inserted: 0,
+                duplicates: 4
+            }
+        );
+
+        let (events, size): (i64, i64) = db
+            .run(|connection| {
+                Ok(connection.query_row(
+                    "SELECT (SELECT COUNT(*) FROM test_smtp_receive_events), size
+                    FROM test_smtp_receive_transactions",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?)
+            })
+            .await
+            .unwrap();
+        assert_eq!(events, 2);
+        assert_eq!(size, 5_000_000_000);
+    }
+}
@@ -0,0 +1,275 @@
+use crate::database::Database;
+use crate::ingest::{self, Ingestion};
+use crate::parser::ParseOptions;
+use chrono::Utc;
+use color_eyre::eyre::Result;
+use log::{debug, info, warn};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use walkdir::WalkDir;
+
+/// Files not written for this long are closed until they are written again
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Interval between checks for idle files
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Follower of a directory with log files that are still being written
+///
+/// This struct is used to tail Exchange logs: every file that is written to is
+/// kept open, and the lines appended to it are parsed and inserted as they arrive.
+struct Follower<'a> {
+    db: &'a dyn Database,
+    options: ParseOptions,
+    batch_size: usize,
+    files: HashMap<PathBuf, Ingestion>,
+}
+
+impl Follower<'_> {
+    /// Reads the lines appended to a file, opening the file if needed
+    ///
+    /// Returns `true` if the file was not open before.
+    async fn update(&mut self, path: &Path, force: bool) -> Result<bool> {
+        if let Some(ingestion) = self.files.get_mut(path)
+            && !ingestion.refresh().await?
+        {
+            info!("File {} was replaced, reading it again", path.display());
+            self.close(path, true).await?;
+        }
+
+        let opened = !self.files.contains_key(path);
+        if opened {
+            let Some(ingestion) = Ingestion::open(self.db, path, self.options, force).await? else {
+                return Ok(false);
+            };
+            self.files.insert(path.to_path_buf(), ingestion);
+        }
+
+        let ingestion = self.files.get_mut(path).expect("file was just opened");
+        match read_and_save(ingestion, self.db, self.batch_size).await {
+            Ok(record_count) if record_count > 0 => {
+                debug!("Read {} records from {}", record_count, path.display());
+            }
+            Ok(_) => {}
+            Err(e) => {
+                // The file is opened again from the journal on its next change
+                self.files.remove(path);
+                return Err(e);
+            }
+        }
+
+        if is_idle(&self.files[path]) {
+            self.close(path, false).await?;
+        }
+        Ok(opened)
+    }
+
+    /// Reads a file found in the directory when following starts
+    ///
+    /// A file that Exchange has already rotated away from is read to the end and closed.
+    async fn open_existing(&mut self, path: &Path, force: bool) -> Result<()> {
+        self.update(path, force).await?;
+        if ingest::is_rotated(path)? {
+            self.close(path, true).await?;
+        }
+        Ok(())
+    }
+
+    /// Closes a file and saves the position reached in the journal
+    ///
+    /// With `finalize` the file will not be written to any more (it was rotated,
+    /// removed or replaced), so the records of SMTP sessions still in progress are
+    /// inserted as they are. Otherwise these sessions are read again from the
+    /// journal position when the file is opened next time.
+    async fn close(&mut self, path: &Path, finalize: bool) -> Result<()> {
+        let Some(mut ingestion) = self.files.remove(path) else {
+            return Ok(());
+        };
+
+        if finalize {
+            ingestion.close();
+        }
+        read_and_save(&mut ingestion, self.db, self.batch_size).await?;
+        ingestion.report();
+        let stats = ingestion.insert_stats();
+        debug!(
+            "Closed {} ({} rows inserted, {} duplicates skipped)",
+            path.display(),
+            stats.inserted,
+            stats.duplicates
+        );
+        Ok(())
+    }
+
+    /// Closes the older files of the same type in the directory of a newly opened log
+    ///
+    /// Exchange starts a new file every hour or when the current one reaches its
+    /// size limit, after which the previous file is no longer written to.
+    async fn rotate(&mut self, path: &Path) -> Result<()> {
+        let Some((log_type, modified_at)) = self
+            .files
+            .get(path)
+            .map(|ingestion| (ingestion.log_type(), ingestion.modified_at()))
+        else {
+            return Ok(());
+        };
+
+        let rotated: Vec<PathBuf> = self
+            .files
+            .iter()
+            .filter(|(other, ingestion)| {
+                other.as_path() != path
+                    && other.parent() == path.parent()
+                    && ingestion.log_type() == log_type
+                    && ingestion.modified_at() <= modified_at
+            })
+            .map(|(other, _)| other.clone())
+            .collect();
+
+        for other in rotated {
+            info!("Log rotated from {} to {}", other.display(), path.display());
+            self.close(&other, true).await?;
+        }
+        Ok(())
+    }
+
+    /// Closes the files that have not been written for a while
+    ///
+    /// An idle file may still be written to later, so its open SMTP sessions are kept
+    /// for the next time it is opened.
+    async fn close_idle(&mut self) -> Result<()> {
+        let idle: Vec<PathBuf> = self
+            .files
+            .iter()
+            .filter(|(_, ingestion)| is_idle(ingestion))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in idle {
+            self.close(&path, false).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_event(&mut self, event: Event) -> Result<()> {
+        match event.kind {
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => {
+                for path in &event.paths {
+                    self.close(path, true).await?;
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                if let [from, to] = event.paths.as_slice() {
+                    self.close(from, true).await?;
+                    self.update(to, false).await?;
+                }
+            }
+            EventKind::Create(_) | EventKind::Modify(_) => {
+                for path in event.paths.iter().filter(|path| path.is_file()) {
+                    if self.update(path, false).await? {
+                        self.rotate(path).await?;
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Reads the records available in a file and saves the checkpoint in the journal
+///
+/// The checkpoint does not pass the first line of an SMTP session that is still
+/// in progress, so after a restart the session is read again from its start.
+async fn read_and_save(
+    ingestion: &mut Ingestion,
+    db: &dyn Database,
+    batch_size: usize,
+) -> Result<u64> {
+    let record_count = ingestion.read_available(db, batch_size).await?;
+    ingestion.save(db).await?;
+    Ok(record_count)
+}
+
+/// Checks whether a file has not been written for longer than `IDLE_TIMEOUT`
+fn is_idle(ingestion: &Ingestion) -> bool {
+    (Utc::now() - ingestion.modified_at())
+        .to_std()
+        .is_ok_and(|age| age > IDLE_TIMEOUT)
+}
+
+/// Watches a directory and ingests the lines appended to its log files until Ctrl+C
+///
+/// Existing files are read first (resuming from the ingestion journal), then
+/// the directory is watched for new and growing files. Trailing lines without a
+/// line feed are held back until they are complete.
+pub async fn run(
+    db: &dyn Database,
+    logs_dir: &Path,
+    options: ParseOptions,
+    batch_size: usize,
+    force: bool,
+) -> Result<()> {
+    let (sender, mut events) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = sender.send(event);
+    })?;
+    watcher.watch(logs_dir, RecursiveMode::Recursive)?;
+
+    let mut follower = Follower {
+        db,
+        options,
+        batch_size,
+        files: HashMap::new(),
+    };
+
+    let existing = WalkDir::new(logs_dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file());
+    for entry in existing {
+        if let Err(e) = follower.open_existing(entry.path(), force).await {
+            warn!("Error processing file {}: {}", entry.path().display(), e);
+        }
+    }
+
+    info!("Watching {} for new log lines", logs_dir.display());
+
+    let mut idle_check = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(Ok(event)) => {
+                    if let Err(e) = follower.handle_event(event).await {
+                        warn!("Error processing file change: {}", e);
+                    }
+                }
+                Some(Err(e)) => warn!("Error watching {}: {}", logs_dir.display(), e),
+                None => break,
+            },
+            _ = idle_check.tick() => {
+                if let Err(e) = follower.close_idle().await {
+                    warn!("Error closing idle files: {}", e);
+                }
+            }
+            _ = &mut shutdown => {
+                info!("Stopping, closing {} open files", follower.files.len());
+                break;
+            }
+        }
+    }
+
+    // The files are still being written: the sessions in progress are read again on the next start
+    let open: Vec<PathBuf> = follower.files.keys().cloned().collect();
+    for path in open {
+        follower.close(&path, false).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,448 @@
+use crate::database::{Database, InsertStats};
+use crate::journal::{self, FileState, IngestPlan};
+use crate::models::{IngestedFile, LogRecord, LogType, RecordBatch};
+use crate::parser::{LogParser, ParseOptions, RecordReader};
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::Result;
+use futures::TryStreamExt;
+use lazy_static::lazy_static;
+use log::info;
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+
+lazy_static! {
+    /// Name of an Exchange log file: prefix, date and hour, sequence number
+    static ref LOG_FILE_NAME_REGEX: Regex = Regex::new(r"(?i)^([a-z]+)(\d+)-(\d+)\.log$").unwrap();
+}
+
+/// Log file being ingested into the database
+///
+/// This struct is used to read the records of a file in bounded batches and to keep
+/// the entry of the file in the ingestion journal up to date.
+///
+/// ### Examples
+///
+/// ```
+/// if let Some(mut ingestion) = Ingestion::open(db, path, options, false).await? {
+///     ingestion.read_available(db, 5000).await?;
+///     ingestion.save(db).await?;
+/// }
+/// ```
+pub struct Ingestion {
+    path: PathBuf,
+    journal_path: String,
+    state: FileState,
+    records: RecordReader,
+    record_count: u64,
+    stats: InsertStats,
+}
+
+impl Ingestion {
+    /// Opens a log file, resuming from the position saved in the ingestion journal
+    ///
+    /// Returns `None` if the file has not changed since it was ingested. With `force`
+    /// the journal is ignored and the file is read from the start.
+    pub async fn open(
+        db: &dyn Database,
+        path: &Path,
+        options: ParseOptions,
+        force: bool,
+    ) -> Result<Option<Self>> {
+        let state = FileState::read(path).await?;
+        let journal_path = journal_path(path)?;
+        let entry = if force {
+            None
+        } else {
+            db.get_ingested_file(&journal_path).await?
+        };
+
+        let (resume, record_count) = match journal::plan(path, &state, entry.as_ref()).await? {
+            IngestPlan::Skip => {
+                info!("Skipping unchanged file {}", path.display());
+                return Ok(None);
+            }
+            IngestPlan::Resume(resume) => {
+                info!(
+                    "Resuming {} from byte {} (line {}), {} bytes already read",
+                    path.display(),
+                    resume.position.offset,
+                    resume.position.line,
+                    resume.delivered
+                );
+                let record_count = entry.map_or(0, |entry| entry.record_count as u64);
+                (Some(resume), record_count)
+            }
+            IngestPlan::Full => (None, 0),
+        };
+
+        let records = LogParser::open_log_file(path, options, resume).await?;
+        Ok(Some(Ingestion {
+            path: path.to_path_buf(),
+            journal_path,
+            state,
+            records,
+            record_count,
+            stats: InsertStats::default(),
+        }))
+    }
+
+    pub fn log_type(&self) -> LogType {
+        self.records.log_type()
+    }
+
+    /// Returns the modification time of the file when it was last looked at
+    pub fn modified_at(&self) -> DateTime<Utc> {
+        self.state.modified_at
+    }
+
+    /// Returns the number of rows inserted and skipped as duplicates since the file was opened
+    pub fn insert_stats(&self) -> InsertStats {
+        self.stats
+    }
+
+    /// Looks at the size and modification time of the file again before reading appended lines
+    ///
+    /// Returns `false` if the file became shorter than the part already read, i.e.
+    /// it was replaced by another file with the same name.
+    pub async fn refresh(&mut self) -> Result<bool> {
+        self.state = FileState::read(&self.path).await?;
+        Ok(self.state.size >= self.records.position().offset)
+    }
+
+    /// Reads the records available and inserts them into the database in batches
+    ///
+    /// Returns the number of records read, not counting rejected lines.
+    pub async fn read_available(&mut self, db: &dyn Database, batch_size: usize) -> Result<u64> {
+        let mut batch = RecordBatch::for_file(&self.journal_path);
+        let mut record_count = 0;
+
+        let mut records = pin!(self.records.records());
+        while let Some(record) = records.try_next().await? {
+            if !matches!(record, LogRecord::RejectedLine(_)) {
+                record_count += 1;
+            }
+            batch.push(record);
+            if batch.len() >= batch_size {
+                let full = std::mem::replace(&mut batch, RecordBatch::for_file(&self.journal_path));
+                self.stats += db.insert_batch(full).await?;
+            }
+        }
+
+        if !batch.is_empty() {
+            self.stats += db.insert_batch(batch).await?;
+        }
+
+        self.record_count += record_count;
+        Ok(record_count)
+    }
+
+    /// Ends the file: the records of SMTP sessions still in progress are read by
+    /// the next `read_available`
+    ///
+    /// Only for files that will not be written to any more: a session continued
+    /// after `close` would be stored incomplete.
+    pub fn close(&mut self) {
+        self.records.close();
+    }
+
+    /// Inserts the records of SMTP sessions still in progress if the log was rotated
+    ///
+    /// Sessions not finished in a file that Exchange no longer writes to will not
+    /// be continued in it; in other files they are read again by the next run.
+    /// Returns the number of records read.
+    pub async fn finish_if_rotated(&mut self, db: &dyn Database, batch_size: usize) -> Result<u64> {
+        if !is_rotated(&self.path)? {
+            return Ok(0);
+        }
+        self.close();
+        self.read_available(db, batch_size).await
+    }
+
+    /// Logs the encoding of the file and the number of lines that could not be parsed
+    pub fn report(&self) {
+        self.records.report();
+    }
+
+    /// Saves the position reached in the ingestion journal
+    ///
+    /// The position saved is the start of the oldest SMTP session still in
+    /// progress, so that the next run reads the whole session again. The size
+    /// saved is the part of the file already read: the records of the lines
+    /// before it are not delivered again.
+    pub async fn save(&self, db: &dyn Database) -> Result<()> {
+        let position = self.records.checkpoint();
+        let size = self.records.delivered();
+        db.save_ingested_file(IngestedFile {
+            id: None,
+            path: self.journal_path.clone(),
+            size: size as i64,
+            modified_at: self.state.modified_at,
+            content_hash: journal::content_hash(&self.path, size).await?,
+            byte_offset: position.offset as i64,
+            line_number: position.line as i64,
+            log_type: format!("{:?}", self.log_type()),
+            record_count: self.record_count as i64,
+            ingested_at: Utc::now(),
+        })
+        .await
+    }
+}
+
+/// Checks whether Exchange has started a newer file of the same log next to this one
+///
+/// Exchange starts a new file every hour or when the current one reaches its size
+/// limit (`RECV2024011514-1.LOG`, `RECV2024011514-2.LOG`, `RECV2024011515-1.LOG`),
+/// after which the older file is no longer written to. Files named otherwise are
+/// never considered rotated.
+pub fn is_rotated(path: &Path) -> Result<bool> {
+    let Some((prefix, key)) = log_file_key(path) else {
+        return Ok(false);
+    };
+    let Some(directory) = path.parent() else {
+        return Ok(false);
+    };
+
+    for entry in std::fs::read_dir(directory)? {
+        if let Some((other_prefix, other_key)) = log_file_key(&entry?.path())
+            && other_prefix.eq_ignore_ascii_case(&prefix)
+            && other_key > key
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Splits the name of an Exchange log file into its prefix and its (date, sequence number)
+fn log_file_key(path: &Path) -> Option<(String, (u64, u64))> {
+    let name = path.file_name()?.to_str()?;
+    let captures = LOG_FILE_NAME_REGEX.captures(name)?;
+    let date = captures[2].parse().ok()?;
+    let sequence = captures[3].parse().ok()?;
+    Some((captures[1].to_string(), (date, sequence)))
+}
+
+/// Returns the path under which a file is stored in the ingestion journal
+///
+/// The path is made absolute and symbolic links are resolved, so that the same
+/// file is found whatever directory or link it is reached through.
+fn journal_path(path: &Path) -> Result<String> {
+    Ok(std::fs::canonicalize(path)?.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::sqlite::SqliteDatabase;
+    use crate::parser::ErrorPolicy;
+
+    const HEADER: &str = "#Software: Microsoft Exchange Server\r\n\
+        #Version: 15.0.0.0\r\n\
+        #Log-type: SMTP Receive Protocol Log\r\n\
+        #Date: 2024-01-15T14:00:00.000Z\r\n\
+        #Fields: date-time,connector-id,session-id,sequence-number,local-endpoint,remote-endpoint,event,data,context\r\n";
+
+    fn line(sequence_number: i32, event: &str, data: &str) -> String {
+        format!(
+            "2024-01-15T14:00:{:02}.000Z,MAIL01\\Default,08DC1234ABCD5678,{},10.0.0.1:25,10.0.0.2:50000,{},{},\r\n",
+            sequence_number, sequence_number, event, data
+        )
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("elp-ingest-test-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Reads the file like a run of the program, in batches of two records
+    async fn ingest(db: &dyn Database, path: &Path) -> InsertStats {
+        let mut ingestion = Ingestion::open(db, path, options(), false)
+            .await
+            .unwrap()
+            .expect("the file has changed");
+        ingestion.read_available(db, 2).await.unwrap();
+        ingestion.finish_if_rotated(db, 2).await.unwrap();
+        ingestion.save(db).await.unwrap();
+        ingestion.insert_stats()
+    }
+
+    fn options() -> ParseOptions {
+        ParseOptions {
+            encoding: None,
+            on_error: ErrorPolicy::Fail,
+            follow: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn completes_session_cut_off_at_end_of_file() {
+        let dir = temp_dir("resume");
+        let path = dir.join("RECV2024011514-1.LOG");
+        let database = dir.join("logs.db");
+        let db = SqliteDatabase::new(database.to_str().unwrap(), None)
+            .await
+            .unwrap();
+        db.migrate().await.unwrap();
+
+        let lines = [
+            line(0, "+", ""),
+            line(1, "<", "EHLO client.example.com"),
+            line(2, ">", "250 mail01.example.com Hello"),
+            line(3, "<", "MAIL FROM:<sender@example.com> SIZE=2048"),
+            line(4, ">", "250 2.1.0 Sender OK"),
+            line(5, "<", "RCPT TO:<first@example.com>"),
+            line(6, ">", "250 2.1.5 Recipient OK"),
+            line(7, "<", "DATA"),
+            line(8, ">", "354 Start mail input"),
+            line(9, ">", "250 2.6.0 <id@example.com> Queued mail"),
+            line(10, "-", ""),
+        ];
+        std::fs::write(&path, format!("{}{}", HEADER, lines[..6].concat())).unwrap();
+
+        // The session is still open: only its events are stored
+        let first = ingest(&db, &path).await;
+        assert_eq!(
+            first,
+            InsertStats {
+                inserted: 6,
+                duplicates: 0
+            }
+        );
+
+        std::fs::write(&path, format!("{}{}", HEADER, lines.concat())).unwrap();
+
+        // The first lines are read again to rebuild the session, but not stored again
+        let second = ingest(&db, &path).await;
+        assert_eq!(
+            second,
+            InsertStats {
+                inserted: 5 + 3,
+                duplicates: 0
+            }
+        );
+
+        let connection = rusqlite::Connection::open(&database).unwrap();
+        let count = |table: &str| -> i64 {
+            connection
+                .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                    row.get(0)
+                })
+                .unwrap()
+        };
+        assert_eq!(count("smtp_receive_events"), 11);
+        assert_eq!(count("smtp_receive_logs"), 1);
+        assert_eq!(count("smtp_receive_transactions"), 1);
+        assert_eq!(count("smtp_receive_recipients"), 1);
+
+        let session: (String, String, String, i64) = connection
+            .query_row(
+                "SELECT sender, recipient, message_id, size FROM smtp_receive_logs",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            session,
+            (
+                "sender@example.com".to_string(),
+                "first@example.com".to_string(),
+                "id@example.com".to_string(),
+                2048
+            )
+        );
+        let (response_code, recipient_code): (i32, i32) = connection
+            .query_row(
+                "SELECT t.response_code, r.response_code \
+                 FROM smtp_receive_transactions t JOIN smtp_receive_recipients r \
+                 ON r.session_id = t.session_id AND r.transaction_index = t.transaction_index",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((response_code, recipient_code), (250, 250));
+
+        // The session is over, the journal points to the end of the file
+        let size = std::fs::metadata(&path).unwrap().len() as i64;
+        let entry = db
+            .get_ingested_file(&journal_path(&path).unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((entry.byte_offset, entry.size), (size, size));
+        assert_eq!(entry.record_count, 14);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn finishes_open_session_once_log_is_rotated() {
+        let dir = temp_dir("rotated");
+        let path = dir.join("RECV2024011514-1.LOG");
+        let database = dir.join("logs.db");
+        let db = SqliteDatabase::new(database.to_str().unwrap(), None)
+            .await
+            .unwrap();
+        db.migrate().await.unwrap();
+
+        let lines = [
+            line(0, "+", ""),
+            line(1, "<", "MAIL FROM:<sender@example.com>"),
+            line(2, ">", "250 2.1.0 Sender OK"),
+        ];
+        std::fs::write(&path, format!("{}{}", HEADER, lines.concat())).unwrap();
+        ingest(&db, &path).await;
+
+        // The file has not changed, but its session is still open
+        std::fs::write(dir.join("RECV2024011514-2.LOG"), HEADER).unwrap();
+        let stats = ingest(&db, &path).await;
+        assert_eq!(
+            stats,
+            InsertStats {
+                inserted: 2,
+                duplicates: 0
+            }
+        );
+
+        let sender: String = rusqlite::Connection::open(&database)
+            .unwrap()
+            .query_row("SELECT sender FROM smtp_receive_logs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(sender, "sender@example.com");
+        // Nothing is left open, the file is skipped from now on
+        assert!(
+            Ingestion::open(&db, &path, options(), false)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn detects_newer_file_of_the_same_log() {
+        let dir = temp_dir("rotation");
+        let touch = |name: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, "").unwrap();
+            path
+        };
+
+        let first = touch("RECV2024011514-1.LOG");
+        touch("SEND2024011515-1.LOG");
+        assert!(!is_rotated(&first).unwrap());
+
+        let second = touch("RECV2024011514-2.LOG");
+        assert!(is_rotated(&first).unwrap());
+        assert!(!is_rotated(&second).unwrap());
+
+        touch("recv2024011515-1.log");
+        assert!(is_rotated(&second).unwrap());
+        assert!(!is_rotated(&touch("custom.log")).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+use crate::models::IngestedFile;
+use crate::parser::{FilePosition, ResumePoint};
+use chrono::{DateTime, SubsecRound, Utc};
+use color_eyre::eyre::Result;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// Number of bytes at the start of a file covered by the content hash
+const HASHED_PREFIX_LENGTH: u64 = 64 * 1024;
+
+/// Size and modification time of a log file on disk
+///
+/// This struct is used to compare a file with its entry in the ingestion journal.
+///
+/// ### Examples
+///
+/// ```
+/// let state = FileState::read(Path::new("/logs/MSGTRK2024010100-1.LOG")).await?;
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct FileState {
+    pub size: u64,
+    pub modified_at: DateTime<Utc>,
+}
+
+impl FileState {
+    pub async fn read(path: &Path) -> Result<Self> {
+        let metadata = tokio::fs::metadata(path).await?;
+        Ok(FileState {
+            size: metadata.len(),
+            // Databases keep timestamps with microsecond precision
+            modified_at: DateTime::<Utc>::from(metadata.modified()?).trunc_subsecs(6),
+        })
+    }
+}
+
+/// What to do with a log file according to the ingestion journal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IngestPlan {
+    /// The file has not changed since it was ingested
+    Skip,
+    /// The file has grown, only the lines after the part already delivered are new
+    Resume(ResumePoint),
+    /// The file is new or was rewritten and has to be read from the start
+    Full,
+}
+
+/// Computes the SHA-256 hash of the start of a file
+///
+/// Only the first `size` bytes, and at most 64 KiB, are hashed. A file that has
+/// only been appended to keeps the hash of its old prefix.
+pub async fn content_hash(path: &Path, size: u64) -> Result<String> {
+    let file = File::open(path).await?;
+    let mut prefix = Vec::new();
+    file.take(size.min(HASHED_PREFIX_LENGTH))
+        .read_to_end(&mut prefix)
+        .await?;
+    Ok(format!("{:x}", Sha256::digest(&prefix)))
+}
+
+/// Checks whether a journal entry describes the file on disk
+///
+/// The file of the entry must not be longer than the file now, and it must have
+/// the same beginning. Otherwise the file was replaced by a new one.
+pub async fn matches(path: &Path, size: u64, entry: &IngestedFile) -> Result<bool> {
+    let stored_size = entry.size as u64;
+    Ok(size >= stored_size && content_hash(path, stored_size).await? == entry.content_hash)
+}
+
+/// Decides whether a file has to be read and from which position
+pub async fn plan(
+    path: &Path,
+    state: &FileState,
+    entry: Option<&IngestedFile>,
+) -> Result<IngestPlan> {
+    let Some(entry) = entry else {
+        return Ok(IngestPlan::Full);
+    };
+
+    // A file with SMTP sessions still open is read again even if unchanged, so
+    // that the sessions are finished once the log has been rotated
+    let stored_size = entry.size as u64;
+    if state.size == stored_size
+        && state.modified_at == entry.modified_at
+        && entry.byte_offset == entry.size
+    {
+        return Ok(IngestPlan::Skip);
+    }
+
+    // A file that shrank or whose beginning changed was replaced by a new one
+    if !matches(path, state.size, entry).await? {
+        return Ok(IngestPlan::Full);
+    }
+
+    Ok(IngestPlan::Resume(ResumePoint {
+        position: FilePosition {
+            offset: entry.byte_offset as u64,
+            line: entry.line_number as usize,
+        },
+        delivered: stored_size,
+    }))
+}
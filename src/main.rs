@@ -1,41 +1,29 @@
 mod config;
 mod database;
+mod follow;
+mod ingest;
+mod journal;
 mod models;
 mod parser;
+mod sink;
+mod tls;
 
-use clap::Parser;
 use color_eyre::eyre::Result;
 use colored::Colorize;
-use config::Args;
+use config::{Command, ConfigCommand};
+use database::{Database, InsertStats};
 use futures::stream::{StreamExt, TryStreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
+use ingest::Ingestion;
 use log::{error, info};
-use parser::{LogParser, ParsedLog};
+use models::LogType;
+use parser::ParseOptions;
+use sink::fanout::{FanOut, SinkStats};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use walkdir::WalkDir;
 
-/// Макрос для обработки и вставки логов в базу данных
-macro_rules! process_logs {
-    ($db:expr, $logs:expr, $path:expr, $success_counter:expr, $error_counter:expr, $log_type:expr, $insert_method:ident) => {
-        if !$logs.is_empty() {
-            if let Err(e) = $db.$insert_method($logs).await {
-                error!(
-                    "Error inserting {} logs for {}: {}",
-                    $log_type,
-                    $path.display(),
-                    e
-                );
-                let mut count = $error_counter.lock().unwrap();
-                *count += 1;
-            } else {
-                let mut count = $success_counter.lock().unwrap();
-                *count += 1;
-            }
-        }
-    };
-}
-
 /// Макрос для форматирования текста цветом
 macro_rules! fmt {
     (success => $text:expr) => {
@@ -61,6 +49,76 @@ macro_rules! fmt {
     };
 }
 
+/// Читает лог-файл потоком и записывает записи в базу данных пакетами
+///
+/// Файлы, не изменившиеся с прошлого запуска, пропускаются, а дописанные файлы
+/// дочитываются с сохраненной в журнале позиции (если не указан `force`).
+/// Возвращает тип лога и число вставленных строк и дубликатов или `None`, если файл пропущен.
+async fn process_file(
+    db: &dyn Database,
+    path: &Path,
+    batch_size: usize,
+    options: ParseOptions,
+    force: bool,
+) -> Result<Option<(LogType, InsertStats)>> {
+    let Some(mut ingestion) = Ingestion::open(db, path, options, force).await? else {
+        return Ok(None);
+    };
+    let log_type = ingestion.log_type();
+    let mut record_count = ingestion.read_available(db, batch_size).await?;
+    record_count += ingestion.finish_if_rotated(db, batch_size).await?;
+    ingestion.report();
+    ingestion.save(db).await?;
+
+    let stats = ingestion.insert_stats();
+    info!(
+        "Processed {} {:?} records from {} ({} rows inserted, {} duplicates skipped)",
+        record_count,
+        log_type,
+        path.display(),
+        stats.inserted,
+        stats.duplicates
+    );
+    Ok(Some((log_type, stats)))
+}
+
+/// Выполняет подкоманду `migrate`: применяет недостающие миграции схемы БД
+/// каждого выхода или, с `--status`, только выводит текущую и последнюю версии схемы
+async fn run_migrate(db: &FanOut, status: bool) -> Result<()> {
+    for (name, sink) in db.sinks() {
+        let latest = database::migrations::latest_version(&sink.migrations());
+
+        if status {
+            let current = sink.schema_version().await?;
+            println!(
+                "{} {} {} {}",
+                fmt!(label => format!("Версия схемы БД ({name}):")),
+                fmt!(num => current),
+                fmt!(label => "последняя:"),
+                fmt!(num => latest)
+            );
+            if current < latest {
+                println!(
+                    "{}",
+                    fmt!(highlight => "Есть непримененные миграции, выполните migrate")
+                );
+            }
+            continue;
+        }
+
+        let applied = sink.migrate().await?;
+        println!(
+            "{} {} {} {} {}",
+            fmt!(success => "✓"),
+            fmt!(success => format!("Применено миграций ({name}):")),
+            fmt!(num => applied),
+            fmt!(success => "версия схемы БД:"),
+            fmt!(num => latest)
+        );
+    }
+    Ok(())
+}
+
 /// Выводит статистику обработки логов в консоль
 fn print_statistics(
     total_files: u64,
@@ -68,6 +126,7 @@ fn print_statistics(
     smtp_receive: usize,
     smtp_send: usize,
     message_tracking: usize,
+    skipped: usize,
     errors: usize,
 ) {
     let files_per_second = total_files as f64 / duration.as_secs_f64();
@@ -104,6 +163,11 @@ fn print_statistics(
         fmt!(label => "Message Tracking:"),
         fmt!(num => message_tracking)
     );
+    println!(
+        "  {} {}",
+        fmt!(label => "Без изменений (пропущено):"),
+        fmt!(num => skipped)
+    );
 
     if errors > 0 {
         println!(
@@ -116,6 +180,42 @@ fn print_statistics(
     }
 }
 
+/// Выводит в консоль число вставленных в БД строк и пропущенных дубликатов
+fn print_insert_statistics(rows: InsertStats) {
+    println!(
+        "  {} {}",
+        fmt!(label => "Вставлено строк:"),
+        fmt!(num => rows.inserted)
+    );
+    println!(
+        "  {} {}",
+        fmt!(label => "Дубликатов пропущено:"),
+        fmt!(num => rows.duplicates)
+    );
+}
+
+/// Выводит в консоль число строк и ошибок каждого выхода
+fn print_sink_statistics(sinks: &[(String, SinkStats)]) {
+    println!("\n{} {}", "📤".bold(), fmt!(info => "Выходы:"));
+    for (name, stats) in sinks {
+        let errors = if stats.errors > 0 {
+            fmt!(error => stats.errors.to_string())
+        } else {
+            fmt!(ok => "0")
+        };
+        println!(
+            "  {} {} {} {} {} {} {}",
+            fmt!(label => format!("{name}:")),
+            fmt!(label => "вставлено"),
+            fmt!(num => stats.inserted),
+            fmt!(label => "дубликатов"),
+            fmt!(num => stats.duplicates),
+            fmt!(label => "ошибок"),
+            errors
+        );
+    }
+}
+
 /// Main function
 ///
 /// This function is the entry point of the program.
@@ -125,22 +225,30 @@ async fn main() -> Result<()> {
     env_logger::init();
     color_eyre::install()?;
 
-    let args = Args::parse();
+    let (args, settings) = config::load()?;
     let start_time = Instant::now();
 
-    // Initialize database connection
-    let db = Arc::new(
-        database::create_database(
-            args.db_type,
-            &args.db_host,
-            args.db_port,
-            &args.db_user,
-            &args.db_password,
-            &args.db_name,
-            args.table_prefix.as_deref(),
-        )
-        .await?,
-    );
+    if let Some(Command::Config {
+        command: ConfigCommand::Show,
+    }) = args.command
+    {
+        for setting in settings {
+            println!("{}", setting);
+        }
+        return Ok(());
+    }
+
+    // Initialize database connection and the other outputs chosen with --output.
+    // Every output connects when it is created, so that a wrong address, password
+    // or certificate is reported before any file is read
+    let db = Arc::new(sink::create_sinks(&args).await?);
+
+    if let Some(Command::Migrate { status }) = args.command {
+        return run_migrate(&db, status).await;
+    }
+
+    // Приводим схему БД к версии, ожидаемой программой
+    db.migrate().await?;
 
     info!(
         "Starting to process log files in {} with {} concurrent tasks",
@@ -148,6 +256,18 @@ async fn main() -> Result<()> {
         args.concurrent_files
     );
 
+    let batch_size = args.batch_size.max(1);
+    let options = ParseOptions {
+        encoding: args.encoding,
+        on_error: args.on_error,
+        follow: args.follow,
+    };
+    let force = args.force;
+
+    if args.follow {
+        return follow::run(&*db, &args.logs_dir, options, batch_size, force).await;
+    }
+
     // Собираем список файлов для обработки
     let files_to_process: Vec<_> = WalkDir::new(&args.logs_dir)
         .into_iter()
@@ -168,7 +288,9 @@ async fn main() -> Result<()> {
     let smtp_receive_count = Arc::new(Mutex::new(0));
     let smtp_send_count = Arc::new(Mutex::new(0));
     let message_tracking_count = Arc::new(Mutex::new(0));
+    let skipped_count = Arc::new(Mutex::new(0));
     let error_count = Arc::new(Mutex::new(0));
+    let insert_stats = Arc::new(Mutex::new(InsertStats::default()));
 
     // Обрабатываем файлы параллельно
     futures::stream::iter(files_to_process)
@@ -178,48 +300,31 @@ async fn main() -> Result<()> {
             let smtp_receive_count_clone = Arc::clone(&smtp_receive_count);
             let smtp_send_count_clone = Arc::clone(&smtp_send_count);
             let message_tracking_count_clone = Arc::clone(&message_tracking_count);
+            let skipped_count_clone = Arc::clone(&skipped_count);
             let error_count_clone = Arc::clone(&error_count);
+            let insert_stats_clone = Arc::clone(&insert_stats);
 
             async move {
                 let path = entry.path();
                 pb_clone.set_message(format!("Processing {}", path.display()));
 
-                match LogParser::parse_log_file(path).await {
-                    Ok(parsed_log) => match parsed_log {
-                        ParsedLog::SmtpReceive(logs) => {
-                            process_logs!(
-                                db_clone,
-                                logs,
-                                path,
-                                smtp_receive_count_clone,
-                                error_count_clone,
-                                "SMTP Receive",
-                                insert_smtp_receive_logs
-                            );
-                        }
-                        ParsedLog::SmtpSend(logs) => {
-                            process_logs!(
-                                db_clone,
-                                logs,
-                                path,
-                                smtp_send_count_clone,
-                                error_count_clone,
-                                "SMTP Send",
-                                insert_smtp_send_logs
-                            );
-                        }
-                        ParsedLog::MessageTracking(logs) => {
-                            process_logs!(
-                                db_clone,
-                                logs,
-                                path,
-                                message_tracking_count_clone,
-                                error_count_clone,
-                                "Message Tracking",
-                                insert_message_tracking_logs
-                            );
-                        }
-                    },
+                match process_file(&*db_clone, path, batch_size, options, force).await {
+                    Ok(None) => {
+                        let mut count = skipped_count_clone.lock().unwrap();
+                        *count += 1;
+                    }
+                    Ok(Some((log_type, stats))) => {
+                        *insert_stats_clone.lock().unwrap() += stats;
+
+                        let counter = match log_type {
+                            LogType::SmtpReceive => &smtp_receive_count_clone,
+                            LogType::SmtpSend => &smtp_send_count_clone,
+                            LogType::MessageTracking => &message_tracking_count_clone,
+                            LogType::Unknown => &error_count_clone,
+                        };
+                        let mut count = counter.lock().unwrap();
+                        *count += 1;
+                    }
                     Err(e) => {
                         error!("Error processing file {}: {}", path.display(), e);
                         let mut count = error_count_clone.lock().unwrap();
@@ -242,7 +347,9 @@ async fn main() -> Result<()> {
     let smtp_receive = *smtp_receive_count.lock().unwrap();
     let smtp_send = *smtp_send_count.lock().unwrap();
     let message_tracking = *message_tracking_count.lock().unwrap();
+    let skipped = *skipped_count.lock().unwrap();
     let errors = *error_count.lock().unwrap();
+    let rows = *insert_stats.lock().unwrap();
 
     // Выводим статистику
     print_statistics(
@@ -251,8 +358,15 @@ async fn main() -> Result<()> {
         smtp_receive,
         smtp_send,
         message_tracking,
+        skipped,
         errors,
     );
+    let sinks = db.statistics();
+    if sinks.len() > 1 {
+        print_sink_statistics(&sinks);
+    } else {
+        print_insert_statistics(rows);
+    }
 
     Ok(())
 }
@@ -1,6 +1,13 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+lazy_static! {
+    /// Date in the name of an Exchange log file, e.g. `MSGTRK2024011514-1.LOG`
+    static ref LOG_FILE_DATE_REGEX: Regex = Regex::new(r"(?i)(\d{8})\d*-\d+\.log$").unwrap();
+}
+
 /// SMTP Receive log
 ///
 /// This struct is used to represent a SMTP Receive log.
@@ -28,7 +35,8 @@ use serde::{Deserialize, Serialize};
 /// ```
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SmtpReceiveLog {
-    pub id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
     pub date_time: DateTime<Utc>,
     pub connector_id: String,
     pub session_id: String,
@@ -42,7 +50,7 @@ pub struct SmtpReceiveLog {
     pub recipient: Option<String>,
     pub message_id: Option<String>,
     pub subject: Option<String>,
-    pub size: Option<i32>,
+    pub size: Option<i64>,
 }
 
 /// SMTP Send log
@@ -72,7 +80,8 @@ pub struct SmtpReceiveLog {
 /// ```
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SmtpSendLog {
-    pub id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
     pub date_time: DateTime<Utc>,
     pub connector_id: String,
     pub session_id: String,
@@ -89,6 +98,119 @@ pub struct SmtpSendLog {
     pub record_id: Option<String>,
 }
 
+/// SMTP protocol event
+///
+/// This struct is used to represent a single line of a SMTP Receive or SMTP Send
+/// protocol log. Unlike `SmtpReceiveLog` and `SmtpSendLog`, which summarise a whole
+/// session, every command and server response is kept as a separate event.
+///
+/// ### Examples
+///
+/// ```
+/// let event = SmtpEvent {
+///     id: None,
+///     date_time: Utc::now(),
+///     connector_id: "123".to_string(),
+///     session_id: "456".to_string(),
+///     sequence_number: 3,
+///     local_endpoint: "127.0.0.1:25".to_string(),
+///     remote_endpoint: "127.0.0.1:1235".to_string(),
+///     event: "<".to_string(),
+///     data: Some("EHLO client.example.com".to_string()),
+///     context: None,
+/// };
+/// ```
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SmtpEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub date_time: DateTime<Utc>,
+    pub connector_id: String,
+    pub session_id: String,
+    pub sequence_number: i32,
+    pub local_endpoint: String,
+    pub remote_endpoint: String,
+    pub event: String,
+    pub data: Option<String>,
+    pub context: Option<String>,
+}
+
+/// SMTP recipient
+///
+/// This struct is used to represent a single `RCPT TO` command of a SMTP Receive
+/// or SMTP Send session together with the response the server gave to it.
+///
+/// ### Examples
+///
+/// ```
+/// let recipient = SmtpRecipient {
+///     id: None,
+///     date_time: Utc::now(),
+///     session_id: "456".to_string(),
+///     sequence_number: 10,
+///     transaction_index: 0,
+///     recipient: "test@example.com".to_string(),
+///     response_code: Some(250),
+/// };
+/// ```
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SmtpRecipient {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub date_time: DateTime<Utc>,
+    pub session_id: String,
+    pub sequence_number: i32,
+    pub transaction_index: i32,
+    pub recipient: String,
+    pub response_code: Option<i32>,
+}
+
+/// SMTP message transaction
+///
+/// This struct is used to represent a single message transaction of a SMTP Receive
+/// or SMTP Send session: everything from `MAIL FROM` up to the final response to
+/// `DATA`/`BDAT ... LAST`, `RSET` or the start of the next transaction. One session
+/// may carry several transactions.
+///
+/// ### Examples
+///
+/// ```
+/// let transaction = SmtpTransaction {
+///     id: None,
+///     date_time: Utc::now(),
+///     connector_id: "123".to_string(),
+///     session_id: "456".to_string(),
+///     transaction_index: 0,
+///     local_endpoint: "127.0.0.1:25".to_string(),
+///     remote_endpoint: "127.0.0.1:1235".to_string(),
+///     sender: Some("test@example.com".to_string()),
+///     recipient_count: 1,
+///     size: Some(1024),
+///     message_id: Some("789@example.com".to_string()),
+///     record_id: None,
+///     response_code: Some(250),
+///     response: Some("250 2.6.0 <789@example.com> Queued mail for delivery".to_string()),
+/// };
+/// ```
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SmtpTransaction {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub date_time: DateTime<Utc>,
+    pub connector_id: String,
+    pub session_id: String,
+    pub transaction_index: i32,
+    pub local_endpoint: String,
+    pub remote_endpoint: String,
+    pub sender: Option<String>,
+    pub recipient_count: i32,
+    pub size: Option<i64>,
+    pub message_id: Option<String>,
+    pub record_id: Option<String>,
+    pub response_code: Option<i32>,
+    pub response: Option<String>,
+}
+
 /// Message Tracking log
 ///
 /// This struct is used to represent a Message Tracking log.
@@ -132,7 +254,8 @@ pub struct SmtpSendLog {
 /// ```
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MessageTrackingLog {
-    pub id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
     pub date_time: DateTime<Utc>,
     pub client_ip: Option<String>,
     pub client_hostname: Option<String>,
@@ -147,7 +270,7 @@ pub struct MessageTrackingLog {
     pub network_message_id: String,
     pub recipient_address: String,
     pub recipient_status: Option<String>,
-    pub total_bytes: Option<i32>,
+    pub total_bytes: Option<i64>,
     pub recipient_count: i32,
     pub related_recipient_address: Option<String>,
     pub reference: Option<String>,
@@ -165,6 +288,92 @@ pub struct MessageTrackingLog {
     pub schema_version: Option<String>,
 }
 
+/// Rejected line
+///
+/// This struct is used to represent a log line that could not be parsed and was
+/// quarantined instead of failing the whole file.
+///
+/// ### Examples
+///
+/// ```
+/// let rejected = RejectedLine {
+///     id: None,
+///     rejected_at: Utc::now(),
+///     file_path: "/logs/RECV2024010100-1.LOG".to_string(),
+///     line_number: 42,
+///     line: "2024-01-01T00:00:00.000Z,...".to_string(),
+///     reason: "invalid digit found in string".to_string(),
+/// };
+/// ```
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RejectedLine {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub rejected_at: DateTime<Utc>,
+    pub file_path: String,
+    pub line_number: i32,
+    pub line: String,
+    pub reason: String,
+}
+
+impl RejectedLine {
+    /// Returns the time the line was logged at
+    ///
+    /// This is the `date-time` field the line starts with or, if it cannot be parsed,
+    /// the date in the name of the log file. Unlike `rejected_at`, it stays the same
+    /// when the file is processed again.
+    pub fn logged_at(&self) -> Option<DateTime<Utc>> {
+        let logged_at = self
+            .line
+            .split(',')
+            .next()
+            .and_then(|field| DateTime::parse_from_rfc3339(field.trim()).ok())
+            .map(|date_time| date_time.with_timezone(&Utc));
+        let file_date = || {
+            let captures = LOG_FILE_DATE_REGEX.captures(&self.file_path)?;
+            let date = NaiveDate::parse_from_str(&captures[1], "%Y%m%d").ok()?;
+            Some(date.and_hms_opt(0, 0, 0)?.and_utc())
+        };
+        logged_at.or_else(file_date)
+    }
+}
+
+/// Ingested file
+///
+/// This struct is used to represent an entry of the ingestion journal: a log file
+/// that has already been processed and the position up to which it was read.
+///
+/// ### Examples
+///
+/// ```
+/// let file = IngestedFile {
+///     id: None,
+///     path: "/logs/MSGTRK2024010100-1.LOG".to_string(),
+///     size: 1048576,
+///     modified_at: Utc::now(),
+///     content_hash: "9f86d081884c7d659a2feaa0c55ad015...".to_string(),
+///     byte_offset: 1048576,
+///     line_number: 2500,
+///     log_type: "MessageTracking".to_string(),
+///     record_count: 2495,
+///     ingested_at: Utc::now(),
+/// };
+/// ```
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IngestedFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub path: String,
+    pub size: i64,
+    pub modified_at: DateTime<Utc>,
+    pub content_hash: String,
+    pub byte_offset: i64,
+    pub line_number: i64,
+    pub log_type: String,
+    pub record_count: i64,
+    pub ingested_at: DateTime<Utc>,
+}
+
 /// Log type
 ///
 /// This enum is used to represent the type of log.
@@ -174,10 +383,145 @@ pub struct MessageTrackingLog {
 /// ```
 /// let log_type = LogType::SmtpReceive;
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LogType {
     SmtpReceive,
     SmtpSend,
     MessageTracking,
     Unknown,
 }
+
+/// SMTP protocol log direction
+///
+/// This enum is used to tell SMTP Receive and SMTP Send records apart when
+/// both directions share the same record type.
+///
+/// ### Examples
+///
+/// ```
+/// let table = SmtpDirection::Receive.table_name("events"); // "smtp_receive_events"
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpDirection {
+    Receive,
+    Send,
+}
+
+impl SmtpDirection {
+    /// Returns the table name (without prefix) for the given kind of records
+    pub fn table_name(self, kind: &str) -> String {
+        match self {
+            SmtpDirection::Receive => format!("smtp_receive_{}", kind),
+            SmtpDirection::Send => format!("smtp_send_{}", kind),
+        }
+    }
+}
+
+/// Log record
+///
+/// This enum is used to represent a single record produced by the log parser.
+///
+/// ### Examples
+///
+/// ```
+/// let record = LogRecord::SmtpEvent(SmtpDirection::Receive, event);
+/// ```
+#[derive(Debug, Clone)]
+pub enum LogRecord {
+    SmtpReceiveSession(SmtpReceiveLog),
+    SmtpSendSession(SmtpSendLog),
+    SmtpEvent(SmtpDirection, SmtpEvent),
+    SmtpTransaction(SmtpDirection, SmtpTransaction),
+    SmtpRecipient(SmtpDirection, SmtpRecipient),
+    MessageTracking(Box<MessageTrackingLog>),
+    RejectedLine(RejectedLine),
+}
+
+/// SMTP records of one direction
+///
+/// This struct is used to group the detail records of SMTP Receive or SMTP Send sessions.
+#[derive(Debug, Clone, Default)]
+pub struct SmtpRecords {
+    pub events: Vec<SmtpEvent>,
+    pub transactions: Vec<SmtpTransaction>,
+    pub recipients: Vec<SmtpRecipient>,
+}
+
+impl SmtpRecords {
+    pub fn len(&self) -> usize {
+        self.events.len() + self.transactions.len() + self.recipients.len()
+    }
+}
+
+/// Record batch
+///
+/// This struct is used to group parsed records by destination table before they
+/// are written to the database.
+///
+/// ### Examples
+///
+/// ```
+/// let mut batch = RecordBatch::for_file(&journal_path);
+/// batch.push(record);
+/// if batch.len() >= 5000 {
+///     db.insert_batch(std::mem::replace(&mut batch, RecordBatch::for_file(&journal_path))).await?;
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RecordBatch {
+    /// Path of the file the records come from, as stored in the ingestion journal
+    pub source: Option<String>,
+    pub smtp_receive_logs: Vec<SmtpReceiveLog>,
+    pub smtp_send_logs: Vec<SmtpSendLog>,
+    pub smtp_receive: SmtpRecords,
+    pub smtp_send: SmtpRecords,
+    pub message_tracking_logs: Vec<MessageTrackingLog>,
+    pub rejected_lines: Vec<RejectedLine>,
+}
+
+impl RecordBatch {
+    /// Returns an empty batch for the records of a file
+    pub fn for_file(journal_path: &str) -> Self {
+        RecordBatch {
+            source: Some(journal_path.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the detail records of the given SMTP direction
+    pub fn smtp(&mut self, direction: SmtpDirection) -> &mut SmtpRecords {
+        match direction {
+            SmtpDirection::Receive => &mut self.smtp_receive,
+            SmtpDirection::Send => &mut self.smtp_send,
+        }
+    }
+
+    pub fn push(&mut self, record: LogRecord) {
+        match record {
+            LogRecord::SmtpReceiveSession(log) => self.smtp_receive_logs.push(log),
+            LogRecord::SmtpSendSession(log) => self.smtp_send_logs.push(log),
+            LogRecord::SmtpEvent(direction, event) => self.smtp(direction).events.push(event),
+            LogRecord::SmtpTransaction(direction, transaction) => {
+                self.smtp(direction).transactions.push(transaction)
+            }
+            LogRecord::SmtpRecipient(direction, recipient) => {
+                self.smtp(direction).recipients.push(recipient)
+            }
+            LogRecord::MessageTracking(log) => self.message_tracking_logs.push(*log),
+            LogRecord::RejectedLine(line) => self.rejected_lines.push(line),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.smtp_receive_logs.len()
+            + self.smtp_send_logs.len()
+            + self.smtp_receive.len()
+            + self.smtp_send.len()
+            + self.message_tracking_logs.len()
+            + self.rejected_lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
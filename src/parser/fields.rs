@@ -0,0 +1,88 @@
+use color_eyre::eyre::{Result, eyre};
+
+/// Splits a W3C log line into fields according to RFC 4180
+///
+/// Exchange wraps values that contain commas or quotes in double quotes and
+/// escapes embedded quotes by doubling them (`""`). Unquoted fields are taken
+/// as-is, so a stray quote in the middle of an unquoted value is kept literally.
+///
+/// ### Examples
+///
+/// ```
+/// let fields = split_fields(r#"a,"250 2.0.0 OK, queued","say ""hi""""#)?;
+/// assert_eq!(fields, vec!["a", "250 2.0.0 OK, queued", r#"say "hi""#]);
+/// ```
+pub fn split_fields(line: &str) -> Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut at_field_start = true;
+    let mut in_quotes = false;
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            ',' => {
+                fields.push(std::mem::take(&mut field));
+                at_field_start = true;
+                continue;
+            }
+            '"' if at_field_start => in_quotes = true,
+            _ => field.push(c),
+        }
+        at_field_start = false;
+    }
+
+    if in_quotes {
+        return Err(eyre!("Unterminated quoted field"));
+    }
+
+    fields.push(field);
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_commas_inside_quoted_fields() {
+        let fields = split_fields(r#"a,"250 2.0.0 OK, queued",b"#).unwrap();
+        assert_eq!(fields, vec!["a", "250 2.0.0 OK, queued", "b"]);
+    }
+
+    #[test]
+    fn unescapes_doubled_quotes() {
+        let fields = split_fields(r#""say ""hi""","""quoted""""#).unwrap();
+        assert_eq!(fields, vec![r#"say "hi""#, r#""quoted""#]);
+    }
+
+    #[test]
+    fn keeps_empty_quoted_fields() {
+        let fields = split_fields(r#""",a,"","#).unwrap();
+        assert_eq!(fields, vec!["", "a", "", ""]);
+    }
+
+    #[test]
+    fn keeps_quotes_inside_unquoted_fields() {
+        let fields = split_fields(r#"a "b" c,d"#).unwrap();
+        assert_eq!(fields, vec![r#"a "b" c"#, "d"]);
+    }
+
+    #[test]
+    fn rejects_unterminated_quoted_field() {
+        assert!(split_fields(r#"a,"250 2.0.0 OK, queued"#).is_err());
+        assert!(split_fields(r#"a,"say ""hi"""#).is_err());
+    }
+}
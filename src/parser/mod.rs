@@ -0,0 +1,535 @@
+mod fields;
+mod reader;
+mod smtp;
+mod tracking;
+
+use crate::models::{LogRecord, LogType, RejectedLine, SmtpReceiveLog, SmtpSendLog};
+use chrono::Utc;
+use color_eyre::eyre::{Report, Result, eyre};
+use encoding_rs::Encoding;
+use fields::split_fields;
+use futures::stream::{self, Stream};
+use log::{info, warn};
+pub use reader::FilePosition;
+use reader::LineReader;
+use smtp::SmtpLogParser;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tracking::MessageTrackingParser;
+
+pub struct LogParser;
+
+/// What to do with a data line that cannot be parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Log a warning and continue with the next line
+    Skip,
+    /// Emit the line as a `RejectedLine` record and continue with the next line
+    Quarantine,
+    /// Stop processing the file
+    Fail,
+}
+
+impl std::str::FromStr for ErrorPolicy {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(ErrorPolicy::Skip),
+            "quarantine" => Ok(ErrorPolicy::Quarantine),
+            "fail" => Ok(ErrorPolicy::Fail),
+            _ => Err(eyre!("Unsupported error policy: {}", s)),
+        }
+    }
+}
+
+/// Options controlling how log files are read
+#[derive(Debug, Clone, Copy)]
+pub struct ParseOptions {
+    /// Encoding of the files, detected from the contents when `None`
+    pub encoding: Option<&'static Encoding>,
+    /// Handling of lines that cannot be parsed
+    pub on_error: ErrorPolicy,
+    /// Files are still being written: a trailing line without a line feed is not complete
+    pub follow: bool,
+}
+
+/// Where to resume reading a file that was partly read by a previous run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResumePoint {
+    /// Start of the first line to read, before any SMTP session still open
+    pub position: FilePosition,
+    /// End of the part of the file whose records were already delivered
+    pub delivered: u64,
+}
+
+/// Incremental parser of the data lines of one log type
+trait RecordParser: Send {
+    /// Parses a data line starting at `start` and pushes the records that are already complete
+    fn parse_line(
+        &mut self,
+        start: FilePosition,
+        line: &str,
+        indices: &HashMap<String, usize>,
+        output: &mut VecDeque<LogRecord>,
+    ) -> Result<()>;
+
+    /// Pushes the records still held back once the file is known to be complete
+    fn finish(&mut self, output: &mut VecDeque<LogRecord>);
+
+    /// Returns the start of the first line of the oldest record still held back
+    fn oldest_open(&self) -> Option<FilePosition> {
+        None
+    }
+}
+
+/// Header block (`#Software`, `#Log-type`, `#Fields`, ...) at the start of a log file
+struct LogHeader {
+    log_type: LogType,
+    /// Lines already read from the file that still have to be parsed, with their start
+    lines: VecDeque<(FilePosition, String)>,
+}
+
+/// Reader of the records of one log file
+///
+/// Records are parsed lazily as the stream returned by `records` is polled, so
+/// only the current line and the SMTP sessions still open are kept in memory.
+///
+/// The records of SMTP sessions still in progress are held back until the session
+/// ends or `close` is called, so that no incomplete session is ever delivered.
+pub struct RecordReader {
+    log_type: LogType,
+    path: PathBuf,
+    reader: LineReader<File>,
+    replay: VecDeque<(FilePosition, String)>,
+    on_error: ErrorPolicy,
+    rejected_lines: u64,
+    parser: Box<dyn RecordParser>,
+    fields_indices: Option<HashMap<String, usize>>,
+    pending: VecDeque<LogRecord>,
+    finished: bool,
+    /// End of the part of the file whose records were delivered by a previous run
+    delivered: u64,
+    /// The current line was read by a previous run and only rebuilds the open sessions
+    replaying: bool,
+}
+
+impl RecordReader {
+    pub fn log_type(&self) -> LogType {
+        self.log_type
+    }
+
+    /// Returns the position after the last complete line read from the file
+    pub fn position(&self) -> FilePosition {
+        self.reader.position()
+    }
+
+    /// Returns the position from which a later run has to resume reading
+    ///
+    /// This is the start of the first line of the oldest SMTP session that is
+    /// still in progress, so that the whole session is read again. Without such
+    /// a session it is the position after the last complete line.
+    pub fn checkpoint(&self) -> FilePosition {
+        self.parser.oldest_open().unwrap_or_else(|| self.position())
+    }
+
+    /// Returns the end of the part of the file whose records have been delivered
+    ///
+    /// A later run resuming from `checkpoint` reads the lines up to this offset
+    /// again only to rebuild the SMTP sessions still open, without repeating their records.
+    pub fn delivered(&self) -> u64 {
+        self.delivered.max(self.reader.offset())
+    }
+
+    /// Returns the parsed records as a stream that ends at the end of the data available
+    ///
+    /// The end of the stream only means that there is nothing more to read yet: a new
+    /// stream picks up the lines appended later. Records of SMTP sessions still in
+    /// progress are held back until the session ends or `close` is called.
+    pub fn records(&mut self) -> impl Stream<Item = Result<LogRecord>> + '_ {
+        stream::try_unfold(self, |reader| async move {
+            Ok(reader.next_record().await?.map(|record| (record, reader)))
+        })
+    }
+
+    /// Returns the next parsed record, or `None` when there is nothing more to read
+    async fn next_record(&mut self) -> Result<Option<LogRecord>> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                return Ok(Some(record));
+            }
+            if self.finished {
+                return Ok(None);
+            }
+
+            let line = match self.replay.pop_front() {
+                Some(line) => Some(line),
+                None => {
+                    let start = self.reader.position();
+                    self.reader.next_line().await?.map(|line| (start, line))
+                }
+            };
+            let Some((start, line)) = line else {
+                return Ok(None);
+            };
+
+            self.replaying = self.reader.offset() <= self.delivered;
+            self.parse_line(start, &line)?;
+            if self.replaying {
+                // The records of the line were delivered when it was first read
+                self.pending.clear();
+            }
+        }
+    }
+
+    /// Ends the file: the records of SMTP sessions still in progress become
+    /// available from `records`
+    ///
+    /// Called once no more lines will be written to the file, e.g. after log rotation.
+    pub fn close(&mut self) {
+        if !self.finished {
+            self.parser.finish(&mut self.pending);
+            self.finished = true;
+        }
+    }
+
+    /// Logs the encoding the file was decoded with and the number of rejected lines
+    pub fn report(&self) {
+        if self.rejected_lines > 0 {
+            warn!(
+                "{} lines of {} could not be parsed ({:?})",
+                self.rejected_lines,
+                self.path.display(),
+                self.on_error
+            );
+        }
+
+        let encoding = self.reader.encoding().name();
+        match self.reader.malformed_lines() {
+            0 => info!("Decoded {} as {}", self.path.display(), encoding),
+            malformed => warn!(
+                "Decoded {} as {} with {} malformed lines",
+                self.path.display(),
+                encoding,
+                malformed
+            ),
+        }
+    }
+
+    fn parse_line(&mut self, start: FilePosition, line: &str) -> Result<()> {
+        if line.starts_with("#Fields:") {
+            self.fields_indices = Some(LogParser::parse_fields_header(line)?);
+            return Ok(());
+        }
+
+        if line.starts_with("#") || line.trim().is_empty() {
+            return Ok(());
+        }
+
+        if let Some(indices) = &self.fields_indices
+            && let Err(error) = self
+                .parser
+                .parse_line(start, line, indices, &mut self.pending)
+        {
+            return self.reject(line, error);
+        }
+        Ok(())
+    }
+
+    /// Applies the error policy to a data line that could not be parsed
+    fn reject(&mut self, line: &str, error: Report) -> Result<()> {
+        if self.replaying {
+            return Ok(());
+        }
+
+        let line_number = self.reader.line_number();
+        match self.on_error {
+            ErrorPolicy::Fail => return Err(eyre!("line {}: {}", line_number, error)),
+            ErrorPolicy::Skip => warn!(
+                "Skipping line {} of {}: {}",
+                line_number,
+                self.path.display(),
+                error
+            ),
+            ErrorPolicy::Quarantine => {
+                // The `line_number` column of `rejected_lines` is a 32-bit integer
+                let line_number = i32::try_from(line_number).map_err(|_| {
+                    eyre!(
+                        "line {}: line number is too large for rejected_lines: {}",
+                        line_number,
+                        error
+                    )
+                })?;
+                self.pending
+                    .push_back(LogRecord::RejectedLine(RejectedLine {
+                        id: None,
+                        rejected_at: Utc::now(),
+                        file_path: self.path.display().to_string(),
+                        line_number,
+                        line: line.to_string(),
+                        reason: error.to_string(),
+                    }))
+            }
+        }
+        self.rejected_lines += 1;
+        Ok(())
+    }
+}
+
+impl LogParser {
+    /// Reads the header block of a log file and detects the log type from `#Log-type`
+    ///
+    /// Reading stops at the first line that is not a comment, so only the header is
+    /// read. The lines read so far are returned to be replayed by the parser.
+    async fn read_header(reader: &mut LineReader<File>) -> Result<LogHeader> {
+        let mut header = LogHeader {
+            log_type: LogType::Unknown,
+            lines: VecDeque::new(),
+        };
+
+        loop {
+            let start = reader.position();
+            let Some(line) = reader.next_line().await? else {
+                break;
+            };
+            let is_comment = line.starts_with("#");
+            if line.starts_with("#Log-type:") {
+                header.log_type = match line.trim() {
+                    "#Log-type: SMTP Receive Protocol Log" => LogType::SmtpReceive,
+                    "#Log-type: SMTP Send Protocol Log" => LogType::SmtpSend,
+                    "#Log-type: Message Tracking Log" => LogType::MessageTracking,
+                    _ => LogType::Unknown,
+                };
+            }
+            header.lines.push_back((start, line));
+            if !is_comment {
+                break;
+            }
+        }
+
+        Ok(header)
+    }
+
+    /// Opens a log file for reading its records
+    ///
+    /// The file is opened once: the header used for type detection is replayed to
+    /// the parser. Lines are read and decoded one at a time, so memory usage does
+    /// not depend on the size of the file. Data lines that cannot be parsed are
+    /// handled according to `options.on_error`.
+    ///
+    /// If `resume` is given, the header is still read from the start of the file,
+    /// but data lines are read from the saved position. The records of the lines
+    /// before `resume.delivered` are not returned again.
+    pub async fn open_log_file(
+        file_path: &Path,
+        options: ParseOptions,
+        resume: Option<ResumePoint>,
+    ) -> Result<RecordReader> {
+        let mut reader = LineReader::open(file_path, options.encoding).await?;
+        if options.follow {
+            reader.hold_incomplete_lines();
+        }
+        let LogHeader { log_type, lines } = Self::read_header(&mut reader).await?;
+        let parser: Box<dyn RecordParser> = match log_type {
+            LogType::SmtpReceive => Box::new(SmtpLogParser::<SmtpReceiveLog>::new()),
+            LogType::SmtpSend => Box::new(SmtpLogParser::<SmtpSendLog>::new()),
+            LogType::MessageTracking => Box::new(MessageTrackingParser),
+            LogType::Unknown => {
+                return Err(eyre!("Unknown log type in file: {}", file_path.display()));
+            }
+        };
+
+        let mut records = RecordReader {
+            log_type,
+            path: file_path.to_path_buf(),
+            reader,
+            replay: lines,
+            on_error: options.on_error,
+            rejected_lines: 0,
+            parser,
+            fields_indices: None,
+            pending: VecDeque::new(),
+            finished: false,
+            delivered: 0,
+            replaying: false,
+        };
+
+        if let Some(ResumePoint {
+            position,
+            delivered,
+        }) = resume
+        {
+            // Only the header is needed from the lines before the saved position
+            while let Some((start, line)) = records.replay.pop_front() {
+                if line.starts_with("#") {
+                    records.parse_line(start, &line)?;
+                }
+            }
+            records.reader.seek(position).await?;
+            records.delivered = delivered;
+        }
+
+        Ok(records)
+    }
+
+    /// Builds a field name to column index map from a `#Fields:` header line
+    fn parse_fields_header(line: &str) -> Result<HashMap<String, usize>> {
+        let fields = split_fields(line.trim_start_matches("#Fields:"))?;
+        Ok(fields
+            .into_iter()
+            .enumerate()
+            .map(|(i, field)| (field.trim().to_string(), i))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    const HEADER: &str = "#Software: Microsoft Exchange Server\r\n\
+        #Version: 15.0.0.0\r\n\
+        #Log-type: SMTP Receive Protocol Log\r\n\
+        #Date: 2024-01-15T14:00:00.000Z\r\n\
+        #Fields: date-time,connector-id,session-id,sequence-number,local-endpoint,remote-endpoint,event,data,context\r\n";
+
+    const VALID: &str =
+        "2024-01-15T14:00:00.000Z,MAIL01\\Default,A,0,10.0.0.1:25,10.0.0.2:50000,+,,\r\n";
+    const BROKEN: &str = "2024-01-15T14:00:01.000Z,MAIL01\\Default,A\r\n";
+
+    /// Writes a SMTP Receive log with the given data lines to a temporary file
+    fn write_log(name: &str, data: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "elp-parser-test-{}-{}.log",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, format!("{}{}", HEADER, data)).unwrap();
+        path
+    }
+
+    fn options(on_error: ErrorPolicy) -> ParseOptions {
+        ParseOptions {
+            encoding: None,
+            on_error,
+            follow: false,
+        }
+    }
+
+    async fn read_records(
+        path: &Path,
+        options: ParseOptions,
+        resume: Option<ResumePoint>,
+    ) -> Result<Vec<LogRecord>> {
+        let mut reader = LogParser::open_log_file(path, options, resume).await?;
+        reader.records().try_collect().await
+    }
+
+    fn rejected(records: &[LogRecord]) -> Vec<&RejectedLine> {
+        records
+            .iter()
+            .filter_map(|record| match record {
+                LogRecord::RejectedLine(line) => Some(line),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn skips_lines_that_cannot_be_parsed() {
+        let path = write_log("skip", &format!("{}{}", BROKEN, VALID));
+        let records = read_records(&path, options(ErrorPolicy::Skip), None)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(rejected(&records).is_empty());
+        assert!(records.iter().any(
+            |record| matches!(record, LogRecord::SmtpEvent(_, event) if event.session_id == "A")
+        ));
+    }
+
+    #[tokio::test]
+    async fn quarantines_lines_that_cannot_be_parsed() {
+        let path = write_log("quarantine", &format!("{}{}", VALID, BROKEN));
+        let records = read_records(&path, options(ErrorPolicy::Quarantine), None)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let rejected = rejected(&records);
+        assert_eq!(rejected.len(), 1);
+        // Header lines count: the broken line is the 7th line of the file
+        assert_eq!(rejected[0].line_number, 7);
+        assert_eq!(rejected[0].line, BROKEN.trim_end());
+        assert_eq!(rejected[0].file_path, path.display().to_string());
+        assert_eq!(
+            rejected[0].reason,
+            "Line has fewer parts than expected fields"
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_at_first_line_that_cannot_be_parsed() {
+        let path = write_log("fail", &format!("{}{}", BROKEN, VALID));
+        let error = read_records(&path, options(ErrorPolicy::Fail), None)
+            .await
+            .unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            error.to_string(),
+            "line 6: Line has fewer parts than expected fields"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_to_quarantine_line_number_beyond_i32() {
+        let path = write_log("overflow", BROKEN);
+        let resume = ResumePoint {
+            position: FilePosition {
+                offset: HEADER.len() as u64,
+                line: i32::MAX as usize,
+            },
+            delivered: HEADER.len() as u64,
+        };
+        let error = read_records(&path, options(ErrorPolicy::Quarantine), Some(resume))
+            .await
+            .unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(
+            error
+                .to_string()
+                .starts_with("line 2147483648: line number is too large for rejected_lines"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn decodes_with_the_encoding_given_in_options() {
+        let (data, _, _) = encoding_rs::KOI8_R.encode(
+            "2024-01-15T14:00:00.000Z,MAIL01\\Default,A,0,10.0.0.1:25,10.0.0.2:50000,*,,Тема\r\n",
+        );
+        let path = write_log("encoding", "");
+        let mut contents = std::fs::read(&path).unwrap();
+        contents.extend_from_slice(&data);
+        std::fs::write(&path, contents).unwrap();
+
+        let options = ParseOptions {
+            encoding: Some(encoding_rs::KOI8_R),
+            ..options(ErrorPolicy::Fail)
+        };
+        let records = read_records(&path, options, None).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let Some(LogRecord::SmtpEvent(_, event)) = records.first() else {
+            panic!("expected an event, got {:?}", records);
+        };
+        // Without the option the line would be detected as Windows-1251
+        assert_eq!(event.context.as_deref(), Some("Тема"));
+    }
+}
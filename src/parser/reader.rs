@@ -0,0 +1,410 @@
+use color_eyre::eyre::Result;
+use encoding_rs::{Encoding, UTF_8, UTF_16BE, UTF_16LE, WINDOWS_1251};
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, BufReader};
+
+/// Size of the read buffer used for log files
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Position in a log file right after a complete line
+///
+/// This struct is used to resume reading a file that has grown since it was last read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FilePosition {
+    /// Byte offset from the start of the file
+    pub offset: u64,
+    /// Number of lines before the offset
+    pub line: usize,
+}
+
+/// Reads a log file line by line
+///
+/// Only the current line is kept in memory, so memory usage does not depend on
+/// the file size. Each line is decoded separately with the encoding chosen as follows:
+///
+/// 1. the encoding given explicitly by the caller;
+/// 2. the encoding of the byte order mark (UTF-8, UTF-16LE or UTF-16BE);
+/// 3. UTF-8 if the first line with non-ASCII bytes is valid UTF-8, otherwise Windows-1251.
+///
+/// Until the first non-ASCII line the encoding is left undecided, since ASCII text
+/// decodes the same way in both candidates.
+pub struct LineReader<R> {
+    reader: BufReader<R>,
+    encoding: Option<&'static Encoding>,
+    buffer: Vec<u8>,
+    malformed_lines: u64,
+    offset: u64,
+    line_number: usize,
+    checkpoint: FilePosition,
+    hold_incomplete: bool,
+    partial: bool,
+}
+
+impl LineReader<File> {
+    pub async fn open(file_path: &Path, encoding: Option<&'static Encoding>) -> Result<Self> {
+        let file = File::open(file_path).await?;
+        Self::new(file, encoding).await
+    }
+}
+
+impl<R: AsyncRead + Unpin> LineReader<R> {
+    pub async fn new(inner: R, encoding: Option<&'static Encoding>) -> Result<Self> {
+        let mut reader = BufReader::with_capacity(READ_BUFFER_SIZE, inner);
+        let head = reader.fill_buf().await?;
+        let bom = Encoding::for_bom(head);
+        let bom_length = bom.map_or(0, |(_, length)| length);
+        reader.consume(bom_length);
+
+        let start = FilePosition {
+            offset: bom_length as u64,
+            line: 0,
+        };
+        Ok(LineReader {
+            reader,
+            encoding: encoding.or(bom.map(|(encoding, _)| encoding)),
+            buffer: Vec::new(),
+            malformed_lines: 0,
+            offset: start.offset,
+            line_number: start.line,
+            checkpoint: start,
+            hold_incomplete: false,
+            partial: false,
+        })
+    }
+
+    /// Makes the reader wait for the line feed of a trailing line
+    ///
+    /// Used for files that are still being written: a line without a line feed at
+    /// the end of the file is not returned until the rest of it has been appended.
+    pub fn hold_incomplete_lines(&mut self) {
+        self.hold_incomplete = true;
+    }
+
+    /// Returns the number of the last line read, starting from 1
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    /// Returns the position right after the last line that ended with a line feed
+    ///
+    /// A trailing line without a line feed may still be written to, so reading
+    /// should be resumed from this position rather than from the end of the file.
+    pub fn position(&self) -> FilePosition {
+        self.checkpoint
+    }
+
+    /// Returns the byte offset right after the last line read, complete or not
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the encoding used to decode the file
+    ///
+    /// A file that contained only ASCII text so far is reported as UTF-8.
+    pub fn encoding(&self) -> &'static Encoding {
+        self.encoding.unwrap_or(UTF_8)
+    }
+
+    /// Returns the number of lines that contained bytes invalid in the encoding
+    pub fn malformed_lines(&self) -> u64 {
+        self.malformed_lines
+    }
+
+    /// Reads and decodes the next line without the line terminator
+    pub async fn next_line(&mut self) -> Result<Option<String>> {
+        if !self.partial {
+            self.buffer.clear();
+        }
+        if !self.read_raw_line().await? {
+            return Ok(None);
+        }
+
+        // Encodings without a byte order mark are ASCII-compatible
+        let complete = self.ends_with_line_feed(self.encoding.unwrap_or(UTF_8));
+        self.partial = !complete && self.hold_incomplete;
+        if self.partial {
+            return Ok(None);
+        }
+
+        let encoding = match self.encoding {
+            Some(encoding) => encoding,
+            None if self.buffer.is_ascii() => UTF_8,
+            None => {
+                let encoding = if std::str::from_utf8(&self.buffer).is_ok() {
+                    UTF_8
+                } else {
+                    WINDOWS_1251
+                };
+                self.encoding = Some(encoding);
+                encoding
+            }
+        };
+
+        self.offset += self.buffer.len() as u64;
+        self.line_number += 1;
+        if complete {
+            self.checkpoint = FilePosition {
+                offset: self.offset,
+                line: self.line_number,
+            };
+        }
+
+        let (line, had_errors) = encoding.decode_without_bom_handling(&self.buffer);
+        if had_errors {
+            self.malformed_lines += 1;
+        }
+        Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+    }
+
+    /// Checks whether the line in the buffer is terminated by a line feed
+    fn ends_with_line_feed(&self, encoding: &'static Encoding) -> bool {
+        let even = self.buffer.len().is_multiple_of(2);
+        if encoding == UTF_16LE {
+            even && self.buffer.ends_with(&[b'\n', 0])
+        } else if encoding == UTF_16BE {
+            even && self.buffer.ends_with(&[0, b'\n'])
+        } else {
+            self.buffer.ends_with(b"\n")
+        }
+    }
+
+    /// Reads the bytes of the next line into the buffer, including the terminator
+    ///
+    /// Returns `false` at end of file. In UTF-16 the line feed is a two-byte code
+    /// unit, so a `0x0A` byte only ends the line when it forms that unit.
+    async fn read_raw_line(&mut self) -> Result<bool> {
+        loop {
+            let read = self.reader.read_until(b'\n', &mut self.buffer).await?;
+            if read == 0 {
+                return Ok(!self.buffer.is_empty());
+            }
+            if self.buffer.last() != Some(&b'\n') {
+                return Ok(true);
+            }
+
+            let position = self.buffer.len() - 1;
+            if self.encoding == Some(UTF_16LE) {
+                if position.is_multiple_of(2) {
+                    let mut high = [0u8; 1];
+                    if self.reader.read(&mut high).await? == 0 {
+                        return Ok(true);
+                    }
+                    self.buffer.push(high[0]);
+                    if high[0] == 0 {
+                        return Ok(true);
+                    }
+                }
+            } else if self.encoding == Some(UTF_16BE) {
+                if position % 2 == 1 && self.buffer[position - 1] == 0 {
+                    return Ok(true);
+                }
+            } else {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> LineReader<R> {
+    /// Moves the reader to a position saved earlier, e.g. by a previous run
+    pub async fn seek(&mut self, position: FilePosition) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(position.offset)).await?;
+        self.offset = position.offset;
+        self.line_number = position.line;
+        self.checkpoint = position;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    async fn read_lines(bytes: &[u8], encoding: Option<&'static Encoding>) -> Vec<String> {
+        let mut reader = LineReader::new(bytes, encoding).await.unwrap();
+        let mut lines = Vec::new();
+        while let Some(line) = reader.next_line().await.unwrap() {
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn reads_lines_across_buffer_boundaries() {
+        // The longest lines span several read buffers, the others straddle their edges
+        let lines: Vec<String> = (0..40)
+            .map(|i| format!("{}:{}", i, "x".repeat(i * 5000)))
+            .collect();
+        let text: String = lines.iter().map(|line| format!("{}\r\n", line)).collect();
+        assert!(text.len() > 10 * READ_BUFFER_SIZE);
+
+        let mut reader = LineReader::new(text.as_bytes(), None).await.unwrap();
+        let mut offset = 0;
+        for (i, expected) in lines.iter().enumerate() {
+            let line = reader.next_line().await.unwrap();
+            assert_eq!(line.as_deref(), Some(expected.as_str()));
+            offset += expected.len() as u64 + 2;
+            assert_eq!(
+                reader.position(),
+                FilePosition {
+                    offset,
+                    line: i + 1
+                }
+            );
+        }
+        assert_eq!(reader.next_line().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn returns_trailing_line_without_line_feed_at_end_of_file() {
+        let lines = read_lines(b"first\nsecond", None).await;
+        assert_eq!(lines, vec!["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn holds_incomplete_line_until_it_is_appended() {
+        let path = std::env::temp_dir().join(format!(
+            "elp-reader-test-{}-incomplete.log",
+            std::process::id()
+        ));
+        std::fs::write(&path, "first\nsec").unwrap();
+
+        let mut reader = LineReader::open(&path, None).await.unwrap();
+        reader.hold_incomplete_lines();
+        assert_eq!(reader.next_line().await.unwrap().as_deref(), Some("first"));
+        assert_eq!(reader.next_line().await.unwrap(), None);
+        assert_eq!(reader.position(), FilePosition { offset: 6, line: 1 });
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"ond\nthird\n").unwrap();
+        assert_eq!(reader.next_line().await.unwrap().as_deref(), Some("second"));
+        assert_eq!(reader.next_line().await.unwrap().as_deref(), Some("third"));
+        assert_eq!(
+            reader.position(),
+            FilePosition {
+                offset: 19,
+                line: 3
+            }
+        );
+        assert_eq!(reader.next_line().await.unwrap(), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn utf16(text: &str, big_endian: bool) -> Vec<u8> {
+        let mut bytes = if big_endian {
+            vec![0xFE, 0xFF]
+        } else {
+            vec![0xFF, 0xFE]
+        };
+        for unit in text.encode_utf16() {
+            if big_endian {
+                bytes.extend(unit.to_be_bytes());
+            } else {
+                bytes.extend(unit.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn skips_utf8_byte_order_mark() {
+        let mut reader = LineReader::new(&b"\xEF\xBB\xBF#Fields: date-time\n"[..], None)
+            .await
+            .unwrap();
+        assert_eq!(
+            reader.next_line().await.unwrap().as_deref(),
+            Some("#Fields: date-time")
+        );
+        assert_eq!(reader.encoding(), UTF_8);
+        assert_eq!(
+            reader.position(),
+            FilePosition {
+                offset: 22,
+                line: 1
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn decodes_utf16_from_byte_order_mark() {
+        // U+010A has 0x0A as one of its bytes, which must not end the line
+        let text = "Тема \u{010A}\r\nsecond\r\n";
+        for big_endian in [false, true] {
+            let bytes = utf16(text, big_endian);
+            let mut reader = LineReader::new(bytes.as_slice(), None).await.unwrap();
+            assert_eq!(
+                reader.next_line().await.unwrap().as_deref(),
+                Some("Тема \u{010A}")
+            );
+            assert_eq!(reader.next_line().await.unwrap().as_deref(), Some("second"));
+            assert_eq!(reader.next_line().await.unwrap(), None);
+            assert_eq!(
+                reader.encoding(),
+                if big_endian { UTF_16BE } else { UTF_16LE }
+            );
+            assert_eq!(reader.position().offset, bytes.len() as u64);
+            assert_eq!(reader.malformed_lines(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn detects_utf8_and_windows_1251_from_first_non_ascii_line() {
+        let (windows_1251, _, _) = WINDOWS_1251.encode("ascii\nТема письма\n");
+        let mut reader = LineReader::new(&windows_1251[..], None).await.unwrap();
+        assert_eq!(reader.next_line().await.unwrap().as_deref(), Some("ascii"));
+        assert_eq!(reader.encoding(), UTF_8);
+        assert_eq!(
+            reader.next_line().await.unwrap().as_deref(),
+            Some("Тема письма")
+        );
+        assert_eq!(reader.encoding(), WINDOWS_1251);
+
+        let lines = read_lines("ascii\nТема письма\n".as_bytes(), None).await;
+        assert_eq!(lines, vec!["ascii", "Тема письма"]);
+    }
+
+    #[tokio::test]
+    async fn uses_explicit_encoding_and_counts_malformed_lines() {
+        // Valid UTF-8 is still decoded as Windows-1251 when that encoding is given
+        let lines = read_lines("Тема\n".as_bytes(), Some(WINDOWS_1251)).await;
+        assert_eq!(lines, vec!["РўРµРјР°"]);
+
+        let mut reader = LineReader::new(&b"ok\n\xFF\xFE broken\n"[..], Some(UTF_8))
+            .await
+            .unwrap();
+        while reader.next_line().await.unwrap().is_some() {}
+        assert_eq!(reader.encoding(), UTF_8);
+        assert_eq!(reader.malformed_lines(), 1);
+    }
+
+    #[tokio::test]
+    async fn resumes_from_saved_position() {
+        let path =
+            std::env::temp_dir().join(format!("elp-reader-test-{}-resume.log", std::process::id()));
+        std::fs::write(&path, "first\nsecond\nthird\n").unwrap();
+
+        let mut reader = LineReader::open(&path, None).await.unwrap();
+        reader
+            .seek(FilePosition { offset: 6, line: 1 })
+            .await
+            .unwrap();
+        assert_eq!(reader.next_line().await.unwrap().as_deref(), Some("second"));
+        assert_eq!(reader.line_number(), 2);
+        assert_eq!(
+            reader.position(),
+            FilePosition {
+                offset: 13,
+                line: 2
+            }
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,763 @@
+use super::fields::split_fields;
+use super::{FilePosition, RecordParser};
+use crate::models::{
+    LogRecord, SmtpDirection, SmtpEvent, SmtpReceiveLog, SmtpRecipient, SmtpSendLog,
+    SmtpTransaction,
+};
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Result, eyre};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::{HashMap, VecDeque};
+
+lazy_static! {
+    static ref COMMAND_REGEX: Regex = Regex::new(r"^([A-Za-z][A-Za-z0-9-]*)(?:\s|$)").unwrap();
+    static ref RESPONSE_REGEX: Regex = Regex::new(r"^(\d{3})([ -]|$)").unwrap();
+    static ref MAIL_FROM_REGEX: Regex = Regex::new(r"(?i)^MAIL FROM:\s*<([^>]*)>").unwrap();
+    static ref RCPT_TO_REGEX: Regex = Regex::new(r"(?i)^RCPT TO:\s*<([^>]*)>").unwrap();
+    static ref SIZE_REGEX: Regex = Regex::new(r"(?i)\bSIZE=(\d+)").unwrap();
+    static ref BDAT_LAST_REGEX: Regex = Regex::new(r"(?i)^BDAT\s+\d+\s+LAST\b").unwrap();
+    static ref MESSAGE_ID_REGEX: Regex = Regex::new(r"<([^>]+)>").unwrap();
+    static ref RECORD_ID_REGEX: Regex = Regex::new(r"RecordId (\d+)").unwrap();
+    static ref INTERNET_MESSAGE_ID_REGEX: Regex =
+        Regex::new(r"InternetMessageId <([^>]+)>").unwrap();
+    static ref PROXY_SESSION_REGEX: Regex = Regex::new(r"session id (\w+)").unwrap();
+}
+
+/// Parses the fields shared by SMTP Receive and SMTP Send protocol log lines
+fn parse_event(line: &str, indices: &HashMap<String, usize>) -> Result<SmtpEvent> {
+    let parts = split_fields(line)?;
+    if parts.len() < indices.len() {
+        return Err(eyre!("Line has fewer parts than expected fields"));
+    }
+
+    let date_time = DateTime::parse_from_rfc3339(&parts[indices["date-time"]])
+        .map_err(|e| eyre!("Failed to parse date: {}", e))?
+        .with_timezone(&Utc);
+
+    let optional = |field: &str| parts.get(indices[field]).filter(|s| !s.is_empty()).cloned();
+
+    Ok(SmtpEvent {
+        id: None,
+        date_time,
+        connector_id: parts[indices["connector-id"]].clone(),
+        session_id: parts[indices["session-id"]].clone(),
+        sequence_number: parts[indices["sequence-number"]]
+            .parse::<i32>()
+            .map_err(|e| eyre!("Failed to parse sequence number: {}", e))?,
+        local_endpoint: parts[indices["local-endpoint"]].clone(),
+        remote_endpoint: parts[indices["remote-endpoint"]].clone(),
+        event: parts[indices["event"]].clone(),
+        data: optional("data"),
+        context: optional("context"),
+    })
+}
+
+/// Session summary record of a SMTP protocol log
+pub trait SmtpSessionLog: Sized + Send {
+    const DIRECTION: SmtpDirection;
+
+    /// Creates the summary from the first line of the session
+    fn start(event: &SmtpEvent) -> Self;
+
+    /// Looks at every following line of the session
+    fn observe(&mut self, _event: &SmtpEvent) {}
+
+    /// Completes the summary once the session is over
+    fn finish(self, conversation: &SmtpConversation) -> LogRecord;
+}
+
+impl SmtpSessionLog for SmtpReceiveLog {
+    const DIRECTION: SmtpDirection = SmtpDirection::Receive;
+
+    fn start(event: &SmtpEvent) -> Self {
+        SmtpReceiveLog {
+            id: None,
+            date_time: event.date_time,
+            connector_id: event.connector_id.clone(),
+            session_id: event.session_id.clone(),
+            sequence_number: event.sequence_number,
+            local_endpoint: event.local_endpoint.clone(),
+            remote_endpoint: event.remote_endpoint.clone(),
+            event: event.event.clone(),
+            data: event.data.clone(),
+            context: event.context.clone(),
+            sender: None,
+            recipient: None,
+            message_id: None,
+            subject: None,
+            size: None,
+        }
+    }
+
+    fn finish(mut self, conversation: &SmtpConversation) -> LogRecord {
+        // The session summary describes the first message transaction of the session
+        if let Some(transaction) = conversation.transactions.first() {
+            self.sender = transaction.sender.clone();
+            self.recipient = conversation.first_recipient(transaction);
+            self.message_id = transaction.message_id.clone();
+            self.size = transaction.size;
+        }
+        LogRecord::SmtpReceiveSession(self)
+    }
+}
+
+impl SmtpSessionLog for SmtpSendLog {
+    const DIRECTION: SmtpDirection = SmtpDirection::Send;
+
+    fn start(event: &SmtpEvent) -> Self {
+        SmtpSendLog {
+            id: None,
+            date_time: event.date_time,
+            connector_id: event.connector_id.clone(),
+            session_id: event.session_id.clone(),
+            sequence_number: event.sequence_number,
+            local_endpoint: event.local_endpoint.clone(),
+            remote_endpoint: event.remote_endpoint.clone(),
+            event: event.event.clone(),
+            data: event.data.clone(),
+            context: event.context.clone(),
+            proxy_session_id: None,
+            sender: None,
+            recipient: None,
+            message_id: None,
+            record_id: None,
+        }
+    }
+
+    fn observe(&mut self, event: &SmtpEvent) {
+        if let Some(context) = &event.context
+            && context.contains("Proxying inbound session")
+            && let Some(captures) = PROXY_SESSION_REGEX.captures(context)
+        {
+            self.proxy_session_id = captures.get(1).map(|m| m.as_str().to_string());
+        }
+    }
+
+    fn finish(mut self, conversation: &SmtpConversation) -> LogRecord {
+        // The session summary describes the first message transaction of the session
+        if let Some(transaction) = conversation.transactions.first() {
+            self.sender = transaction.sender.clone();
+            self.recipient = conversation.first_recipient(transaction);
+            self.message_id = transaction.message_id.clone();
+            self.record_id = transaction.record_id.clone();
+        }
+        LogRecord::SmtpSendSession(self)
+    }
+}
+
+/// SMTP session that has not disconnected yet, with the start of its first line
+struct OpenSession<T> {
+    start: FilePosition,
+    log: T,
+    conversation: SmtpConversation,
+}
+
+/// Incremental parser of SMTP Receive and SMTP Send protocol logs
+///
+/// Events are emitted as soon as their line is read. Session summaries, transactions
+/// and recipients are emitted when the session disconnects or the file ends, so only
+/// the sessions that are still open are kept in memory.
+pub struct SmtpLogParser<T> {
+    sessions: HashMap<String, OpenSession<T>>,
+}
+
+impl<T: SmtpSessionLog> SmtpLogParser<T> {
+    pub fn new() -> Self {
+        SmtpLogParser {
+            sessions: HashMap::new(),
+        }
+    }
+
+    fn emit_session(session: OpenSession<T>, output: &mut VecDeque<LogRecord>) {
+        let OpenSession {
+            log, conversation, ..
+        } = session;
+        output.push_back(log.finish(&conversation));
+        output.extend(
+            conversation
+                .transactions
+                .into_iter()
+                .map(|transaction| LogRecord::SmtpTransaction(T::DIRECTION, transaction)),
+        );
+        output.extend(
+            conversation
+                .recipients
+                .into_iter()
+                .map(|recipient| LogRecord::SmtpRecipient(T::DIRECTION, recipient)),
+        );
+    }
+}
+
+impl<T: SmtpSessionLog> RecordParser for SmtpLogParser<T> {
+    fn parse_line(
+        &mut self,
+        start: FilePosition,
+        line: &str,
+        indices: &HashMap<String, usize>,
+        output: &mut VecDeque<LogRecord>,
+    ) -> Result<()> {
+        let event = parse_event(line, indices)?;
+
+        // Create or get existing session log
+        let session = self
+            .sessions
+            .entry(event.session_id.clone())
+            .or_insert_with(|| OpenSession {
+                start,
+                log: T::start(&event),
+                conversation: SmtpConversation::new(T::DIRECTION),
+            });
+        session.log.observe(&event);
+        session.conversation.process(&event);
+
+        let disconnected = event.event == "-";
+        let session_id = disconnected.then(|| event.session_id.clone());
+        output.push_back(LogRecord::SmtpEvent(T::DIRECTION, event));
+
+        if let Some(session_id) = session_id
+            && let Some(session) = self.sessions.remove(&session_id)
+        {
+            Self::emit_session(session, output);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, output: &mut VecDeque<LogRecord>) {
+        for (_, session) in self.sessions.drain() {
+            Self::emit_session(session, output);
+        }
+    }
+
+    fn oldest_open(&self) -> Option<FilePosition> {
+        self.sessions
+            .values()
+            .map(|session| session.start)
+            .min_by_key(|start| start.offset)
+    }
+}
+
+/// Command that is still waiting for a response from the other side
+///
+/// Transaction and recipient references are indices into the conversation vectors.
+enum PendingCommand {
+    Mail(usize),
+    Rcpt(usize, usize),
+    Data(usize),
+    Other,
+}
+
+/// Tracks the command/response conversation of a single SMTP session
+///
+/// Commands are matched with responses in order, so pipelined commands
+/// (`MAIL`, several `RCPT`, `DATA` sent at once) still get the right response.
+/// Intermediate replies (`334`, `354`) and continuation lines (`250-...`)
+/// keep the command pending until its final reply arrives.
+///
+/// A new message transaction starts with every `MAIL FROM` and ends with the
+/// final response to `DATA`/`BDAT ... LAST`, with `RSET` or with the next `MAIL FROM`.
+pub struct SmtpConversation {
+    direction: SmtpDirection,
+    pending: VecDeque<PendingCommand>,
+    current: Option<usize>,
+    announced: Option<(Option<String>, Option<String>)>,
+    pub transactions: Vec<SmtpTransaction>,
+    pub recipients: Vec<SmtpRecipient>,
+}
+
+impl SmtpConversation {
+    pub fn new(direction: SmtpDirection) -> Self {
+        SmtpConversation {
+            direction,
+            pending: VecDeque::new(),
+            current: None,
+            announced: None,
+            transactions: Vec::new(),
+            recipients: Vec::new(),
+        }
+    }
+
+    /// Event marker of lines sent by the SMTP client
+    fn command_marker(&self) -> &'static str {
+        match self.direction {
+            SmtpDirection::Receive => "<",
+            SmtpDirection::Send => ">",
+        }
+    }
+
+    /// Event marker of lines sent by the SMTP server
+    fn response_marker(&self) -> &'static str {
+        match self.direction {
+            SmtpDirection::Receive => ">",
+            SmtpDirection::Send => "<",
+        }
+    }
+
+    /// Feeds the next protocol log line of the session
+    pub fn process(&mut self, event: &SmtpEvent) {
+        match event.event.as_str() {
+            "-" => self.current = None,
+            "*" => self.process_information(event),
+            marker => {
+                let Some(data) = event.data.as_deref() else {
+                    return;
+                };
+                if marker == self.command_marker() {
+                    self.process_command(event, data);
+                } else if marker == self.response_marker() {
+                    self.process_response(data);
+                }
+            }
+        }
+    }
+
+    /// Remembers the message announced by Exchange before it is sent (SMTP Send only)
+    fn process_information(&mut self, event: &SmtpEvent) {
+        let Some(context) = event.context.as_deref() else {
+            return;
+        };
+
+        if context.contains("sending message with RecordId") {
+            let record_id = RECORD_ID_REGEX
+                .captures(context)
+                .map(|captures| captures[1].to_string());
+            let message_id = INTERNET_MESSAGE_ID_REGEX
+                .captures(context)
+                .map(|captures| captures[1].to_string());
+            self.announced = Some((record_id, message_id));
+        }
+    }
+
+    fn process_command(&mut self, event: &SmtpEvent, data: &str) {
+        let Some(captures) = COMMAND_REGEX.captures(data) else {
+            return;
+        };
+
+        let pending = match captures[1].to_ascii_uppercase().as_str() {
+            "MAIL" => {
+                let (record_id, message_id) = self.announced.take().unwrap_or_default();
+                self.transactions.push(SmtpTransaction {
+                    id: None,
+                    date_time: event.date_time,
+                    connector_id: event.connector_id.clone(),
+                    session_id: event.session_id.clone(),
+                    transaction_index: self.transactions.len() as i32,
+                    local_endpoint: event.local_endpoint.clone(),
+                    remote_endpoint: event.remote_endpoint.clone(),
+                    sender: MAIL_FROM_REGEX
+                        .captures(data)
+                        .map(|captures| captures[1].to_string())
+                        .filter(|sender| !sender.is_empty()),
+                    recipient_count: 0,
+                    size: SIZE_REGEX
+                        .captures(data)
+                        .and_then(|captures| captures[1].parse::<i64>().ok()),
+                    message_id,
+                    record_id,
+                    response_code: None,
+                    response: None,
+                });
+                let index = self.transactions.len() - 1;
+                self.current = Some(index);
+                PendingCommand::Mail(index)
+            }
+            // A RCPT outside of a transaction (before MAIL, after RSET or after
+            // the end of the message) is rejected by the server and is not stored
+            "RCPT" => match (self.current, RCPT_TO_REGEX.captures(data)) {
+                (Some(index), Some(rcpt)) => {
+                    let transaction = &mut self.transactions[index];
+                    transaction.recipient_count += 1;
+                    self.recipients.push(SmtpRecipient {
+                        id: None,
+                        date_time: event.date_time,
+                        session_id: event.session_id.clone(),
+                        sequence_number: event.sequence_number,
+                        transaction_index: transaction.transaction_index,
+                        recipient: rcpt[1].to_string(),
+                        response_code: None,
+                    });
+                    PendingCommand::Rcpt(self.recipients.len() - 1, index)
+                }
+                _ => PendingCommand::Other,
+            },
+            "DATA" => self
+                .current
+                .map_or(PendingCommand::Other, PendingCommand::Data),
+            "BDAT" if BDAT_LAST_REGEX.is_match(data) => self
+                .current
+                .map_or(PendingCommand::Other, PendingCommand::Data),
+            "RSET" => {
+                self.current = None;
+                PendingCommand::Other
+            }
+            _ => PendingCommand::Other,
+        };
+        self.pending.push_back(pending);
+    }
+
+    fn process_response(&mut self, data: &str) {
+        let Some(captures) = RESPONSE_REGEX.captures(data) else {
+            return;
+        };
+
+        // Multiline reply: only the last line completes the command
+        if &captures[2] == "-" {
+            return;
+        }
+
+        let code: i32 = captures[1].parse().unwrap_or_default();
+        // Intermediate reply (e.g. 334 for AUTH, 354 for DATA): command is still in progress
+        if matches!(code / 100, 1 | 3) {
+            return;
+        }
+
+        match self.pending.pop_front() {
+            Some(PendingCommand::Mail(index)) => self.set_response(index, code, data),
+            Some(PendingCommand::Rcpt(recipient, index)) => {
+                self.recipients[recipient].response_code = Some(code);
+                self.set_response(index, code, data);
+            }
+            Some(PendingCommand::Data(index)) => {
+                self.set_response(index, code, data);
+                let transaction = &mut self.transactions[index];
+                if transaction.message_id.is_none() {
+                    transaction.message_id = MESSAGE_ID_REGEX
+                        .captures(data)
+                        .map(|captures| captures[1].to_string());
+                }
+                if self.current == Some(index) {
+                    self.current = None;
+                }
+            }
+            Some(PendingCommand::Other) | None => {}
+        }
+    }
+
+    /// Returns the first recipient of the given transaction
+    pub fn first_recipient(&self, transaction: &SmtpTransaction) -> Option<String> {
+        self.recipients
+            .iter()
+            .find(|recipient| recipient.transaction_index == transaction.transaction_index)
+            .map(|recipient| recipient.recipient.clone())
+    }
+
+    /// Records the latest response of a transaction; the `DATA` response ends up being the final one
+    fn set_response(&mut self, index: usize, code: i32, data: &str) {
+        let transaction = &mut self.transactions[index];
+        transaction.response_code = Some(code);
+        transaction.response = Some(data.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds a SMTP Receive session: `<` lines come from the client, `>` lines from the server
+    fn receive(lines: &[(&str, &str)]) -> SmtpConversation {
+        let mut conversation = SmtpConversation::new(SmtpDirection::Receive);
+        for (sequence_number, (marker, data)) in lines.iter().enumerate() {
+            conversation.process(&SmtpEvent {
+                id: None,
+                date_time: DateTime::from_timestamp(1_700_000_000 + sequence_number as i64, 0)
+                    .unwrap(),
+                connector_id: "MAIL01\\Default Frontend MAIL01".into(),
+                session_id: "08DC1234ABCD5678".into(),
+                sequence_number: sequence_number as i32,
+                local_endpoint: "10.0.0.1:25".into(),
+                remote_endpoint: "10.0.0.2:50000".into(),
+                event: marker.to_string(),
+                data: Some(data.to_string()),
+                context: None,
+            });
+        }
+        conversation
+    }
+
+    fn recipients(conversation: &SmtpConversation) -> Vec<(&str, i32)> {
+        conversation
+            .recipients
+            .iter()
+            .map(|recipient| (recipient.recipient.as_str(), recipient.transaction_index))
+            .collect()
+    }
+
+    fn response_codes(conversation: &SmtpConversation) -> Vec<Option<i32>> {
+        conversation
+            .recipients
+            .iter()
+            .map(|recipient| recipient.response_code)
+            .collect()
+    }
+
+    #[test]
+    fn matches_pipelined_responses_in_order() {
+        let conversation = receive(&[
+            ("<", "MAIL FROM:<sender@example.com> SIZE=1024"),
+            ("<", "RCPT TO:<first@example.com>"),
+            ("<", "RCPT TO:<second@example.com>"),
+            ("<", "DATA"),
+            (">", "250 2.1.0 Sender OK"),
+            (">", "250 2.1.5 Recipient OK"),
+            (">", "550 5.1.1 User unknown"),
+            (">", "354 Start mail input; end with <CRLF>.<CRLF>"),
+            (">", "250 2.6.0 <id@example.com> Queued mail for delivery"),
+        ]);
+
+        assert_eq!(conversation.transactions.len(), 1);
+        let transaction = &conversation.transactions[0];
+        assert_eq!(transaction.sender.as_deref(), Some("sender@example.com"));
+        assert_eq!(transaction.size, Some(1024));
+        assert_eq!(transaction.recipient_count, 2);
+        assert_eq!(transaction.response_code, Some(250));
+        assert_eq!(
+            transaction.response.as_deref(),
+            Some("250 2.6.0 <id@example.com> Queued mail for delivery")
+        );
+        assert_eq!(response_codes(&conversation), vec![Some(250), Some(550)]);
+    }
+
+    #[test]
+    fn keeps_message_size_beyond_32_bits() {
+        let conversation = receive(&[
+            (
+                "<",
+                "MAIL FROM:<sender@example.com> SIZE=5368709120 BODY=8BITMIME",
+            ),
+            (">", "250 2.1.0 Sender OK"),
+        ]);
+
+        assert_eq!(conversation.transactions[0].size, Some(5_368_709_120));
+    }
+
+    #[test]
+    fn keeps_command_pending_until_last_line_of_multiline_reply() {
+        let conversation = receive(&[
+            ("<", "MAIL FROM:<sender@example.com>"),
+            (">", "250-2.1.0 Sender OK"),
+            (">", "250 2.1.0 Sender OK"),
+            ("<", "RCPT TO:<first@example.com>"),
+            (">", "250 2.1.5 Recipient OK"),
+        ]);
+
+        assert_eq!(response_codes(&conversation), vec![Some(250)]);
+    }
+
+    #[test]
+    fn starts_new_transaction_after_rset() {
+        let conversation = receive(&[
+            ("<", "MAIL FROM:<first@example.com>"),
+            (">", "250 2.1.0 Sender OK"),
+            ("<", "RCPT TO:<rejected@example.com>"),
+            (">", "550 5.1.1 User unknown"),
+            ("<", "RSET"),
+            (">", "250 2.0.0 Resetting"),
+            ("<", "RCPT TO:<orphan@example.com>"),
+            (">", "503 5.5.1 Need MAIL command"),
+            ("<", "MAIL FROM:<second@example.com>"),
+            (">", "250 2.1.0 Sender OK"),
+            ("<", "RCPT TO:<accepted@example.com>"),
+            (">", "250 2.1.5 Recipient OK"),
+            ("<", "DATA"),
+            (">", "354 Start mail input"),
+            (">", "250 2.6.0 <id@example.com> Queued mail for delivery"),
+        ]);
+
+        assert_eq!(conversation.transactions.len(), 2);
+        let first = &conversation.transactions[0];
+        assert_eq!(first.recipient_count, 1);
+        assert_eq!(first.response_code, Some(550));
+        assert_eq!(first.message_id, None);
+
+        let second = &conversation.transactions[1];
+        assert_eq!(second.transaction_index, 1);
+        assert_eq!(second.recipient_count, 1);
+        assert_eq!(second.response_code, Some(250));
+
+        // The RCPT between RSET and MAIL does not belong to any transaction and is not stored
+        assert_eq!(
+            recipients(&conversation),
+            vec![("rejected@example.com", 0), ("accepted@example.com", 1)]
+        );
+        assert_eq!(response_codes(&conversation), vec![Some(550), Some(250)]);
+    }
+
+    #[test]
+    fn skips_recipients_before_mail() {
+        let conversation = receive(&[
+            ("<", "RCPT TO:<early@example.com>"),
+            (">", "503 5.5.1 Need MAIL command"),
+            ("<", "MAIL FROM:<sender@example.com>"),
+            (">", "250 2.1.0 Sender OK"),
+            ("<", "RCPT TO:<first@example.com>"),
+            (">", "250 2.1.5 Recipient OK"),
+        ]);
+
+        assert_eq!(recipients(&conversation), vec![("first@example.com", 0)]);
+        assert_eq!(conversation.transactions[0].response_code, Some(250));
+    }
+
+    #[test]
+    fn takes_message_id_from_data_reply() {
+        let conversation = receive(&[
+            ("<", "MAIL FROM:<sender@example.com>"),
+            (">", "250 2.1.0 Sender OK"),
+            ("<", "RCPT TO:<first@example.com>"),
+            (">", "250 2.1.5 Recipient OK"),
+            ("<", "DATA"),
+            (">", "354 Start mail input"),
+            (
+                ">",
+                "250 2.6.0 <AM0PR01MB1234.eurprd01.prod.example.com> [InternalId=1] Queued mail for delivery",
+            ),
+        ]);
+
+        assert_eq!(
+            conversation.transactions[0].message_id.as_deref(),
+            Some("AM0PR01MB1234.eurprd01.prod.example.com")
+        );
+    }
+
+    #[test]
+    fn ends_transaction_with_bdat_last() {
+        let conversation = receive(&[
+            ("<", "MAIL FROM:<sender@example.com>"),
+            ("<", "RCPT TO:<first@example.com>"),
+            ("<", "BDAT 4096"),
+            (">", "250 2.1.0 Sender OK"),
+            (">", "250 2.1.5 Recipient OK"),
+            (">", "250 2.0.0 4096 bytes received"),
+            ("<", "BDAT 100 LAST"),
+            (">", "250 2.6.0 <id@example.com> Queued mail for delivery"),
+            ("<", "RCPT TO:<late@example.com>"),
+            (">", "503 5.5.1 Need MAIL command"),
+        ]);
+
+        assert_eq!(conversation.transactions.len(), 1);
+        let transaction = &conversation.transactions[0];
+        assert_eq!(transaction.message_id.as_deref(), Some("id@example.com"));
+        assert_eq!(transaction.response_code, Some(250));
+        // The transaction is over, a later RCPT is neither its recipient nor stored
+        assert_eq!(transaction.recipient_count, 1);
+        assert_eq!(recipients(&conversation), vec![("first@example.com", 0)]);
+        assert_eq!(response_codes(&conversation), vec![Some(250)]);
+    }
+
+    /// Field indices of the `#Fields:` header of SMTP protocol logs
+    fn indices() -> HashMap<String, usize> {
+        "date-time,connector-id,session-id,sequence-number,local-endpoint,remote-endpoint,event,data,context"
+            .split(',')
+            .enumerate()
+            .map(|(i, field)| (field.to_string(), i))
+            .collect()
+    }
+
+    fn line(session: &str, sequence_number: i32, event: &str) -> String {
+        format!(
+            "2024-01-15T14:00:00.000Z,MAIL01\\Default,{},{},10.0.0.1:25,10.0.0.2:50000,{},,",
+            session, sequence_number, event
+        )
+    }
+
+    #[test]
+    fn emits_every_event_and_the_session_records_on_disconnect() {
+        let lines = [
+            "2024-01-15T14:00:00.000Z,MAIL01\\Default,A,0,10.0.0.1:25,10.0.0.2:50000,+,,",
+            "2024-01-15T14:00:01.000Z,MAIL01\\Default,A,1,10.0.0.1:25,10.0.0.2:50000,<,MAIL FROM:<sender@example.com> SIZE=2048,",
+            "2024-01-15T14:00:01.000Z,MAIL01\\Default,A,2,10.0.0.1:25,10.0.0.2:50000,>,\"250 2.1.0 Sender OK\",",
+            "2024-01-15T14:00:02.000Z,MAIL01\\Default,A,3,10.0.0.1:25,10.0.0.2:50000,<,RCPT TO:<first@example.com>,",
+            "2024-01-15T14:00:02.000Z,MAIL01\\Default,A,4,10.0.0.1:25,10.0.0.2:50000,>,250 2.1.5 Recipient OK,",
+            "2024-01-15T14:00:03.000Z,MAIL01\\Default,A,5,10.0.0.1:25,10.0.0.2:50000,*,,\"Set Session Permissions, SMTPSubmit\"",
+            "2024-01-15T14:00:04.000Z,MAIL01\\Default,A,6,10.0.0.1:25,10.0.0.2:50000,-,,Local",
+        ];
+
+        let indices = indices();
+        let mut parser = SmtpLogParser::<SmtpReceiveLog>::new();
+        let mut output = VecDeque::new();
+        for (i, line) in lines.iter().enumerate() {
+            let start = FilePosition {
+                offset: i as u64 * 100,
+                line: i,
+            };
+            parser
+                .parse_line(start, line, &indices, &mut output)
+                .unwrap();
+            if i < lines.len() - 1 {
+                // Every line is an event of its own, emitted as soon as it is read
+                assert_eq!(output.len(), i + 1);
+            }
+        }
+
+        let events: Vec<&SmtpEvent> = output
+            .iter()
+            .filter_map(|record| match record {
+                LogRecord::SmtpEvent(SmtpDirection::Receive, event) => Some(event),
+                _ => None,
+            })
+            .collect();
+        let sequence: Vec<(i32, &str)> = events
+            .iter()
+            .map(|event| (event.sequence_number, event.event.as_str()))
+            .collect();
+        assert_eq!(
+            sequence,
+            vec![
+                (0, "+"),
+                (1, "<"),
+                (2, ">"),
+                (3, "<"),
+                (4, ">"),
+                (5, "*"),
+                (6, "-")
+            ]
+        );
+        assert_eq!(events[0].data, None);
+        assert_eq!(events[2].data.as_deref(), Some("250 2.1.0 Sender OK"));
+        assert_eq!(
+            events[5].context.as_deref(),
+            Some("Set Session Permissions, SMTPSubmit")
+        );
+
+        // The session summary, its transaction and recipient follow the last event
+        let records: Vec<&LogRecord> = output.iter().skip(lines.len()).collect();
+        let [
+            LogRecord::SmtpReceiveSession(session),
+            LogRecord::SmtpTransaction(SmtpDirection::Receive, transaction),
+            LogRecord::SmtpRecipient(SmtpDirection::Receive, recipient),
+        ] = records.as_slice()
+        else {
+            panic!("unexpected session records: {:?}", records);
+        };
+        assert_eq!(session.session_id, "A");
+        assert_eq!(session.sender.as_deref(), Some("sender@example.com"));
+        assert_eq!(session.recipient.as_deref(), Some("first@example.com"));
+        assert_eq!(session.size, Some(2048));
+        assert_eq!(transaction.recipient_count, 1);
+        assert_eq!(recipient.response_code, Some(250));
+        assert!(parser.oldest_open().is_none());
+    }
+
+    #[test]
+    fn reports_first_line_of_oldest_open_session() {
+        let indices = indices();
+        let mut parser = SmtpLogParser::<SmtpReceiveLog>::new();
+        let mut output = VecDeque::new();
+        let mut feed = |offset: u64, line: String| {
+            let start = FilePosition {
+                offset,
+                line: offset as usize,
+            };
+            parser
+                .parse_line(start, &line, &indices, &mut output)
+                .unwrap();
+            parser.oldest_open().map(|start| start.offset)
+        };
+
+        assert_eq!(feed(10, line("A", 0, "+")), Some(10));
+        assert_eq!(feed(20, line("B", 0, "+")), Some(10));
+        assert_eq!(feed(30, line("A", 1, "<")), Some(10));
+        // Once A disconnects, B is the oldest session still open
+        assert_eq!(feed(40, line("A", 2, "-")), Some(20));
+        assert_eq!(feed(50, line("B", 1, "-")), None);
+    }
+}
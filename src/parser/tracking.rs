@@ -0,0 +1,118 @@
+use super::fields::split_fields;
+use super::{FilePosition, RecordParser};
+use crate::models::{LogRecord, MessageTrackingLog};
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Result, eyre};
+use std::collections::{HashMap, VecDeque};
+
+/// Parser of Message Tracking logs, one record per line
+pub struct MessageTrackingParser;
+
+impl RecordParser for MessageTrackingParser {
+    fn parse_line(
+        &mut self,
+        _start: FilePosition,
+        line: &str,
+        indices: &HashMap<String, usize>,
+        output: &mut VecDeque<LogRecord>,
+    ) -> Result<()> {
+        let parts = split_fields(line)?;
+        if parts.len() < indices.len() {
+            return Err(eyre!("Line has fewer parts than expected fields"));
+        }
+
+        let date_time = DateTime::parse_from_rfc3339(&parts[indices["date-time"]])
+            .map_err(|e| eyre!("Failed to parse date: {}", e))?
+            .with_timezone(&Utc);
+
+        let get_field = |field: &str| -> Option<String> {
+            indices
+                .get(field)
+                .and_then(|&idx| parts.get(idx))
+                .filter(|s| !s.is_empty())
+                .cloned()
+        };
+
+        let get_required_field = |field: &str| -> String {
+            indices
+                .get(field)
+                .and_then(|&idx| parts.get(idx))
+                .cloned()
+                .unwrap_or_default()
+        };
+
+        output.push_back(LogRecord::MessageTracking(Box::new(MessageTrackingLog {
+            id: None,
+            date_time,
+            client_ip: get_field("client-ip"),
+            client_hostname: get_field("client-hostname"),
+            server_ip: get_field("server-ip"),
+            server_hostname: get_required_field("server-hostname"),
+            source_context: get_field("source-context"),
+            connector_id: get_field("connector-id"),
+            source: get_field("source"),
+            event_id: get_required_field("event-id"),
+            internal_message_id: get_required_field("internal-message-id"),
+            message_id: get_required_field("message-id"),
+            network_message_id: get_required_field("network-message-id"),
+            recipient_address: get_required_field("recipient-address"),
+            recipient_status: get_field("recipient-status"),
+            total_bytes: get_field("total-bytes").and_then(|s| s.parse::<i64>().ok()),
+            recipient_count: get_field("recipient-count")
+                .and_then(|s| s.parse::<i32>().ok())
+                .unwrap_or(0),
+            related_recipient_address: get_field("related-recipient-address"),
+            reference: get_field("reference"),
+            message_subject: get_field("message-subject"),
+            sender_address: get_required_field("sender-address"),
+            return_path: get_field("return-path"),
+            message_info: get_field("message-info"),
+            directionality: get_field("directionality"),
+            tenant_id: get_field("tenant-id"),
+            original_client_ip: get_field("original-client-ip"),
+            original_server_ip: get_field("original-server-ip"),
+            custom_data: get_field("custom-data"),
+            transport_traffic_type: get_field("transport-traffic-type"),
+            log_id: get_field("log-id"),
+            schema_version: get_field("schema-version"),
+        })));
+        Ok(())
+    }
+
+    fn finish(&mut self, _output: &mut VecDeque<LogRecord>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_message_sizes_beyond_32_bits() {
+        let indices: HashMap<String, usize> = [
+            "date-time",
+            "server-hostname",
+            "event-id",
+            "message-id",
+            "recipient-address",
+            "total-bytes",
+            "recipient-count",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, field)| (field.to_string(), i))
+        .collect();
+        let line = "2024-01-15T14:00:00.000Z,MAIL01,RECEIVE,<id@example.com>,user@example.com,5368709120,1";
+
+        let mut output = VecDeque::new();
+        MessageTrackingParser
+            .parse_line(FilePosition::default(), line, &indices, &mut output)
+            .unwrap();
+
+        let Some(LogRecord::MessageTracking(log)) = output.pop_front() else {
+            panic!("expected a message tracking record");
+        };
+        assert_eq!(log.total_bytes, Some(5_368_709_120));
+        assert_eq!(log.recipient_count, 1);
+        assert_eq!(log.message_id, "<id@example.com>");
+    }
+}
@@ -0,0 +1,146 @@
+/// Type of a column of an exported table
+#[derive(Debug, Clone, Copy)]
+pub enum ColumnType {
+    Timestamp,
+    Text,
+    Int32,
+    Int64,
+}
+
+/// Column of an exported table
+#[derive(Debug, Clone, Copy)]
+pub struct Column {
+    pub name: &'static str,
+    pub column_type: ColumnType,
+    pub nullable: bool,
+}
+
+const fn column(name: &'static str, column_type: ColumnType) -> Column {
+    Column {
+        name,
+        column_type,
+        nullable: false,
+    }
+}
+
+const fn optional(name: &'static str, column_type: ColumnType) -> Column {
+    Column {
+        name,
+        column_type,
+        nullable: true,
+    }
+}
+
+use ColumnType::{Int32, Int64, Text, Timestamp};
+
+pub const SMTP_RECEIVE_LOG_COLUMNS: &[Column] = &[
+    column("date_time", Timestamp),
+    column("connector_id", Text),
+    column("session_id", Text),
+    column("sequence_number", Int32),
+    column("local_endpoint", Text),
+    column("remote_endpoint", Text),
+    column("event", Text),
+    optional("data", Text),
+    optional("context", Text),
+    optional("sender", Text),
+    optional("recipient", Text),
+    optional("message_id", Text),
+    optional("subject", Text),
+    optional("size", Int64),
+];
+
+pub const SMTP_SEND_LOG_COLUMNS: &[Column] = &[
+    column("date_time", Timestamp),
+    column("connector_id", Text),
+    column("session_id", Text),
+    column("sequence_number", Int32),
+    column("local_endpoint", Text),
+    column("remote_endpoint", Text),
+    column("event", Text),
+    optional("data", Text),
+    optional("context", Text),
+    optional("proxy_session_id", Text),
+    optional("sender", Text),
+    optional("recipient", Text),
+    optional("message_id", Text),
+    optional("record_id", Text),
+];
+
+pub const SMTP_EVENT_COLUMNS: &[Column] = &[
+    column("date_time", Timestamp),
+    column("connector_id", Text),
+    column("session_id", Text),
+    column("sequence_number", Int32),
+    column("local_endpoint", Text),
+    column("remote_endpoint", Text),
+    column("event", Text),
+    optional("data", Text),
+    optional("context", Text),
+];
+
+pub const SMTP_TRANSACTION_COLUMNS: &[Column] = &[
+    column("date_time", Timestamp),
+    column("connector_id", Text),
+    column("session_id", Text),
+    column("transaction_index", Int32),
+    column("local_endpoint", Text),
+    column("remote_endpoint", Text),
+    optional("sender", Text),
+    column("recipient_count", Int32),
+    optional("size", Int64),
+    optional("message_id", Text),
+    optional("record_id", Text),
+    optional("response_code", Int32),
+    optional("response", Text),
+];
+
+pub const SMTP_RECIPIENT_COLUMNS: &[Column] = &[
+    column("date_time", Timestamp),
+    column("session_id", Text),
+    column("sequence_number", Int32),
+    column("transaction_index", Int32),
+    column("recipient", Text),
+    optional("response_code", Int32),
+];
+
+pub const MESSAGE_TRACKING_LOG_COLUMNS: &[Column] = &[
+    column("date_time", Timestamp),
+    optional("client_ip", Text),
+    optional("client_hostname", Text),
+    optional("server_ip", Text),
+    column("server_hostname", Text),
+    optional("source_context", Text),
+    optional("connector_id", Text),
+    optional("source", Text),
+    column("event_id", Text),
+    column("internal_message_id", Text),
+    column("message_id", Text),
+    column("network_message_id", Text),
+    column("recipient_address", Text),
+    optional("recipient_status", Text),
+    optional("total_bytes", Int64),
+    column("recipient_count", Int32),
+    optional("related_recipient_address", Text),
+    optional("reference", Text),
+    optional("message_subject", Text),
+    column("sender_address", Text),
+    optional("return_path", Text),
+    optional("message_info", Text),
+    optional("directionality", Text),
+    optional("tenant_id", Text),
+    optional("original_client_ip", Text),
+    optional("original_server_ip", Text),
+    optional("custom_data", Text),
+    optional("transport_traffic_type", Text),
+    optional("log_id", Text),
+    optional("schema_version", Text),
+];
+
+pub const REJECTED_LINE_COLUMNS: &[Column] = &[
+    column("rejected_at", Timestamp),
+    column("file_path", Text),
+    column("line_number", Int32),
+    column("line", Text),
+    column("reason", Text),
+];
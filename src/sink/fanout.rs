@@ -0,0 +1,569 @@
+use crate::database::migrations::Migration;
+use crate::database::{Database, InsertStats};
+use crate::journal::{self, FileState};
+use crate::models::{
+    IngestedFile, MessageTrackingLog, RecordBatch, RejectedLine, SmtpDirection, SmtpEvent,
+    SmtpReceiveLog, SmtpRecipient, SmtpSendLog, SmtpTransaction,
+};
+use async_trait::async_trait;
+use color_eyre::eyre::{Result, eyre};
+use futures::future::join_all;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// What to do when one of several outputs fails
+///
+/// This enum is used to choose with `--on-sink-error` whether a failing output
+/// stops the processing of the file or is skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkErrorPolicy {
+    /// The file is counted as failed and is processed again on the next run
+    Fail,
+    /// The error is logged and counted, the other outputs receive the records
+    Continue,
+}
+
+impl std::str::FromStr for SinkErrorPolicy {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fail" => Ok(SinkErrorPolicy::Fail),
+            "continue" => Ok(SinkErrorPolicy::Continue),
+            _ => Err(eyre!("Неизвестная политика ошибок выхода: {}", s)),
+        }
+    }
+}
+
+/// Rows delivered to one output and errors of the output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SinkStats {
+    pub inserted: u64,
+    pub duplicates: u64,
+    pub errors: u64,
+}
+
+/// Output with its name and counters
+struct Sink {
+    name: String,
+    db: Box<dyn Database>,
+    stats: Mutex<SinkStats>,
+}
+
+/// Delivery of the records to several outputs at once
+///
+/// This struct is used to fill e.g. a reporting database and an archive of
+/// files in a single run. Every batch is sent to all outputs concurrently.
+///
+/// The ingestion journal is read from all outputs and the least advanced entry
+/// is used, so an output added later receives the files from the start; the
+/// outputs that already have the records skip them as duplicates (files get
+/// them again). The journal is saved to every output, except the outputs that
+/// failed to receive a batch of the file under `SinkErrorPolicy::Continue`: their
+/// entry stays where it was, so the next run sends them the file again.
+///
+/// ### Examples
+///
+/// ```
+/// let db = FanOut::new(vec![("postgres".into(), postgres), ("jsonl".into(), files)], SinkErrorPolicy::Fail);
+/// db.insert_batch(batch).await?;
+/// ```
+pub struct FanOut {
+    sinks: Vec<Sink>,
+    policy: SinkErrorPolicy,
+    /// Indices of the outputs that failed a batch, by journal path of the file,
+    /// until the file is opened again
+    failed: Mutex<HashMap<String, HashSet<usize>>>,
+}
+
+impl FanOut {
+    pub fn new(sinks: Vec<(String, Box<dyn Database>)>, policy: SinkErrorPolicy) -> Self {
+        FanOut {
+            sinks: sinks
+                .into_iter()
+                .map(|(name, db)| Sink {
+                    name,
+                    db,
+                    stats: Mutex::new(SinkStats::default()),
+                })
+                .collect(),
+            policy,
+            failed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the outputs with their names
+    pub fn sinks(&self) -> impl Iterator<Item = (&str, &dyn Database)> {
+        self.sinks
+            .iter()
+            .map(|sink| (sink.name.as_str(), sink.db.as_ref()))
+    }
+
+    /// Returns the counters of every output
+    pub fn statistics(&self) -> Vec<(String, SinkStats)> {
+        self.sinks
+            .iter()
+            .map(|sink| (sink.name.clone(), *sink.stats.lock().unwrap()))
+            .collect()
+    }
+
+    /// Applies the error policy to the results of an operation on every output
+    ///
+    /// Returns the results of the outputs that succeeded, `None` for those that
+    /// failed. Fails if the policy is `Fail` and an output failed, or if all
+    /// outputs failed.
+    fn settle<R>(&self, operation: &str, results: Vec<Result<R>>) -> Result<Vec<Option<R>>> {
+        let mut settled = Vec::with_capacity(results.len());
+        let mut first_error = None;
+        for (sink, result) in self.sinks.iter().zip(results) {
+            match result {
+                Ok(value) => settled.push(Some(value)),
+                Err(e) => {
+                    sink.stats.lock().unwrap().errors += 1;
+                    warn!("Output {} failed to {}: {}", sink.name, operation, e);
+                    first_error.get_or_insert((sink.name.clone(), e));
+                    settled.push(None);
+                }
+            }
+        }
+
+        match first_error {
+            Some((name, e))
+                if self.policy == SinkErrorPolicy::Fail || settled.iter().all(Option::is_none) =>
+            {
+                Err(e.wrap_err(format!("Ошибка выхода {}", name)))
+            }
+            _ => Ok(settled),
+        }
+    }
+
+    /// Returns the result of the first output that succeeded
+    fn first<R: Default>(results: Vec<Option<R>>) -> R {
+        results.into_iter().flatten().next().unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl Database for FanOut {
+    /// Returns the lowest schema version of the outputs
+    async fn schema_version(&self) -> Result<i32> {
+        let mut lowest = i32::MAX;
+        for sink in &self.sinks {
+            lowest = lowest.min(sink.db.schema_version().await?);
+        }
+        Ok(if self.sinks.is_empty() { 0 } else { lowest })
+    }
+
+    /// Migrations belong to the outputs, see `migrate`
+    fn migrations(&self) -> Vec<Migration> {
+        Vec::new()
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        Err(eyre!(
+            "Migration {} has to be applied to a single output",
+            migration.version
+        ))
+    }
+
+    /// Applies the pending migrations of every output
+    async fn migrate(&self) -> Result<usize> {
+        let mut applied = 0;
+        for sink in &self.sinks {
+            let count = sink.db.migrate().await?;
+            if count > 0 {
+                info!("Applied {} migrations to {}", count, sink.name);
+            }
+            applied += count;
+        }
+        Ok(applied)
+    }
+
+    async fn insert_smtp_receive_logs(&self, logs: Vec<SmtpReceiveLog>) -> Result<u64> {
+        let results = join_all(
+            self.sinks
+                .iter()
+                .map(|sink| sink.db.insert_smtp_receive_logs(logs.clone())),
+        )
+        .await;
+        Ok(Self::first(self.settle("insert rows", results)?))
+    }
+
+    async fn insert_smtp_send_logs(&self, logs: Vec<SmtpSendLog>) -> Result<u64> {
+        let results = join_all(
+            self.sinks
+                .iter()
+                .map(|sink| sink.db.insert_smtp_send_logs(logs.clone())),
+        )
+        .await;
+        Ok(Self::first(self.settle("insert rows", results)?))
+    }
+
+    async fn insert_smtp_events(
+        &self,
+        direction: SmtpDirection,
+        events: Vec<SmtpEvent>,
+    ) -> Result<u64> {
+        let results = join_all(
+            self.sinks
+                .iter()
+                .map(|sink| sink.db.insert_smtp_events(direction, events.clone())),
+        )
+        .await;
+        Ok(Self::first(self.settle("insert rows", results)?))
+    }
+
+    async fn insert_smtp_transactions(
+        &self,
+        direction: SmtpDirection,
+        transactions: Vec<SmtpTransaction>,
+    ) -> Result<u64> {
+        let results = join_all(self.sinks.iter().map(|sink| {
+            sink.db
+                .insert_smtp_transactions(direction, transactions.clone())
+        }))
+        .await;
+        Ok(Self::first(self.settle("insert rows", results)?))
+    }
+
+    async fn insert_smtp_recipients(
+        &self,
+        direction: SmtpDirection,
+        recipients: Vec<SmtpRecipient>,
+    ) -> Result<u64> {
+        let results = join_all(self.sinks.iter().map(|sink| {
+            sink.db
+                .insert_smtp_recipients(direction, recipients.clone())
+        }))
+        .await;
+        Ok(Self::first(self.settle("insert rows", results)?))
+    }
+
+    async fn insert_message_tracking_logs(&self, logs: Vec<MessageTrackingLog>) -> Result<u64> {
+        let results = join_all(
+            self.sinks
+                .iter()
+                .map(|sink| sink.db.insert_message_tracking_logs(logs.clone())),
+        )
+        .await;
+        Ok(Self::first(self.settle("insert rows", results)?))
+    }
+
+    async fn insert_rejected_lines(&self, lines: Vec<RejectedLine>) -> Result<u64> {
+        let results = join_all(
+            self.sinks
+                .iter()
+                .map(|sink| sink.db.insert_rejected_lines(lines.clone())),
+        )
+        .await;
+        Ok(Self::first(self.settle("insert rows", results)?))
+    }
+
+    /// Returns the least advanced journal entry of the outputs
+    ///
+    /// An output without an entry, or with an entry of a file that was replaced
+    /// since, needs the whole file, so no entry is returned then. The file is read
+    /// again from the returned entry, which resends the batches lost by the outputs
+    /// that failed, so the journal is saved to them again from now on.
+    async fn get_ingested_file(&self, path: &str) -> Result<Option<IngestedFile>> {
+        self.failed.lock().unwrap().remove(path);
+
+        let results = join_all(
+            self.sinks
+                .iter()
+                .map(|sink| sink.db.get_ingested_file(path)),
+        )
+        .await;
+
+        let Some(entries) = self
+            .settle("read the ingestion journal", results)?
+            .into_iter()
+            .flatten()
+            .collect::<Option<Vec<IngestedFile>>>()
+        else {
+            return Ok(None);
+        };
+
+        let size = FileState::read(Path::new(path)).await?.size;
+        for entry in &entries {
+            if !journal::matches(Path::new(path), size, entry).await? {
+                return Ok(None);
+            }
+        }
+        Ok(entries.into_iter().min_by_key(|entry| entry.byte_offset))
+    }
+
+    /// Saves the journal entry to the outputs that received every batch of the file
+    async fn save_ingested_file(&self, file: IngestedFile) -> Result<()> {
+        let failed = self
+            .failed
+            .lock()
+            .unwrap()
+            .get(&file.path)
+            .cloned()
+            .unwrap_or_default();
+        let results = join_all(self.sinks.iter().enumerate().map(|(index, sink)| {
+            let file = file.clone();
+            let skip = failed.contains(&index);
+            async move {
+                if skip {
+                    warn!(
+                        "Not saving the ingestion journal of {} to output {}: records were lost",
+                        file.path, sink.name
+                    );
+                    return Ok(());
+                }
+                sink.db.save_ingested_file(file).await
+            }
+        }))
+        .await;
+        self.settle("save the ingestion journal", results)?;
+        Ok(())
+    }
+
+    /// Sends a batch to every output concurrently
+    ///
+    /// Returns the rows inserted into the first output that succeeded; the rows
+    /// of every output are counted in `statistics`.
+    async fn insert_batch(&self, batch: RecordBatch) -> Result<InsertStats> {
+        let results = join_all(
+            self.sinks
+                .iter()
+                .map(|sink| sink.db.insert_batch(batch.clone())),
+        )
+        .await;
+
+        for (sink, result) in self.sinks.iter().zip(&results) {
+            if let Ok(rows) = result {
+                let mut stats = sink.stats.lock().unwrap();
+                stats.inserted += rows.inserted;
+                stats.duplicates += rows.duplicates;
+            }
+        }
+
+        let settled = self.settle("insert rows", results)?;
+        // The journal of the file must not move past the records an output did not receive
+        if let Some(source) = &batch.source {
+            let failed: Vec<usize> = settled
+                .iter()
+                .enumerate()
+                .filter(|(_, rows)| rows.is_none())
+                .map(|(index, _)| index)
+                .collect();
+            if !failed.is_empty() {
+                self.failed
+                    .lock()
+                    .unwrap()
+                    .entry(source.clone())
+                    .or_default()
+                    .extend(failed);
+            }
+        }
+        Ok(Self::first(settled))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::memory::MemoryDatabase;
+    use chrono::Utc;
+    use std::path::PathBuf;
+    use std::sync::atomic::Ordering;
+
+    const LOG: &str = "#Fields: date-time,session-id\n2024-01-15T14:00:00.000Z,08DC1234ABCD5678\n";
+
+    fn log_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "elp-fanout-test-{}-{}.log",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, LOG).unwrap();
+        path
+    }
+
+    fn fan_out(policy: SinkErrorPolicy) -> (FanOut, MemoryDatabase, MemoryDatabase) {
+        let (first, second) = (MemoryDatabase::default(), MemoryDatabase::default());
+        let fan_out = FanOut::new(
+            vec![
+                ("first".into(), Box::new(first.clone())),
+                ("second".into(), Box::new(second.clone())),
+            ],
+            policy,
+        );
+        (fan_out, first, second)
+    }
+
+    fn batch(path: &Path, line_count: i32) -> RecordBatch {
+        let mut batch = RecordBatch::for_file(path.to_str().unwrap());
+        batch.rejected_lines = (1..=line_count)
+            .map(|line_number| RejectedLine {
+                id: None,
+                rejected_at: Utc::now(),
+                file_path: path.display().to_string(),
+                line_number,
+                line: "garbage".into(),
+                reason: "Line has fewer parts than expected fields".into(),
+            })
+            .collect();
+        batch
+    }
+
+    async fn entry(path: &Path, byte_offset: i64) -> IngestedFile {
+        let size = std::fs::metadata(path).unwrap().len();
+        IngestedFile {
+            id: None,
+            path: path.to_str().unwrap().into(),
+            size: size as i64,
+            modified_at: Utc::now(),
+            content_hash: journal::content_hash(path, size).await.unwrap(),
+            byte_offset,
+            line_number: 2,
+            log_type: "SmtpReceive".into(),
+            record_count: 2,
+            ingested_at: Utc::now(),
+        }
+    }
+
+    fn journal_of(db: &MemoryDatabase, path: &Path) -> Option<i64> {
+        db.journal
+            .lock()
+            .unwrap()
+            .get(path.to_str().unwrap())
+            .map(|entry| entry.byte_offset)
+    }
+
+    #[tokio::test]
+    async fn continues_past_failing_output_and_withholds_its_journal() {
+        let path = log_file("continue");
+        let (fan_out, first, second) = fan_out(SinkErrorPolicy::Continue);
+        second.failing.store(true, Ordering::SeqCst);
+
+        let stats = fan_out.insert_batch(batch(&path, 2)).await.unwrap();
+        assert_eq!(stats.inserted, 2);
+        fan_out
+            .save_ingested_file(entry(&path, 60).await)
+            .await
+            .unwrap();
+        assert_eq!(journal_of(&first, &path), Some(60));
+        assert_eq!(journal_of(&second, &path), None);
+
+        // The output is back, but the batch it lost has not been sent again yet
+        second.failing.store(false, Ordering::SeqCst);
+        fan_out.insert_batch(batch(&path, 1)).await.unwrap();
+        fan_out
+            .save_ingested_file(entry(&path, 80).await)
+            .await
+            .unwrap();
+        assert_eq!(*second.rows.lock().unwrap(), 1);
+        assert_eq!(journal_of(&first, &path), Some(80));
+        assert_eq!(journal_of(&second, &path), None);
+
+        let statistics = fan_out.statistics();
+        assert_eq!(
+            statistics[0].1,
+            SinkStats {
+                inserted: 3,
+                duplicates: 0,
+                errors: 0
+            }
+        );
+        assert_eq!(
+            statistics[1].1,
+            SinkStats {
+                inserted: 1,
+                duplicates: 0,
+                errors: 1
+            }
+        );
+
+        // Opened again, the file is read from the start for the output without an
+        // entry, after which the journal is saved to both outputs
+        assert!(
+            fan_out
+                .get_ingested_file(path.to_str().unwrap())
+                .await
+                .unwrap()
+                .is_none()
+        );
+        fan_out.insert_batch(batch(&path, 3)).await.unwrap();
+        fan_out
+            .save_ingested_file(entry(&path, 80).await)
+            .await
+            .unwrap();
+        assert_eq!(journal_of(&second, &path), Some(80));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_with_failing_output_under_fail_policy() {
+        let path = log_file("fail");
+        let (fan_out, first, second) = fan_out(SinkErrorPolicy::Fail);
+        second.failing.store(true, Ordering::SeqCst);
+
+        assert!(fan_out.insert_batch(batch(&path, 2)).await.is_err());
+        // The other output received the batch, it skips it as duplicates next time
+        assert_eq!(*first.rows.lock().unwrap(), 2);
+        assert_eq!(fan_out.statistics()[1].1.errors, 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_when_every_output_fails_under_continue_policy() {
+        let path = log_file("all-fail");
+        let (fan_out, first, second) = fan_out(SinkErrorPolicy::Continue);
+        first.failing.store(true, Ordering::SeqCst);
+        second.failing.store(true, Ordering::SeqCst);
+
+        assert!(fan_out.insert_batch(batch(&path, 1)).await.is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resumes_from_least_advanced_entry_of_the_same_file() {
+        let path = log_file("resume");
+        let journal_path = path.to_str().unwrap();
+        let (fan_out, first, second) = fan_out(SinkErrorPolicy::Fail);
+
+        first
+            .save_ingested_file(entry(&path, 80).await)
+            .await
+            .unwrap();
+        assert!(
+            fan_out
+                .get_ingested_file(journal_path)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        second
+            .save_ingested_file(entry(&path, 30).await)
+            .await
+            .unwrap();
+        let resumed = fan_out.get_ingested_file(journal_path).await.unwrap();
+        assert_eq!(resumed.map(|entry| entry.byte_offset), Some(30));
+
+        // An entry of a file replaced since then does not count
+        std::fs::write(&path, LOG.replace("08DC", "08DD")).unwrap();
+        first
+            .save_ingested_file(entry(&path, 80).await)
+            .await
+            .unwrap();
+        assert!(
+            fan_out
+                .get_ingested_file(journal_path)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,502 @@
+use crate::database::Database;
+use crate::database::migrations::Migration;
+use crate::models::{
+    IngestedFile, MessageTrackingLog, RejectedLine, SmtpDirection, SmtpEvent, SmtpReceiveLog,
+    SmtpRecipient, SmtpSendLog, SmtpTransaction,
+};
+use arrow_json::ReaderBuilder;
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use color_eyre::eyre::{Result, eyre};
+use log::{debug, info};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::OutputFormat;
+use super::columns::{
+    Column, ColumnType, MESSAGE_TRACKING_LOG_COLUMNS, REJECTED_LINE_COLUMNS, SMTP_EVENT_COLUMNS,
+    SMTP_RECEIVE_LOG_COLUMNS, SMTP_RECIPIENT_COLUMNS, SMTP_SEND_LOG_COLUMNS,
+    SMTP_TRANSACTION_COLUMNS,
+};
+use super::journal_file::JournalFile;
+
+/// Name of the file with the ingestion journal in the output directory
+const JOURNAL_FILE_NAME: &str = "ingested_files.json";
+
+/// Builds the Arrow schema of a table from its columns
+fn arrow_schema(columns: &[Column]) -> Schema {
+    Schema::new(
+        columns
+            .iter()
+            .map(|column| {
+                let data_type = match column.column_type {
+                    ColumnType::Timestamp => {
+                        DataType::Timestamp(TimeUnit::Microsecond, Some("+00:00".into()))
+                    }
+                    ColumnType::Text => DataType::Utf8,
+                    ColumnType::Int32 => DataType::Int32,
+                    ColumnType::Int64 => DataType::Int64,
+                };
+                Field::new(column.name, data_type, column.nullable)
+            })
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Export of parsed records to files instead of a database
+///
+/// This struct is used to feed other pipelines with normalized records. Every
+/// table becomes a directory in the output directory, and its records are split
+/// by day (UTC) of the record:
+///
+/// - JSON Lines and CSV: records are appended to `{table}/{YYYY-MM-DD}.jsonl` (`.csv`);
+/// - Parquet: every batch is written to a new file `{table}/{YYYY-MM-DD}/part-*.parquet`,
+///   since Parquet files cannot be appended to.
+///
+/// The ingestion journal is kept in `ingested_files.json` in the output directory.
+/// Files are not deduplicated: with `--force` the records are written again.
+///
+/// ### Examples
+///
+/// ```
+/// let sink = FileSink::new(OutputFormat::JsonLines, Path::new("/data/export"), None).await?;
+/// sink.insert_batch(batch).await?;
+/// ```
+pub struct FileSink {
+    format: OutputFormat,
+    output_dir: PathBuf,
+    table_prefix: String,
+    journal: JournalFile,
+    // Serializes the writes, so that concurrent batches never interleave in a file
+    write_lock: Arc<Mutex<()>>,
+    part_counter: Arc<AtomicU64>,
+}
+
+impl FileSink {
+    pub async fn new(
+        format: OutputFormat,
+        output_dir: &Path,
+        table_prefix: Option<&str>,
+    ) -> Result<Self> {
+        tokio::fs::create_dir_all(output_dir).await?;
+
+        let journal = JournalFile::open(&output_dir.join(JOURNAL_FILE_NAME)).await?;
+
+        info!(
+            "Writing {} files to {}",
+            format.extension(),
+            output_dir.display()
+        );
+
+        Ok(FileSink {
+            format,
+            output_dir: output_dir.to_path_buf(),
+            table_prefix: table_prefix.unwrap_or("").to_string(),
+            journal,
+            write_lock: Arc::new(Mutex::new(())),
+            part_counter: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Writes rows to the files of a table, split by the day returned by `date_time`
+    ///
+    /// Returns the number of rows written.
+    async fn write_rows<T: Serialize + Send + 'static>(
+        &self,
+        table: &str,
+        columns: &'static [Column],
+        rows: Vec<T>,
+        date_time: fn(&T) -> DateTime<Utc>,
+    ) -> Result<u64> {
+        if rows.is_empty() {
+            return Ok(0);
+        }
+
+        let row_count = rows.len() as u64;
+        let mut days: BTreeMap<NaiveDate, Vec<T>> = BTreeMap::new();
+        for row in rows {
+            days.entry(date_time(&row).date_naive())
+                .or_default()
+                .push(row);
+        }
+
+        let format = self.format;
+        let table_dir = self
+            .output_dir
+            .join(format!("{}{}", self.table_prefix, table));
+        let write_lock = Arc::clone(&self.write_lock);
+        let part_counter = Arc::clone(&self.part_counter);
+
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let _guard = write_lock
+                .lock()
+                .map_err(|_| eyre!("File sink lock is poisoned"))?;
+            std::fs::create_dir_all(&table_dir)?;
+
+            for (day, rows) in days {
+                match format {
+                    OutputFormat::JsonLines => {
+                        append_json_lines(&table_dir.join(format!("{day}.jsonl")), &rows)?
+                    }
+                    OutputFormat::Csv => append_csv(&table_dir.join(format!("{day}.csv")), &rows)?,
+                    OutputFormat::Parquet => {
+                        let part = part_counter.fetch_add(1, Ordering::Relaxed);
+                        let day_dir = table_dir.join(day.to_string());
+                        std::fs::create_dir_all(&day_dir)?;
+                        let file_name = format!(
+                            "part-{}-{:06}.parquet",
+                            Utc::now().format("%Y%m%dT%H%M%S%6f"),
+                            part
+                        );
+                        write_parquet(&day_dir.join(file_name), columns, &rows)?
+                    }
+                }
+            }
+            Ok(())
+        })
+        .await??;
+
+        debug!("Wrote {} rows of {}", row_count, table);
+        Ok(row_count)
+    }
+}
+
+/// Appends rows to a JSON Lines file, one object per line
+fn append_json_lines<T: Serialize>(path: &Path, rows: &[T]) -> Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    for row in rows {
+        serde_json::to_writer(&mut writer, row)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Appends rows to a CSV file, writing the header if the file is new
+fn append_csv<T: Serialize>(path: &Path, rows: &[T]) -> Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let is_new = file.metadata()?.len() == 0;
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(is_new)
+        .from_writer(BufWriter::new(file));
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes rows to a new Parquet file
+///
+/// The file is written under a temporary name and renamed when complete, so
+/// readers of the directory never see a partial file.
+fn write_parquet<T: Serialize>(path: &Path, columns: &[Column], rows: &[T]) -> Result<()> {
+    let schema = Arc::new(arrow_schema(columns));
+    let mut decoder = ReaderBuilder::new(Arc::clone(&schema))
+        .with_batch_size(rows.len())
+        .build_decoder()?;
+    decoder.serialize(rows)?;
+    let Some(batch) = decoder.flush()? else {
+        return Ok(());
+    };
+
+    let temporary_path = path.with_extension("parquet.tmp");
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer =
+        ArrowWriter::try_new(File::create(&temporary_path)?, schema, Some(properties))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    std::fs::rename(&temporary_path, path)?;
+    Ok(())
+}
+
+#[async_trait]
+impl Database for FileSink {
+    /// Files have no schema to migrate
+    async fn schema_version(&self) -> Result<i32> {
+        Ok(0)
+    }
+
+    fn migrations(&self) -> Vec<Migration> {
+        Vec::new()
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        Err(eyre!(
+            "Migration {} cannot be applied to files",
+            migration.version
+        ))
+    }
+
+    async fn insert_smtp_receive_logs(&self, logs: Vec<SmtpReceiveLog>) -> Result<u64> {
+        self.write_rows("smtp_receive_logs", SMTP_RECEIVE_LOG_COLUMNS, logs, |log| {
+            log.date_time
+        })
+        .await
+    }
+
+    async fn insert_smtp_send_logs(&self, logs: Vec<SmtpSendLog>) -> Result<u64> {
+        self.write_rows("smtp_send_logs", SMTP_SEND_LOG_COLUMNS, logs, |log| {
+            log.date_time
+        })
+        .await
+    }
+
+    async fn insert_smtp_events(
+        &self,
+        direction: SmtpDirection,
+        events: Vec<SmtpEvent>,
+    ) -> Result<u64> {
+        self.write_rows(
+            &direction.table_name("events"),
+            SMTP_EVENT_COLUMNS,
+            events,
+            |event| event.date_time,
+        )
+        .await
+    }
+
+    async fn insert_smtp_transactions(
+        &self,
+        direction: SmtpDirection,
+        transactions: Vec<SmtpTransaction>,
+    ) -> Result<u64> {
+        self.write_rows(
+            &direction.table_name("transactions"),
+            SMTP_TRANSACTION_COLUMNS,
+            transactions,
+            |transaction| transaction.date_time,
+        )
+        .await
+    }
+
+    async fn insert_smtp_recipients(
+        &self,
+        direction: SmtpDirection,
+        recipients: Vec<SmtpRecipient>,
+    ) -> Result<u64> {
+        self.write_rows(
+            &direction.table_name("recipients"),
+            SMTP_RECIPIENT_COLUMNS,
+            recipients,
+            |recipient| recipient.date_time,
+        )
+        .await
+    }
+
+    async fn insert_message_tracking_logs(&self, logs: Vec<MessageTrackingLog>) -> Result<u64> {
+        self.write_rows(
+            "message_tracking_logs",
+            MESSAGE_TRACKING_LOG_COLUMNS,
+            logs,
+            |log| log.date_time,
+        )
+        .await
+    }
+
+    async fn insert_rejected_lines(&self, lines: Vec<RejectedLine>) -> Result<u64> {
+        self.write_rows("rejected_lines", REJECTED_LINE_COLUMNS, lines, |line| {
+            line.logged_at().unwrap_or(line.rejected_at)
+        })
+        .await
+    }
+
+    async fn get_ingested_file(&self, path: &str) -> Result<Option<IngestedFile>> {
+        Ok(self.journal.get(path).await)
+    }
+
+    async fn save_ingested_file(&self, file: IngestedFile) -> Result<()> {
+        self.journal.save(file).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::RecordBatch;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use serde_json::Value;
+
+    fn output_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("elp-file-sink-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn event(date_time: &str, sequence_number: i32) -> SmtpEvent {
+        SmtpEvent {
+            id: None,
+            date_time: date_time.parse().unwrap(),
+            connector_id: "MAIL01\\Default Frontend MAIL01".into(),
+            session_id: "08DC1234ABCD5678".into(),
+            sequence_number,
+            local_endpoint: "10.0.0.1:25".into(),
+            remote_endpoint: "10.0.0.2:50000".into(),
+            event: "<".into(),
+            data: Some("EHLO client.example.com".into()),
+            context: None,
+        }
+    }
+
+    fn rejected_line(line_number: i32, line: &str) -> RejectedLine {
+        RejectedLine {
+            id: None,
+            rejected_at: "2024-03-01T10:00:00Z".parse().unwrap(),
+            file_path: "/logs/RECV2024011523-1.LOG".into(),
+            line_number,
+            line: line.into(),
+            reason: "Line has fewer parts than expected fields".into(),
+        }
+    }
+
+    /// Events of two days and rejected lines dated by their field and by the file name
+    fn batch() -> RecordBatch {
+        let mut batch = RecordBatch::default();
+        batch.smtp_receive.events = vec![
+            event("2024-01-15T23:59:59.999999Z", 0),
+            event("2024-01-15T23:59:59.999999Z", 1),
+            event("2024-01-16T00:00:00Z", 0),
+        ];
+        batch.rejected_lines = vec![
+            rejected_line(7, "2024-01-16T00:00:01.000Z,MAIL01"),
+            rejected_line(8, "garbage"),
+        ];
+        batch
+    }
+
+    async fn write(format: OutputFormat, dir: &Path) {
+        let sink = FileSink::new(format, dir, Some("test_")).await.unwrap();
+        let stats = sink.insert_batch(batch()).await.unwrap();
+        assert_eq!(stats.inserted, 5);
+    }
+
+    fn read_json_lines(path: &Path) -> Vec<Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn read_csv(path: &Path) -> Vec<csv::StringRecord> {
+        let mut reader = csv::Reader::from_path(path).unwrap();
+        reader.records().map(|record| record.unwrap()).collect()
+    }
+
+    /// Reads the single Parquet part of a day back as JSON objects
+    fn read_parquet(day_dir: &Path) -> Vec<Value> {
+        let parts: Vec<PathBuf> = std::fs::read_dir(day_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(parts.len(), 1);
+        let file_name = parts[0].file_name().unwrap().to_str().unwrap();
+        assert!(file_name.starts_with("part-") && file_name.ends_with(".parquet"));
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&parts[0]).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut writer = arrow_json::LineDelimitedWriter::new(Vec::new());
+        for batch in reader {
+            writer.write(&batch.unwrap()).unwrap();
+        }
+        writer.finish().unwrap();
+        String::from_utf8(writer.into_inner())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn writes_json_lines_partitioned_by_day() {
+        let dir = output_dir("jsonl");
+        write(OutputFormat::JsonLines, &dir).await;
+
+        let events = dir.join("test_smtp_receive_events");
+        let first_day = read_json_lines(&events.join("2024-01-15.jsonl"));
+        assert_eq!(first_day.len(), 2);
+        assert_eq!(first_day[1]["sequence_number"], 1);
+        assert_eq!(first_day[1]["date_time"], "2024-01-15T23:59:59.999999Z");
+        assert_eq!(read_json_lines(&events.join("2024-01-16.jsonl")).len(), 1);
+
+        // Rejected lines go to the day they were logged, not the day they were rejected
+        let rejected = dir.join("test_rejected_lines");
+        let by_field = read_json_lines(&rejected.join("2024-01-16.jsonl"));
+        let by_file_name = read_json_lines(&rejected.join("2024-01-15.jsonl"));
+        assert_eq!(by_field[0]["line_number"], 7);
+        assert_eq!(by_file_name[0]["line_number"], 8);
+        assert!(!rejected.join("2024-03-01.jsonl").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writes_csv_partitioned_by_day() {
+        let dir = output_dir("csv");
+        write(OutputFormat::Csv, &dir).await;
+
+        let events = dir.join("test_smtp_receive_events");
+        let first_day = read_csv(&events.join("2024-01-15.csv"));
+        assert_eq!(first_day.len(), 2);
+        let headers = csv::Reader::from_path(events.join("2024-01-15.csv"))
+            .unwrap()
+            .headers()
+            .unwrap()
+            .clone();
+        assert_eq!(&headers[2], "session_id");
+        assert_eq!(&first_day[0][2], "08DC1234ABCD5678");
+        assert_eq!(&first_day[1][3], "1");
+        assert_eq!(read_csv(&events.join("2024-01-16.csv")).len(), 1);
+
+        // A second batch is appended without repeating the header
+        write(OutputFormat::Csv, &dir).await;
+        assert_eq!(read_csv(&events.join("2024-01-15.csv")).len(), 4);
+
+        let rejected = dir.join("test_rejected_lines");
+        assert_eq!(&read_csv(&rejected.join("2024-01-16.csv"))[0][2], "7");
+        assert_eq!(&read_csv(&rejected.join("2024-01-15.csv"))[0][2], "8");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writes_parquet_parts_partitioned_by_day() {
+        let dir = output_dir("parquet");
+        write(OutputFormat::Parquet, &dir).await;
+
+        let events = dir.join("test_smtp_receive_events");
+        let first_day = read_parquet(&events.join("2024-01-15"));
+        assert_eq!(first_day.len(), 2);
+        assert_eq!(first_day[1]["sequence_number"], 1);
+        assert_eq!(first_day[1]["session_id"], "08DC1234ABCD5678");
+        assert!(first_day[1].get("context").is_none());
+        assert_eq!(read_parquet(&events.join("2024-01-16")).len(), 1);
+
+        let rejected = dir.join("test_rejected_lines");
+        assert_eq!(
+            read_parquet(&rejected.join("2024-01-16"))[0]["line_number"],
+            7
+        );
+        assert_eq!(
+            read_parquet(&rejected.join("2024-01-15"))[0]["line_number"],
+            8
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,63 @@
+use crate::models::IngestedFile;
+use color_eyre::eyre::{Result, eyre};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+/// Ingestion journal kept in a JSON file
+///
+/// This struct is used by the sinks that have no place to store the journal
+/// themselves. The whole journal is kept in memory and the file is rewritten on
+/// every change.
+///
+/// ### Examples
+///
+/// ```
+/// let journal = JournalFile::open(Path::new("/data/export/ingested_files.json")).await?;
+/// let entry = journal.get("/logs/MSGTRK2024010100-1.LOG").await;
+/// ```
+pub struct JournalFile {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<String, IngestedFile>>,
+}
+
+impl JournalFile {
+    /// Reads the journal from a file, starting an empty one if the file does not exist
+    pub async fn open(path: &Path) -> Result<Self> {
+        let entries = match tokio::fs::read(path).await {
+            Ok(content) => serde_json::from_slice(&content).map_err(|e| {
+                eyre!(
+                    "Не удалось прочитать журнал загрузки {}: {}",
+                    path.display(),
+                    e
+                )
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(JournalFile {
+            path: path.to_path_buf(),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub async fn get(&self, path: &str) -> Option<IngestedFile> {
+        self.entries.lock().await.get(path).cloned()
+    }
+
+    /// Adds or replaces the entry of a file and writes the journal
+    ///
+    /// The journal is written to a temporary file that is then moved over the old
+    /// one. The lock is held while writing, so that an older journal never
+    /// replaces a newer one.
+    pub async fn save(&self, file: IngestedFile) -> Result<()> {
+        let mut entries = self.entries.lock().await;
+        entries.insert(file.path.clone(), file);
+
+        let temporary_path = self.path.with_extension("json.tmp");
+        tokio::fs::write(&temporary_path, serde_json::to_vec_pretty(&*entries)?).await?;
+        tokio::fs::rename(&temporary_path, &self.path).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use crate::config::Args;
+use crate::database::{self, Database, mssql, postgres};
+use color_eyre::eyre::{Result, eyre};
+use fanout::FanOut;
+
+mod columns;
+pub mod fanout;
+pub mod file;
+mod journal_file;
+pub mod opensearch;
+pub mod syslog;
+
+/// Destination of the records
+///
+/// This enum is used to choose with `--output` where the records are written:
+/// to the database given by `--db-type` or to another sink.
+///
+/// ### Examples
+///
+/// ```
+/// let output: Output = "opensearch".parse()?;
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Database,
+    Files(OutputFormat),
+    OpenSearch,
+    Syslog,
+}
+
+impl std::str::FromStr for Output {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "db" | "database" => Ok(Output::Database),
+            "opensearch" | "elasticsearch" => Ok(Output::OpenSearch),
+            "syslog" => Ok(Output::Syslog),
+            _ => s.parse().map(Output::Files),
+        }
+    }
+}
+
+impl Output {
+    /// Returns the name of the output shown in the statistics
+    pub fn name(self, args: &Args) -> String {
+        match self {
+            Output::Database => format!("{:?}", args.db_type).to_lowercase(),
+            Output::Files(format) => format.extension().to_string(),
+            Output::OpenSearch => "opensearch".to_string(),
+            Output::Syslog => "syslog".to_string(),
+        }
+    }
+}
+
+/// Format of the files written by the file sink
+///
+/// This enum is used to choose how records are exported to files with `--output`.
+///
+/// ### Examples
+///
+/// ```
+/// let format: OutputFormat = "parquet".parse()?;
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    JsonLines,
+    Csv,
+    Parquet,
+}
+
+impl OutputFormat {
+    /// Returns the extension of the files written in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::JsonLines => "jsonl",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Parquet => "parquet",
+        }
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jsonl" | "ndjson" => Ok(OutputFormat::JsonLines),
+            "csv" => Ok(OutputFormat::Csv),
+            "parquet" => Ok(OutputFormat::Parquet),
+            _ => Err(color_eyre::eyre::eyre!(
+                "Неподдерживаемый формат вывода: {}",
+                s
+            )),
+        }
+    }
+}
+
+/// Creates the outputs chosen with `--output` (the database if none is given)
+pub async fn create_sinks(args: &Args) -> Result<FanOut> {
+    let outputs = if args.output.is_empty() {
+        vec![Output::Database]
+    } else {
+        args.output.clone()
+    };
+
+    let mut sinks = Vec::with_capacity(outputs.len());
+    for (i, output) in outputs.iter().enumerate() {
+        if outputs[..i].contains(output) {
+            return Err(eyre!("Выход {} указан несколько раз", output.name(args)));
+        }
+        sinks.push((output.name(args), create_sink(*output, args).await?));
+    }
+    Ok(FanOut::new(sinks, args.on_sink_error))
+}
+
+/// Creates one output from the command line arguments
+pub async fn create_sink(output: Output, args: &Args) -> Result<Box<dyn Database>> {
+    let table_prefix = args.table_prefix.as_deref();
+    match output {
+        Output::Database => {
+            let options = database::ConnectionOptions {
+                host: args.db_host.clone(),
+                port: args.db_port,
+                user: args.db_user.clone(),
+                password: args.db_password.clone(),
+                dbname: args.db_name.clone(),
+                table_prefix: args.table_prefix.clone(),
+                postgres_ssl: postgres::SslOptions {
+                    mode: args.db_sslmode,
+                    root_cert: args.db_ssl_root_cert.clone(),
+                    client_cert: args.db_ssl_cert.clone(),
+                    client_key: args.db_ssl_key.clone(),
+                },
+                mssql: mssql::MsSqlOptions {
+                    connection_string: args.mssql_connection_string.clone(),
+                    instance: args.mssql_instance.clone(),
+                    encryption: args.mssql_encryption,
+                    ca_cert: args.mssql_ca_cert.clone(),
+                    trust_server_certificate: args.mssql_trust_server_certificate,
+                },
+            };
+            database::create_database(args.db_type.clone(), &options).await
+        }
+        Output::Files(format) => {
+            let sink = file::FileSink::new(format, &args.output_dir, table_prefix).await?;
+            Ok(Box::new(sink))
+        }
+        Output::OpenSearch => {
+            let options = opensearch::OpenSearchOptions {
+                url: args.opensearch_url.clone(),
+                username: args.opensearch_user.clone(),
+                password: args.opensearch_password.clone(),
+                index: args.opensearch_index.clone(),
+                journal_index: args.opensearch_journal_index.clone(),
+            };
+            let sink = opensearch::OpenSearchSink::new(options, table_prefix).await?;
+            Ok(Box::new(sink))
+        }
+        Output::Syslog => {
+            let options = syslog::SyslogOptions {
+                address: args.syslog_address.clone(),
+                transport: args.syslog_transport,
+                facility: args.syslog_facility,
+                hostname: args.syslog_hostname.clone(),
+                ca_file: args.syslog_ca_file.clone(),
+                journal_file: args.journal_file.clone(),
+            };
+            let sink = syslog::SyslogSink::new(options).await?;
+            Ok(Box::new(sink))
+        }
+    }
+}
@@ -0,0 +1,814 @@
+use crate::database::Database;
+use crate::database::migrations::Migration;
+use crate::models::{
+    IngestedFile, MessageTrackingLog, RejectedLine, SmtpDirection, SmtpEvent, SmtpReceiveLog,
+    SmtpRecipient, SmtpSendLog, SmtpTransaction,
+};
+use async_trait::async_trait;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Result, eyre};
+use log::{debug, info, warn};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::Serialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+use super::columns::{
+    Column, ColumnType, MESSAGE_TRACKING_LOG_COLUMNS, REJECTED_LINE_COLUMNS, SMTP_EVENT_COLUMNS,
+    SMTP_RECEIVE_LOG_COLUMNS, SMTP_RECIPIENT_COLUMNS, SMTP_SEND_LOG_COLUMNS,
+    SMTP_TRANSACTION_COLUMNS,
+};
+
+/// Number of times a bulk request rejected with 429 Too Many Requests is sent again
+const MAX_RETRIES: u32 = 5;
+
+/// Delay before the first retry, doubled for every next one
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Priority of the index templates, higher than the built-in templates of OpenSearch
+const TEMPLATE_PRIORITY: u32 = 200;
+
+/// Connection settings of the OpenSearch sink
+///
+/// This struct is used to pass the `--opensearch-*` arguments to `OpenSearchSink::new`.
+#[derive(Debug, Clone)]
+pub struct OpenSearchOptions {
+    /// Base URL of the cluster, e.g. `https://opensearch:9200`
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Index name: `{table}` is replaced by the table name, `strftime` specifiers
+    /// (`%Y`, `%m`, `%d`, ...) by the date (UTC) of the record
+    pub index: String,
+    /// Index with the ingestion journal
+    pub journal_index: String,
+}
+
+/// Sink sending records to Elasticsearch or OpenSearch through the `_bulk` API
+///
+/// This struct is used to make mail logs searchable in OpenSearch. Every record
+/// gets an id derived from the unique key of its table, and is sent with the
+/// `create` action: documents that already exist are reported as duplicates, so
+/// processing the same files again does not create copies.
+///
+/// ### Examples
+///
+/// ```
+/// let sink = OpenSearchSink::new(options, None).await?;
+/// sink.insert_batch(batch).await?;
+/// ```
+pub struct OpenSearchSink {
+    client: Client,
+    options: OpenSearchOptions,
+    table_prefix: String,
+}
+
+impl OpenSearchSink {
+    pub async fn new(options: OpenSearchOptions, table_prefix: Option<&str>) -> Result<Self> {
+        if StrftimeItems::new(&options.index).any(|item| matches!(item, Item::Error)) {
+            return Err(eyre!(
+                "Некорректный шаблон имени индекса OpenSearch: {}",
+                options.index
+            ));
+        }
+
+        let sink = OpenSearchSink {
+            client: Client::builder()
+                .timeout(Duration::from_secs(120))
+                .build()?,
+            options: OpenSearchOptions {
+                url: options.url.trim_end_matches('/').to_string(),
+                ..options
+            },
+            table_prefix: table_prefix.unwrap_or("").to_string(),
+        };
+
+        let tables = [
+            ("smtp_receive_logs".to_string(), SMTP_RECEIVE_LOG_COLUMNS),
+            ("smtp_send_logs".to_string(), SMTP_SEND_LOG_COLUMNS),
+            (
+                SmtpDirection::Receive.table_name("events"),
+                SMTP_EVENT_COLUMNS,
+            ),
+            (SmtpDirection::Send.table_name("events"), SMTP_EVENT_COLUMNS),
+            (
+                SmtpDirection::Receive.table_name("transactions"),
+                SMTP_TRANSACTION_COLUMNS,
+            ),
+            (
+                SmtpDirection::Send.table_name("transactions"),
+                SMTP_TRANSACTION_COLUMNS,
+            ),
+            (
+                SmtpDirection::Receive.table_name("recipients"),
+                SMTP_RECIPIENT_COLUMNS,
+            ),
+            (
+                SmtpDirection::Send.table_name("recipients"),
+                SMTP_RECIPIENT_COLUMNS,
+            ),
+            (
+                "message_tracking_logs".to_string(),
+                MESSAGE_TRACKING_LOG_COLUMNS,
+            ),
+            ("rejected_lines".to_string(), REJECTED_LINE_COLUMNS),
+        ];
+        for (table, columns) in tables {
+            sink.put_index_template(&table, columns).await?;
+        }
+
+        info!("Sending records to OpenSearch at {}", sink.options.url);
+        Ok(sink)
+    }
+
+    /// Adds the authentication of the sink to a request
+    fn authenticate(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.options.username {
+            Some(username) => request.basic_auth(username, self.options.password.as_ref()),
+            None => request,
+        }
+    }
+
+    /// Returns the name of the index for a record of a table with the given date
+    fn index_name(&self, table: &str, date_time: DateTime<Utc>) -> String {
+        let template = self
+            .options
+            .index
+            .replace("{table}", &format!("{}{}", self.table_prefix, table));
+        // Index names cannot contain uppercase letters
+        date_time.format(&template).to_string().to_lowercase()
+    }
+
+    /// Returns the pattern matching the indices of a table on every day
+    fn index_pattern(&self, table: &str) -> String {
+        let template = self
+            .options
+            .index
+            .replace("{table}", &format!("{}{}", self.table_prefix, table));
+        let mut pattern = String::new();
+        for item in StrftimeItems::new(&template) {
+            match item {
+                Item::Literal(text) | Item::Space(text) => pattern.push_str(text),
+                Item::OwnedLiteral(text) | Item::OwnedSpace(text) => pattern.push_str(&text),
+                _ if pattern.ends_with('*') => {}
+                _ => pattern.push('*'),
+            }
+        }
+        // "*.*.*" of a date like %Y.%m.%d becomes a single "*"
+        for separator in ["*.*", "*-*", "*_*"] {
+            while pattern.contains(separator) {
+                pattern = pattern.replace(separator, "*");
+            }
+        }
+        pattern.to_lowercase()
+    }
+
+    /// Creates or updates the index template with the mappings of a table
+    async fn put_index_template(&self, table: &str, columns: &[Column]) -> Result<()> {
+        let pattern = self.index_pattern(table);
+        let name = pattern
+            .replace('*', "")
+            .trim_matches(['-', '.', '_'])
+            .to_string();
+        let name = if name.is_empty() {
+            format!("{}{}", self.table_prefix, table)
+        } else {
+            name
+        };
+
+        let properties: serde_json::Map<String, Value> = columns
+            .iter()
+            .map(|column| {
+                let mapping = match column.column_type {
+                    ColumnType::Timestamp => json!({ "type": "date" }),
+                    ColumnType::Int32 => json!({ "type": "integer" }),
+                    ColumnType::Int64 => json!({ "type": "long" }),
+                    ColumnType::Text => json!({
+                        "type": "text",
+                        "fields": { "keyword": { "type": "keyword", "ignore_above": 256 } }
+                    }),
+                };
+                (column.name.to_string(), mapping)
+            })
+            .collect();
+
+        let template = json!({
+            "index_patterns": [pattern],
+            "priority": TEMPLATE_PRIORITY,
+            "template": {
+                "mappings": { "properties": properties }
+            }
+        });
+
+        let request = self
+            .client
+            .put(format!("{}/_index_template/{}", self.options.url, name))
+            .json(&template);
+        check_response(self.authenticate(request).send().await?).await?;
+        debug!("Index template {} created for {}", name, pattern);
+        Ok(())
+    }
+
+    /// Sends rows through the `_bulk` API with the `create` action
+    ///
+    /// Rows rejected with 429 Too Many Requests are sent again with a growing
+    /// delay. Returns the number of documents created; rows whose document
+    /// already exists are not counted.
+    async fn bulk_create<T: Serialize>(
+        &self,
+        table: &str,
+        rows: &[T],
+        date_time: fn(&T) -> DateTime<Utc>,
+        key: fn(&T) -> Vec<String>,
+    ) -> Result<u64> {
+        if rows.is_empty() {
+            return Ok(0);
+        }
+
+        // Every action is the line with the metadata followed by the document
+        let mut actions = Vec::with_capacity(rows.len());
+        for row in rows {
+            let metadata = json!({
+                "create": {
+                    "_index": self.index_name(table, date_time(row)),
+                    "_id": document_id(&key(row)),
+                }
+            });
+            actions.push(format!("{}\n{}\n", metadata, serde_json::to_string(row)?));
+        }
+
+        let mut pending: Vec<usize> = (0..actions.len()).collect();
+        let mut created = 0;
+        let mut delay = INITIAL_RETRY_DELAY;
+        for attempt in 0..=MAX_RETRIES {
+            let body: String = pending.iter().map(|&i| actions[i].as_str()).collect();
+            let request = self
+                .client
+                .post(format!("{}/_bulk", self.options.url))
+                .header("Content-Type", "application/x-ndjson")
+                .body(body);
+            let response = self.authenticate(request).send().await?;
+
+            if response.status() == StatusCode::TOO_MANY_REQUESTS && attempt < MAX_RETRIES {
+                let wait = retry_after(&response).unwrap_or(delay);
+                warn!(
+                    "OpenSearch rejected a bulk request for {} with 429, retrying in {:?}",
+                    table, wait
+                );
+                tokio::time::sleep(wait).await;
+                delay *= 2;
+                continue;
+            }
+
+            let response: Value = check_response(response).await?.json().await?;
+            let items = response["items"]
+                .as_array()
+                .filter(|items| items.len() == pending.len())
+                .ok_or_else(|| eyre!("Unexpected response of the OpenSearch bulk API"))?;
+
+            let mut rejected = Vec::new();
+            for (&action, item) in pending.iter().zip(items) {
+                let result = &item["create"];
+                match result["status"].as_u64() {
+                    Some(200..=299) => created += 1,
+                    // The document was created by an earlier run
+                    Some(409) => {}
+                    Some(429) => rejected.push(action),
+                    status => {
+                        return Err(eyre!(
+                            "OpenSearch failed to index a document into {}: status {:?}, {}",
+                            table,
+                            status,
+                            result["error"]
+                        ));
+                    }
+                }
+            }
+
+            if rejected.is_empty() {
+                debug!("Created {} documents for {}", created, table);
+                return Ok(created);
+            }
+            if attempt < MAX_RETRIES {
+                warn!(
+                    "OpenSearch rejected {} documents for {} with 429, retrying in {:?}",
+                    rejected.len(),
+                    table,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            pending = rejected;
+        }
+
+        Err(eyre!(
+            "OpenSearch rejected {} documents for {} after {} retries",
+            pending.len(),
+            table,
+            MAX_RETRIES
+        ))
+    }
+}
+
+/// Derives the id of a document from the unique key of its row
+fn document_id(key: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    for part in key {
+        hasher.update(part.as_bytes());
+        // Separates the parts, so that ("ab", "c") and ("a", "bc") differ
+        hasher.update([0x1f]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Returns the delay requested by the `Retry-After` header in seconds
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+/// Turns an unsuccessful response into an error with the body of the response
+async fn check_response(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let url = response.url().clone();
+    let body = response.text().await.unwrap_or_default();
+    Err(eyre!(
+        "OpenSearch returned {} for {}: {}",
+        status,
+        url,
+        body.chars().take(1000).collect::<String>()
+    ))
+}
+
+/// Returns the date of a rejected line that chooses its index
+///
+/// The time the line was logged at is used rather than `rejected_at`, so that the
+/// document lands in the same index when the file is processed again and is
+/// recognized as a duplicate.
+fn rejected_line_time(line: &RejectedLine) -> DateTime<Utc> {
+    line.logged_at().unwrap_or(DateTime::UNIX_EPOCH)
+}
+
+/// Formats a timestamp for a document id, with the precision kept by the parser
+fn key_time(date_time: DateTime<Utc>) -> String {
+    date_time.timestamp_micros().to_string()
+}
+
+#[async_trait]
+impl Database for OpenSearchSink {
+    /// Index mappings are managed by index templates, not by migrations
+    async fn schema_version(&self) -> Result<i32> {
+        Ok(0)
+    }
+
+    fn migrations(&self) -> Vec<Migration> {
+        Vec::new()
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        Err(eyre!(
+            "Migration {} cannot be applied to OpenSearch",
+            migration.version
+        ))
+    }
+
+    async fn insert_smtp_receive_logs(&self, logs: Vec<SmtpReceiveLog>) -> Result<u64> {
+        self.bulk_create(
+            "smtp_receive_logs",
+            &logs,
+            |log| log.date_time,
+            |log| {
+                vec![
+                    key_time(log.date_time),
+                    log.session_id.clone(),
+                    log.sequence_number.to_string(),
+                ]
+            },
+        )
+        .await
+    }
+
+    async fn insert_smtp_send_logs(&self, logs: Vec<SmtpSendLog>) -> Result<u64> {
+        self.bulk_create(
+            "smtp_send_logs",
+            &logs,
+            |log| log.date_time,
+            |log| {
+                vec![
+                    key_time(log.date_time),
+                    log.session_id.clone(),
+                    log.sequence_number.to_string(),
+                ]
+            },
+        )
+        .await
+    }
+
+    async fn insert_smtp_events(
+        &self,
+        direction: SmtpDirection,
+        events: Vec<SmtpEvent>,
+    ) -> Result<u64> {
+        self.bulk_create(
+            &direction.table_name("events"),
+            &events,
+            |event| event.date_time,
+            |event| {
+                vec![
+                    key_time(event.date_time),
+                    event.session_id.clone(),
+                    event.sequence_number.to_string(),
+                ]
+            },
+        )
+        .await
+    }
+
+    async fn insert_smtp_transactions(
+        &self,
+        direction: SmtpDirection,
+        transactions: Vec<SmtpTransaction>,
+    ) -> Result<u64> {
+        self.bulk_create(
+            &direction.table_name("transactions"),
+            &transactions,
+            |transaction| transaction.date_time,
+            |transaction| {
+                vec![
+                    key_time(transaction.date_time),
+                    transaction.session_id.clone(),
+                    transaction.transaction_index.to_string(),
+                ]
+            },
+        )
+        .await
+    }
+
+    async fn insert_smtp_recipients(
+        &self,
+        direction: SmtpDirection,
+        recipients: Vec<SmtpRecipient>,
+    ) -> Result<u64> {
+        self.bulk_create(
+            &direction.table_name("recipients"),
+            &recipients,
+            |recipient| recipient.date_time,
+            |recipient| {
+                vec![
+                    key_time(recipient.date_time),
+                    recipient.session_id.clone(),
+                    recipient.sequence_number.to_string(),
+                ]
+            },
+        )
+        .await
+    }
+
+    async fn insert_message_tracking_logs(&self, logs: Vec<MessageTrackingLog>) -> Result<u64> {
+        self.bulk_create(
+            "message_tracking_logs",
+            &logs,
+            |log| log.date_time,
+            |log| {
+                vec![
+                    key_time(log.date_time),
+                    log.internal_message_id.clone(),
+                    log.recipient_address.clone(),
+                    log.event_id.clone(),
+                ]
+            },
+        )
+        .await
+    }
+
+    async fn insert_rejected_lines(&self, lines: Vec<RejectedLine>) -> Result<u64> {
+        self.bulk_create("rejected_lines", &lines, rejected_line_time, |line| {
+            vec![line.file_path.clone(), line.line_number.to_string()]
+        })
+        .await
+    }
+
+    async fn get_ingested_file(&self, path: &str) -> Result<Option<IngestedFile>> {
+        let request = self.client.get(format!(
+            "{}/{}/_doc/{}",
+            self.options.url,
+            self.options.journal_index,
+            document_id(&[path.to_string()])
+        ));
+        let response = self.authenticate(request).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let document: Value = check_response(response).await?.json().await?;
+        Ok(Some(serde_json::from_value(document["_source"].clone())?))
+    }
+
+    async fn save_ingested_file(&self, file: IngestedFile) -> Result<()> {
+        let request = self
+            .client
+            .put(format!(
+                "{}/{}/_doc/{}",
+                self.options.url,
+                self.options.journal_index,
+                document_id(std::slice::from_ref(&file.path))
+            ))
+            .json(&file);
+        check_response(self.authenticate(request).send().await?).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{LogRecord, RecordBatch};
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::time::Instant;
+
+    /// Response of the mock cluster to a `_bulk` request
+    struct BulkResponse {
+        status: u16,
+        retry_after: Option<u64>,
+        item_statuses: Vec<u16>,
+    }
+
+    impl BulkResponse {
+        fn items(item_statuses: &[u16]) -> Self {
+            BulkResponse {
+                status: 200,
+                retry_after: None,
+                item_statuses: item_statuses.to_vec(),
+            }
+        }
+
+        fn too_many_requests(retry_after: Option<u64>) -> Self {
+            BulkResponse {
+                status: 429,
+                retry_after,
+                item_statuses: Vec::new(),
+            }
+        }
+    }
+
+    /// HTTP server answering `_bulk` requests with the given responses in order
+    ///
+    /// Every other request (index templates) is acknowledged. The bodies of the
+    /// `_bulk` requests are recorded with the time they were received.
+    struct MockCluster {
+        url: String,
+        bulk_requests: Arc<Mutex<Vec<(Instant, String)>>>,
+    }
+
+    impl MockCluster {
+        async fn start(responses: Vec<BulkResponse>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let bulk_requests = Arc::new(Mutex::new(Vec::new()));
+            let responses = Arc::new(Mutex::new(VecDeque::from(responses)));
+
+            let requests = Arc::clone(&bulk_requests);
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let requests = Arc::clone(&requests);
+                    let responses = Arc::clone(&responses);
+                    tokio::spawn(async move {
+                        let (reader, mut writer) = stream.into_split();
+                        let mut reader = BufReader::new(reader);
+                        while let Some((path, body)) = read_request(&mut reader).await {
+                            let response = if path == "/_bulk" {
+                                requests.lock().unwrap().push((Instant::now(), body));
+                                let next = responses.lock().unwrap().pop_front();
+                                bulk_response(next.expect("unexpected _bulk request"))
+                            } else {
+                                http_response(200, None, r#"{"acknowledged":true}"#)
+                            };
+                            writer.write_all(response.as_bytes()).await.unwrap();
+                        }
+                    });
+                }
+            });
+
+            MockCluster { url, bulk_requests }
+        }
+
+        async fn sink(&self) -> OpenSearchSink {
+            OpenSearchSink::new(
+                OpenSearchOptions {
+                    url: self.url.clone(),
+                    username: None,
+                    password: None,
+                    index: "exchange-{table}-%Y.%m.%d".into(),
+                    journal_index: "exchange-ingested-files".into(),
+                },
+                None,
+            )
+            .await
+            .unwrap()
+        }
+
+        fn bulk_requests(&self) -> Vec<(Instant, String)> {
+            self.bulk_requests.lock().unwrap().clone()
+        }
+    }
+
+    /// Reads the path and the body of the next request of a connection
+    async fn read_request<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Option<(String, String)> {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).await.ok()? == 0 {
+            return None;
+        }
+        let path = request_line.split_whitespace().nth(1)?.to_string();
+
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).await.ok()?;
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                content_length = value.trim().parse().ok()?;
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).await.ok()?;
+        Some((path, String::from_utf8(body).ok()?))
+    }
+
+    fn bulk_response(response: BulkResponse) -> String {
+        let items: Vec<Value> = response
+            .item_statuses
+            .iter()
+            .map(|status| json!({ "create": { "status": status } }))
+            .collect();
+        let body = json!({ "errors": false, "items": items }).to_string();
+        http_response(response.status, response.retry_after, &body)
+    }
+
+    fn http_response(status: u16, retry_after: Option<u64>, body: &str) -> String {
+        let retry_after = retry_after
+            .map(|seconds| format!("Retry-After: {}\r\n", seconds))
+            .unwrap_or_default();
+        format!(
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}\r\n{}",
+            status,
+            body.len(),
+            retry_after,
+            body
+        )
+    }
+
+    fn rejected_line(line_number: i32, rejected_at: DateTime<Utc>) -> RejectedLine {
+        RejectedLine {
+            id: None,
+            rejected_at,
+            file_path: "/logs/MSGTRK2024011514-1.LOG".into(),
+            line_number,
+            line: format!("2024-01-15T14:00:0{}.000Z,10.0.0.1,broken", line_number),
+            reason: "Line has fewer parts than expected fields".into(),
+        }
+    }
+
+    /// Returns the metadata lines of the actions of a `_bulk` request body
+    fn actions(body: &str) -> Vec<Value> {
+        body.lines()
+            .step_by(2)
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sends_create_actions_and_counts_conflicts_as_duplicates() {
+        let cluster = MockCluster::start(vec![BulkResponse::items(&[201, 409])]).await;
+        let sink = cluster.sink().await;
+
+        let mut batch = RecordBatch::default();
+        for line_number in [1, 2] {
+            batch.push(LogRecord::RejectedLine(rejected_line(
+                line_number,
+                Utc::now(),
+            )));
+        }
+        let stats = sink.insert_batch(batch).await.unwrap();
+        assert_eq!(stats.inserted, 1);
+        assert_eq!(stats.duplicates, 1);
+
+        let requests = cluster.bulk_requests();
+        assert_eq!(requests.len(), 1);
+        let actions = actions(&requests[0].1);
+        assert_eq!(actions.len(), 2);
+        for action in &actions {
+            assert_eq!(
+                action["create"]["_index"],
+                "exchange-rejected_lines-2024.01.15"
+            );
+        }
+        assert_ne!(actions[0]["create"]["_id"], actions[1]["create"]["_id"]);
+    }
+
+    #[tokio::test]
+    async fn rejected_lines_keep_index_and_id_across_runs() {
+        let cluster = MockCluster::start(vec![
+            BulkResponse::items(&[201]),
+            BulkResponse::items(&[409]),
+        ])
+        .await;
+        let sink = cluster.sink().await;
+
+        let first_run = DateTime::from_timestamp(1_705_000_000, 0).unwrap();
+        let second_run = DateTime::from_timestamp(1_706_000_000, 0).unwrap();
+        for rejected_at in [first_run, second_run] {
+            sink.insert_rejected_lines(vec![rejected_line(1, rejected_at)])
+                .await
+                .unwrap();
+        }
+
+        let requests = cluster.bulk_requests();
+        assert_eq!(actions(&requests[0].1), actions(&requests[1].1));
+    }
+
+    #[test]
+    fn rejected_line_without_timestamp_is_indexed_by_file_date() {
+        let line = RejectedLine {
+            line: "garbage".into(),
+            ..rejected_line(1, Utc::now())
+        };
+        assert_eq!(
+            rejected_line_time(&line),
+            DateTime::parse_from_rfc3339("2024-01-15T00:00:00Z").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn waits_for_retry_after_when_request_is_rejected() {
+        let cluster = MockCluster::start(vec![
+            BulkResponse::too_many_requests(Some(1)),
+            BulkResponse::items(&[201]),
+        ])
+        .await;
+        let sink = cluster.sink().await;
+
+        let created = sink
+            .insert_rejected_lines(vec![rejected_line(1, Utc::now())])
+            .await
+            .unwrap();
+        assert_eq!(created, 1);
+
+        let requests = cluster.bulk_requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].0 - requests[0].0 >= Duration::from_secs(1));
+        assert_eq!(requests[0].1, requests[1].1);
+    }
+
+    #[tokio::test]
+    async fn resends_rejected_documents_with_growing_delay() {
+        let cluster = MockCluster::start(vec![
+            BulkResponse::items(&[201, 429, 429]),
+            BulkResponse::items(&[409, 429]),
+            BulkResponse::items(&[201]),
+        ])
+        .await;
+        let sink = cluster.sink().await;
+
+        let lines = (1..=3)
+            .map(|line_number| rejected_line(line_number, Utc::now()))
+            .collect();
+        let created = sink.insert_rejected_lines(lines).await.unwrap();
+        assert_eq!(created, 2);
+
+        let requests = cluster.bulk_requests();
+        let sizes: Vec<usize> = requests
+            .iter()
+            .map(|(_, body)| actions(body).len())
+            .collect();
+        assert_eq!(sizes, vec![3, 2, 1]);
+        // Only the documents rejected with 429 are sent again
+        assert_eq!(actions(&requests[2].1)[0], actions(&requests[0].1)[2]);
+
+        let first_delay = requests[1].0 - requests[0].0;
+        let second_delay = requests[2].0 - requests[1].0;
+        assert!(first_delay >= INITIAL_RETRY_DELAY);
+        assert!(second_delay >= INITIAL_RETRY_DELAY * 2);
+    }
+}
@@ -0,0 +1,713 @@
+use crate::database::migrations::Migration;
+use crate::database::{Database, InsertStats};
+use crate::models::{
+    IngestedFile, MessageTrackingLog, RecordBatch, RejectedLine, SmtpDirection, SmtpEvent,
+    SmtpReceiveLog, SmtpRecipient, SmtpSendLog, SmtpTransaction,
+};
+use crate::tls;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{Result, eyre};
+use futures::FutureExt;
+use log::{debug, info, warn};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+use super::columns::{
+    Column, ColumnType, MESSAGE_TRACKING_LOG_COLUMNS, REJECTED_LINE_COLUMNS,
+    SMTP_RECEIVE_LOG_COLUMNS, SMTP_SEND_LOG_COLUMNS,
+};
+use super::journal_file::JournalFile;
+
+/// APP-NAME of the messages
+const APP_NAME: &str = "exchange-log-parser";
+
+/// SD-ID of the structured data element with the fields of a record
+///
+/// 32473 is the private enterprise number reserved for documentation (RFC 5612).
+const SD_ID: &str = "exchange@32473";
+
+/// Syslog severities used for the records
+const SEVERITY_WARNING: u8 = 4;
+const SEVERITY_INFORMATIONAL: u8 = 6;
+
+/// Names of the syslog facilities, in the order of their codes
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv",
+    "ftp", "ntp", "audit", "alert", "clock", "local0", "local1", "local2", "local3", "local4",
+    "local5", "local6", "local7",
+];
+
+/// Transport of the syslog messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyslogTransport {
+    /// One message per datagram (RFC 5426)
+    Udp,
+    /// Octet-counting framing (RFC 6587)
+    Tcp,
+    /// Octet-counting framing over TLS (RFC 5425)
+    Tls,
+}
+
+impl SyslogTransport {
+    /// Returns the port used when the address has none
+    pub fn default_port(self) -> u16 {
+        match self {
+            SyslogTransport::Udp | SyslogTransport::Tcp => 514,
+            SyslogTransport::Tls => 6514,
+        }
+    }
+}
+
+impl std::str::FromStr for SyslogTransport {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "udp" => Ok(SyslogTransport::Udp),
+            "tcp" => Ok(SyslogTransport::Tcp),
+            "tls" => Ok(SyslogTransport::Tls),
+            _ => Err(eyre!("Неподдерживаемый транспорт syslog: {}", s)),
+        }
+    }
+}
+
+/// Syslog facility given by name (`mail`, `local0`, ...) or by code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Facility(u8);
+
+impl std::str::FromStr for Facility {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        FACILITIES
+            .iter()
+            .position(|name| *name == s)
+            .or_else(|| s.parse().ok().filter(|code| *code < FACILITIES.len()))
+            .map(|code| Facility(code as u8))
+            .ok_or_else(|| eyre!("Неизвестный facility syslog: {}", s))
+    }
+}
+
+/// Settings of the syslog sink
+///
+/// This struct is used to pass the `--syslog-*` arguments to `SyslogSink::new`.
+#[derive(Debug, Clone)]
+pub struct SyslogOptions {
+    /// `host:port` of the syslog server, the port may be omitted
+    pub address: String,
+    pub transport: SyslogTransport,
+    pub facility: Facility,
+    /// HOSTNAME of the records that do not name an Exchange server
+    pub hostname: Option<String>,
+    /// PEM file with the CA certificates trusted for TLS instead of the Mozilla roots
+    pub ca_file: Option<PathBuf>,
+    /// File with the ingestion journal
+    pub journal_file: PathBuf,
+}
+
+/// Byte stream to the syslog server, plain TCP or TLS
+trait SyslogStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> SyslogStream for T {}
+
+/// Connection to the syslog server
+enum Connection {
+    Udp(UdpSocket),
+    Stream(Box<dyn SyslogStream>),
+}
+
+impl Connection {
+    /// Checks whether the server has closed the stream
+    ///
+    /// Syslog servers never send anything, so a stream that is readable has reached
+    /// its end or failed. Writing to such a stream may still succeed, the messages
+    /// would then be lost without an error.
+    fn is_closed(&mut self) -> bool {
+        match self {
+            Connection::Udp(_) => false,
+            Connection::Stream(stream) => {
+                let mut byte = [0; 1];
+                matches!(stream.read(&mut byte).now_or_never(), Some(Ok(0) | Err(_)))
+            }
+        }
+    }
+}
+
+/// Header fields and the free-form message of a record
+struct Entry<'a> {
+    severity: u8,
+    date_time: DateTime<Utc>,
+    hostname: Option<&'a str>,
+    message: String,
+}
+
+/// Sink forwarding records to a SIEM as RFC 5424 syslog messages
+///
+/// This struct is used to send Message Tracking records, SMTP session records
+/// and rejected lines to a syslog server. The MSGID of a message is the table
+/// name of the record, its fields are sent as the structured data element
+/// `[exchange@32473 ...]`. Events, transactions and recipients of SMTP sessions
+/// are not forwarded.
+///
+/// Messages are delivered at least once: a connection closed by the server is
+/// replaced before sending, and after a broken connection the whole batch is sent
+/// again over a new one. The ingestion journal is kept in a local JSON file.
+///
+/// ### Examples
+///
+/// ```
+/// let sink = SyslogSink::new(options).await?;
+/// sink.insert_batch(batch).await?;
+/// ```
+pub struct SyslogSink {
+    options: SyslogOptions,
+    host: String,
+    port: u16,
+    tls: Option<TlsConnector>,
+    connection: Mutex<Option<Connection>>,
+    journal: JournalFile,
+}
+
+impl SyslogSink {
+    pub async fn new(options: SyslogOptions) -> Result<Self> {
+        let (host, port) = split_address(&options.address, options.transport.default_port())?;
+        let tls = match options.transport {
+            SyslogTransport::Tls => Some(tls_connector(options.ca_file.as_ref())?),
+            _ => None,
+        };
+
+        let sink = SyslogSink {
+            journal: JournalFile::open(&options.journal_file).await?,
+            options,
+            host,
+            port,
+            tls,
+            connection: Mutex::new(None),
+        };
+
+        *sink.connection.lock().await = Some(sink.connect().await?);
+        info!(
+            "Sending records to syslog at {}:{} over {:?}",
+            sink.host, sink.port, sink.options.transport
+        );
+        Ok(sink)
+    }
+
+    async fn connect(&self) -> Result<Connection> {
+        let address = tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await?
+            .next()
+            .ok_or_else(|| eyre!("Адрес сервера syslog не найден: {}", self.host))?;
+
+        if self.options.transport == SyslogTransport::Udp {
+            let local = if address.is_ipv6() {
+                "[::]:0"
+            } else {
+                "0.0.0.0:0"
+            };
+            let socket = UdpSocket::bind(local).await?;
+            socket.connect(address).await?;
+            return Ok(Connection::Udp(socket));
+        }
+
+        let stream = TcpStream::connect(address).await.map_err(|e| {
+            eyre!(
+                "Не удалось подключиться к серверу syslog {}: {}",
+                address,
+                e
+            )
+        })?;
+        stream.set_nodelay(true)?;
+
+        let Some(tls) = &self.tls else {
+            return Ok(Connection::Stream(Box::new(stream)));
+        };
+        let server_name = ServerName::try_from(self.host.clone())?;
+        let stream = tls.connect(server_name, stream).await.map_err(|e| {
+            eyre!(
+                "Ошибка TLS при подключении к серверу syslog {}: {}",
+                self.host,
+                e
+            )
+        })?;
+        Ok(Connection::Stream(Box::new(stream)))
+    }
+
+    /// Sends messages, connecting again once if the connection was broken
+    ///
+    /// Returns the number of messages sent.
+    async fn send(&self, messages: Vec<String>) -> Result<u64> {
+        if messages.is_empty() {
+            return Ok(0);
+        }
+
+        let mut connection = self.connection.lock().await;
+        if connection.as_mut().is_some_and(Connection::is_closed) {
+            debug!("Syslog server closed the connection, reconnecting");
+            *connection = None;
+        }
+
+        let mut reconnected = false;
+        loop {
+            let current = match connection.as_mut() {
+                Some(current) => current,
+                None => connection.insert(self.connect().await?),
+            };
+
+            match write_messages(current, &messages).await {
+                Ok(()) => return Ok(messages.len() as u64),
+                Err(e) if !reconnected => {
+                    warn!("Syslog connection failed ({}), reconnecting", e);
+                    *connection = None;
+                    reconnected = true;
+                }
+                Err(e) => {
+                    *connection = None;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Formats rows as syslog messages and sends them
+    async fn send_rows<T: Serialize>(
+        &self,
+        table: &str,
+        columns: &[Column],
+        rows: &[T],
+        entry: fn(&T) -> Entry<'_>,
+    ) -> Result<u64> {
+        let messages = rows
+            .iter()
+            .map(|row| self.format_message(table, columns, row, entry(row)))
+            .collect::<Result<Vec<_>>>()?;
+        let sent = self.send(messages).await?;
+        debug!("Sent {} syslog messages for {}", sent, table);
+        Ok(sent)
+    }
+
+    /// Formats a row as an RFC 5424 message
+    ///
+    /// The timestamp of the record is the TIMESTAMP of the message, the other
+    /// non-empty fields become the parameters of the structured data element.
+    fn format_message<T: Serialize>(
+        &self,
+        table: &str,
+        columns: &[Column],
+        row: &T,
+        entry: Entry,
+    ) -> Result<String> {
+        let fields = serde_json::to_value(row)?;
+        let mut structured_data = format!("[{SD_ID}");
+        for column in columns {
+            if matches!(column.column_type, ColumnType::Timestamp) {
+                continue;
+            }
+            let value = match &fields[column.name] {
+                Value::Null => continue,
+                Value::String(text) => text.clone(),
+                value => value.to_string(),
+            };
+            structured_data.push_str(&format!(
+                " {}=\"{}\"",
+                column.name,
+                escape_param_value(&value)
+            ));
+        }
+        structured_data.push(']');
+
+        let hostname = entry
+            .hostname
+            .or(self.options.hostname.as_deref())
+            .map(header_field)
+            .filter(|hostname| !hostname.is_empty())
+            .unwrap_or_else(|| "-".to_string());
+
+        let mut message = format!(
+            "<{}>1 {} {} {} - {} {}",
+            self.options.facility.0 * 8 + entry.severity,
+            entry.date_time.format("%Y-%m-%dT%H:%M:%S%.6fZ"),
+            hostname,
+            APP_NAME,
+            table,
+            structured_data
+        );
+        if !entry.message.is_empty() {
+            message.push(' ');
+            message.push_str(&entry.message);
+        }
+        Ok(message)
+    }
+}
+
+/// Writes messages to the connection, framed for the transport
+async fn write_messages(connection: &mut Connection, messages: &[String]) -> Result<()> {
+    match connection {
+        Connection::Udp(socket) => {
+            for message in messages {
+                socket.send(message.as_bytes()).await?;
+            }
+        }
+        Connection::Stream(stream) => {
+            let mut buffer = Vec::new();
+            for message in messages {
+                buffer.extend_from_slice(format!("{} ", message.len()).as_bytes());
+                buffer.extend_from_slice(message.as_bytes());
+            }
+            stream.write_all(&buffer).await?;
+            stream.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Splits `host:port` (or `[ipv6]:port`), using the default port if none is given
+fn split_address(address: &str, default_port: u16) -> Result<(String, u16)> {
+    if let Some(rest) = address.strip_prefix('[') {
+        let (host, port) = rest
+            .split_once(']')
+            .ok_or_else(|| eyre!("Некорректный адрес сервера syslog: {}", address))?;
+        let port = match port.strip_prefix(':') {
+            Some(port) => port.parse()?,
+            None => default_port,
+        };
+        return Ok((host.to_string(), port));
+    }
+
+    match address.split_once(':') {
+        Some((host, port)) => Ok((host.to_string(), port.parse()?)),
+        None => Ok((address.to_string(), default_port)),
+    }
+}
+
+/// Builds the TLS connector trusting the Mozilla roots or the CAs of a PEM file
+fn tls_connector(ca_file: Option<&PathBuf>) -> Result<TlsConnector> {
+    let roots = tls::root_certificates(ca_file.map(PathBuf::as_path))?;
+    let config = ClientConfig::builder_with_provider(tls::crypto_provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Escapes `"`, `\` and `]` in a PARAM-VALUE
+fn escape_param_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Keeps the printable US-ASCII characters allowed in a header field, at most 255
+fn header_field(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(255)
+        .collect()
+}
+
+#[async_trait]
+impl Database for SyslogSink {
+    /// A syslog server has no schema to migrate
+    async fn schema_version(&self) -> Result<i32> {
+        Ok(0)
+    }
+
+    fn migrations(&self) -> Vec<Migration> {
+        Vec::new()
+    }
+
+    async fn apply_migration(&self, migration: &Migration) -> Result<()> {
+        Err(eyre!(
+            "Migration {} cannot be applied to syslog",
+            migration.version
+        ))
+    }
+
+    async fn insert_smtp_receive_logs(&self, logs: Vec<SmtpReceiveLog>) -> Result<u64> {
+        self.send_rows(
+            "smtp_receive_logs",
+            SMTP_RECEIVE_LOG_COLUMNS,
+            &logs,
+            |log| Entry {
+                severity: SEVERITY_INFORMATIONAL,
+                date_time: log.date_time,
+                hostname: None,
+                message: format!(
+                    "SMTP session {} from {}",
+                    log.session_id, log.remote_endpoint
+                ),
+            },
+        )
+        .await
+    }
+
+    async fn insert_smtp_send_logs(&self, logs: Vec<SmtpSendLog>) -> Result<u64> {
+        self.send_rows("smtp_send_logs", SMTP_SEND_LOG_COLUMNS, &logs, |log| {
+            Entry {
+                severity: SEVERITY_INFORMATIONAL,
+                date_time: log.date_time,
+                hostname: None,
+                message: format!("SMTP session {} to {}", log.session_id, log.remote_endpoint),
+            }
+        })
+        .await
+    }
+
+    /// Protocol events are not forwarded
+    async fn insert_smtp_events(
+        &self,
+        _direction: SmtpDirection,
+        _events: Vec<SmtpEvent>,
+    ) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Transactions are not forwarded
+    async fn insert_smtp_transactions(
+        &self,
+        _direction: SmtpDirection,
+        _transactions: Vec<SmtpTransaction>,
+    ) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Recipients are not forwarded
+    async fn insert_smtp_recipients(
+        &self,
+        _direction: SmtpDirection,
+        _recipients: Vec<SmtpRecipient>,
+    ) -> Result<u64> {
+        Ok(0)
+    }
+
+    async fn insert_message_tracking_logs(&self, logs: Vec<MessageTrackingLog>) -> Result<u64> {
+        self.send_rows(
+            "message_tracking_logs",
+            MESSAGE_TRACKING_LOG_COLUMNS,
+            &logs,
+            |log| Entry {
+                severity: if log.event_id == "FAIL" {
+                    SEVERITY_WARNING
+                } else {
+                    SEVERITY_INFORMATIONAL
+                },
+                date_time: log.date_time,
+                hostname: Some(&log.server_hostname),
+                message: format!(
+                    "{} {} from {} to {}",
+                    log.event_id, log.message_id, log.sender_address, log.recipient_address
+                ),
+            },
+        )
+        .await
+    }
+
+    async fn insert_rejected_lines(&self, lines: Vec<RejectedLine>) -> Result<u64> {
+        self.send_rows("rejected_lines", REJECTED_LINE_COLUMNS, &lines, |line| {
+            Entry {
+                severity: SEVERITY_WARNING,
+                date_time: line.rejected_at,
+                hostname: None,
+                message: format!("{}:{}: {}", line.file_path, line.line_number, line.reason),
+            }
+        })
+        .await
+    }
+
+    async fn get_ingested_file(&self, path: &str) -> Result<Option<IngestedFile>> {
+        Ok(self.journal.get(path).await)
+    }
+
+    async fn save_ingested_file(&self, file: IngestedFile) -> Result<()> {
+        self.journal.save(file).await
+    }
+
+    /// Sends the records of a batch that are forwarded to syslog
+    ///
+    /// The details of SMTP sessions are not forwarded, so they are counted
+    /// neither as inserted rows nor as duplicates.
+    async fn insert_batch(&self, batch: RecordBatch) -> Result<InsertStats> {
+        let mut inserted = 0;
+        inserted += self
+            .insert_smtp_receive_logs(batch.smtp_receive_logs)
+            .await?;
+        inserted += self.insert_smtp_send_logs(batch.smtp_send_logs).await?;
+        inserted += self
+            .insert_message_tracking_logs(batch.message_tracking_logs)
+            .await?;
+        inserted += self.insert_rejected_lines(batch.rejected_lines).await?;
+        Ok(InsertStats {
+            inserted,
+            duplicates: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    async fn sink(address: String, transport: SyslogTransport) -> SyslogSink {
+        let journal_file = std::env::temp_dir().join(format!(
+            "elp-syslog-test-{}.json",
+            address.replace([':', '.'], "-")
+        ));
+        SyslogSink::new(SyslogOptions {
+            address,
+            transport,
+            facility: "mail".parse().unwrap(),
+            hostname: Some("collector".into()),
+            ca_file: None,
+            journal_file,
+        })
+        .await
+        .unwrap()
+    }
+
+    fn rejected_line(line_number: i32, reason: &str) -> RejectedLine {
+        RejectedLine {
+            id: None,
+            rejected_at: DateTime::parse_from_rfc3339("2024-01-15T14:00:00.5Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            file_path: "/logs/MSGTRK2024011514-1.LOG".into(),
+            line_number,
+            line: "broken".into(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Reads octet-counted frames until `count` messages were received
+    async fn read_frames(stream: &mut TcpStream, count: usize) -> Vec<String> {
+        let mut buffer = Vec::new();
+        let mut messages = Vec::new();
+        while messages.len() < count {
+            let mut chunk = [0; 4096];
+            let read = stream.read(&mut chunk).await.unwrap();
+            assert!(
+                read > 0,
+                "connection closed after {} messages",
+                messages.len()
+            );
+            buffer.extend_from_slice(&chunk[..read]);
+
+            while let Some(space) = buffer.iter().position(|&b| b == b' ') {
+                let length: usize = std::str::from_utf8(&buffer[..space])
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if buffer.len() < space + 1 + length {
+                    break;
+                }
+                let frame: Vec<u8> = buffer.drain(..space + 1 + length).collect();
+                messages.push(String::from_utf8(frame[space + 1..].to_vec()).unwrap());
+            }
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn sends_rfc5424_datagrams_over_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let sink = sink(address, SyslogTransport::Udp).await;
+
+        let sent = sink
+            .insert_rejected_lines(vec![rejected_line(7, "Unterminated quoted field")])
+            .await
+            .unwrap();
+        assert_eq!(sent, 1);
+
+        let mut datagram = [0; 2048];
+        let length = server.recv(&mut datagram).await.unwrap();
+        assert_eq!(
+            std::str::from_utf8(&datagram[..length]).unwrap(),
+            "<20>1 2024-01-15T14:00:00.500000Z collector exchange-log-parser - rejected_lines \
+             [exchange@32473 file_path=\"/logs/MSGTRK2024011514-1.LOG\" line_number=\"7\" \
+             line=\"broken\" reason=\"Unterminated quoted field\"] \
+             /logs/MSGTRK2024011514-1.LOG:7: Unterminated quoted field"
+        );
+    }
+
+    #[tokio::test]
+    async fn frames_messages_with_octet_counting_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let sink = sink(address, SyslogTransport::Tcp).await;
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        // The length counts bytes, not characters
+        let lines = vec![
+            rejected_line(1, "Строка не разобрана"),
+            rejected_line(2, "Line has fewer parts than expected fields"),
+        ];
+        assert_eq!(sink.insert_rejected_lines(lines).await.unwrap(), 2);
+
+        let messages = read_frames(&mut stream, 2).await;
+        assert!(messages[0].starts_with("<20>1 2024-01-15T14:00:00.500000Z collector "));
+        assert!(messages[0].ends_with("LOG:1: Строка не разобрана"));
+        assert!(messages[1].ends_with("LOG:2: Line has fewer parts than expected fields"));
+    }
+
+    #[tokio::test]
+    async fn escapes_structured_data_parameter_values() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let sink = sink(address, SyslogTransport::Tcp).await;
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let line = rejected_line(1, r#"bad "quote" in C:\logs [x]"#);
+        sink.insert_rejected_lines(vec![line]).await.unwrap();
+
+        let messages = read_frames(&mut stream, 1).await;
+        assert!(
+            messages[0].contains(r#" reason="bad \"quote\" in C:\\logs [x\]"]"#),
+            "{}",
+            messages[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnects_and_resends_after_server_closes_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let sink = sink(address, SyslogTransport::Tcp).await;
+
+        let (mut first, _) = listener.accept().await.unwrap();
+        sink.insert_rejected_lines(vec![rejected_line(1, "first")])
+            .await
+            .unwrap();
+        let messages = read_frames(&mut first, 1).await;
+        assert!(messages[0].ends_with("LOG:1: first"));
+
+        // The server goes away between two batches
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let lines = vec![rejected_line(2, "second"), rejected_line(3, "third")];
+        assert_eq!(sink.insert_rejected_lines(lines).await.unwrap(), 2);
+
+        let (mut second, _) = listener.accept().await.unwrap();
+        let messages = read_frames(&mut second, 2).await;
+        assert!(messages[0].ends_with("LOG:2: second"));
+        assert!(messages[1].ends_with("LOG:3: third"));
+    }
+}
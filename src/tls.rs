@@ -0,0 +1,54 @@
+use color_eyre::eyre::{Result, eyre};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::RootCertStore;
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+/// Returns the cryptographic provider of all TLS connections (ring)
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(tokio_rustls::rustls::crypto::ring::default_provider())
+}
+
+/// Returns the trusted CAs: the certificates of a PEM file or the Mozilla roots
+pub fn root_certificates(ca_file: Option<&Path>) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            for certificate in load_certificates(path)? {
+                roots.add(certificate)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    Ok(roots)
+}
+
+/// Reads all certificates of a PEM file
+pub fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            eyre!(
+                "Не удалось прочитать сертификаты из {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+    if certificates.is_empty() {
+        return Err(eyre!("В файле {} нет сертификатов", path.display()));
+    }
+    Ok(certificates)
+}
+
+/// Reads the private key of a PEM file (PKCS#8, PKCS#1 or SEC1)
+pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| {
+        eyre!(
+            "Не удалось прочитать закрытый ключ из {}: {}",
+            path.display(),
+            e
+        )
+    })
+}